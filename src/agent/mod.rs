@@ -0,0 +1,268 @@
+use ic_agent::{Agent, AgentError, Identity};
+use candid::Principal;
+use ic_agent::identity::{BasicIdentity, PemError, Secp256k1Identity};
+use ic_agent::agent::http_transport::ReqwestHttpReplicaV2Transport;
+use garcon::Delay;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::str::FromStr;
+use std::sync::Arc;
+use crate::databox::capacity::CapacityError;
+use crate::transfer::ProgressObserver;
+mod retry;
+pub use retry::{ErrorClass, RetryPolicy};
+pub(crate) use retry::RetryPolicies;
+
+/// Replica the agent talks to
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Network {
+    /// The Internet Computer mainnet
+    #[default]
+    Ic,
+    /// A local replica started by dfx
+    Local,
+    /// Any other replica url
+    Custom(String),
+}
+
+impl Network {
+    pub fn url(&self) -> &str {
+        match self {
+            Network::Ic => "https://ic0.app",
+            Network::Local => "http://127.0.0.1:4943",
+            Network::Custom(url) => url,
+        }
+    }
+
+    /// Only the mainnet root key is built into the agent, other replicas need it fetched
+    pub fn is_mainnet(&self) -> bool {
+        matches!(self, Network::Ic)
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(network: &str) -> Result<Self, Self::Err> {
+        match network {
+            "ic" | "mainnet" => Ok(Network::Ic),
+            "local" => Ok(Network::Local),
+            url if url.starts_with("http://") || url.starts_with("https://") => Ok(Network::Custom(url.to_string())),
+            other => Err(format!("unknown network `{}`, expected `ic`, `local` or a replica url", other)),
+        }
+    }
+}
+
+/// Error of a call which did not get an answer from the canister
+#[derive(Debug)]
+pub enum CallError {
+    Agent(Box<AgentError>),
+    Candid(candid::Error),
+    Identity(PemError),
+    Io(std::io::Error),
+    /// The operation was stopped by its `transfer::CancelToken`
+    Cancelled,
+    /// The files do not fit in the box, by `CapacityPolicy::Reject`
+    Capacity(Box<CapacityError>),
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallError::Agent(error) => write!(f, "agent error: {}", error),
+            CallError::Candid(error) => write!(f, "candid error: {}", error),
+            CallError::Identity(error) => write!(f, "identity error: {}", error),
+            CallError::Io(error) => write!(f, "io error: {}", error),
+            CallError::Cancelled => write!(f, "cancelled"),
+            CallError::Capacity(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for CallError {}
+
+impl From<AgentError> for CallError {
+    fn from(error: AgentError) -> Self {
+        CallError::Agent(Box::new(error))
+    }
+}
+
+impl From<candid::Error> for CallError {
+    fn from(error: candid::Error) -> Self {
+        CallError::Candid(error)
+    }
+}
+
+impl From<PemError> for CallError {
+    fn from(error: PemError) -> Self {
+        CallError::Identity(error)
+    }
+}
+
+impl From<std::io::Error> for CallError {
+    fn from(error: std::io::Error) -> Self {
+        CallError::Io(error)
+    }
+}
+
+/// Load a pem identity, both secp256k1 and ed25519 (dfx) keys are supported
+pub fn load_identity(pem_identity_path: &str) -> Result<Box<dyn Identity>, PemError> {
+    match Secp256k1Identity::from_pem_file(pem_identity_path) {
+        Ok(identity) => Ok(Box::new(identity)),
+        Err(secp256k1_error) => match BasicIdentity::from_pem_file(pem_identity_path) {
+            Ok(identity) => Ok(Box::new(identity)),
+            Err(_) => Err(secp256k1_error),
+        },
+    }
+}
+
+/// Build an agent for `network`, fetching the root key when it is not the mainnet
+///
+/// Example code :
+/// ``` no_run
+/// use metabox_sdk::agent::{self, Network};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let agent = agent::build_agent_for("identities/identity.pem", &Network::Local).await.unwrap();
+///     println!("principal: {}", agent.get_principal().unwrap().to_text());
+/// }
+/// ```
+pub async fn build_agent_for(pem_identity_path: &str, network: &Network) -> Result<Agent, CallError> {
+    let identity = load_identity(pem_identity_path)?;
+    let transport = ReqwestHttpReplicaV2Transport::create(network.url())?;
+    let agent = Agent::builder()
+        .with_transport(transport)
+        .with_boxed_identity(identity)
+        .build()?;
+    if !network.is_mainnet() {
+        agent.fetch_root_key().await?;
+    }
+    Ok(agent)
+}
+
+pub(crate) fn get_waiter() -> Delay {
+    garcon::Delay::builder()
+        .throttle(std::time::Duration::from_millis(500))
+        .timeout(std::time::Duration::from_secs(60 * 5))
+        .build()
+}
+
+pub(crate) fn build_agent(pem_identity_path: &str) -> Agent {
+    let url = Network::Ic.url().to_string();
+    let identity = Secp256k1Identity::from_pem_file(String::from(pem_identity_path)).unwrap();
+    let transport = ReqwestHttpReplicaV2Transport::create(url).expect("transport error");
+    Agent::builder()
+        .with_transport(transport)
+        .with_identity(identity)
+        .build()
+        .expect("build agent error")
+}
+
+/// In-process canister the clients can talk to instead of a replica, see [`crate::databox::mock`]
+pub(crate) trait MockCanister: Send + Sync {
+    fn call(&self, caller: Principal, method_name: &str, arg: &[u8]) -> Result<Vec<u8>, CallError>;
+
+    /// Another canister this one knows, like the boxes of a MetaBox
+    fn sibling(&self, _canister_id: &Principal) -> Option<Arc<dyn MockCanister>> {
+        None
+    }
+}
+
+/// How a client reaches its canister
+#[derive(Clone)]
+pub(crate) enum Transport {
+    Agent(Agent),
+    Mock {
+        canister: Arc<dyn MockCanister>,
+        caller: Principal,
+    },
+}
+
+impl Transport {
+    pub(crate) fn caller(&self) -> Principal {
+        match self {
+            Transport::Agent(agent) => agent.get_principal().unwrap(),
+            Transport::Mock { caller, .. } => *caller,
+        }
+    }
+
+    /// The transport of another canister, for the same caller
+    pub(crate) fn for_canister(&self, canister_id: &Principal) -> Transport {
+        match self {
+            Transport::Agent(agent) => Transport::Agent(agent.clone()),
+            Transport::Mock { canister, caller } => Transport::Mock {
+                canister: canister.sibling(canister_id).unwrap_or_else(|| canister.clone()),
+                caller: *caller,
+            },
+        }
+    }
+
+    pub(crate) async fn query(
+        &self,
+        canister_id: &Principal,
+        method_name: &str,
+        arg: Vec<u8>,
+        policy: &RetryPolicy,
+        observer: Option<&dyn ProgressObserver>,
+    ) -> Result<Vec<u8>, CallError> {
+        policy
+            .run(method_name, false, observer, || self.query_once(canister_id, method_name, arg.clone()))
+            .await
+    }
+
+    pub(crate) async fn update(
+        &self,
+        canister_id: &Principal,
+        method_name: &str,
+        arg: Vec<u8>,
+        policy: &RetryPolicy,
+        observer: Option<&dyn ProgressObserver>,
+    ) -> Result<Vec<u8>, CallError> {
+        policy
+            .run(method_name, true, observer, || self.update_once(canister_id, method_name, arg.clone()))
+            .await
+    }
+
+    async fn query_once(&self, canister_id: &Principal, method_name: &str, arg: Vec<u8>) -> Result<Vec<u8>, CallError> {
+        match self {
+            Transport::Agent(agent) => Ok(agent.query(canister_id, method_name).with_arg(arg).call().await?),
+            Transport::Mock { canister, caller } => {
+                YieldOnce(false).await;
+                canister.call(*caller, method_name, &arg)
+            }
+        }
+    }
+
+    async fn update_once(&self, canister_id: &Principal, method_name: &str, arg: Vec<u8>) -> Result<Vec<u8>, CallError> {
+        match self {
+            Transport::Agent(agent) => Ok(agent
+                .update(canister_id, method_name)
+                .with_arg(arg)
+                .call_and_wait(get_waiter())
+                .await?),
+            Transport::Mock { canister, caller } => {
+                YieldOnce(false).await;
+                canister.call(*caller, method_name, &arg)
+            }
+        }
+    }
+}
+
+// Pending once : concurrent calls to a mock interleave, like calls to a replica
+struct YieldOnce(bool);
+
+impl Future for YieldOnce {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
@@ -0,0 +1,238 @@
+//! Retrying calls which failed for a transient reason
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+use garcon::{ThrottleWaiter, Waiter};
+use ic_agent::AgentError;
+use rand::Rng;
+use crate::transfer::{ProgressObserver, TransferEvent};
+use super::CallError;
+
+/// Reject code of a transient system error (canister queue full, subnet overloaded, ...)
+const SYS_TRANSIENT: u64 = 2;
+
+/// Updates which must not run twice : they create, fund, spend or wipe something
+const NOT_IDEMPOTENT: [&str; 6] = ["createBox", "topUpBox", "deleteBox", "clearall", "transferOutICP", "mintBox"];
+
+/// The kinds of transient failures a call can be retried on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorClass {
+    /// HTTP 5xx from the boundary node or replica
+    ServerError,
+    /// HTTP 429, or a transient reject from a busy canister or subnet
+    Overload,
+    /// The request expired before the replica accepted it, for example because of clock skew
+    IngressExpiry,
+    /// No answer to an update before the waiter timed out
+    Timeout,
+    /// The connection to the replica failed
+    Transport,
+}
+
+impl ErrorClass {
+    pub const ALL: [ErrorClass; 5] = [
+        ErrorClass::ServerError,
+        ErrorClass::Overload,
+        ErrorClass::IngressExpiry,
+        ErrorClass::Timeout,
+        ErrorClass::Transport,
+    ];
+
+    /// Whether an update which failed this way may have run anyway : the replica may have
+    /// accepted the call before the answer was lost
+    pub fn is_ambiguous(&self) -> bool {
+        matches!(self, ErrorClass::ServerError | ErrorClass::Timeout | ErrorClass::Transport)
+    }
+
+    /// Class of a failed call, `None` when retrying can not help
+    pub fn of(error: &CallError) -> Option<ErrorClass> {
+        let error = match error {
+            CallError::Agent(error) => error.as_ref(),
+            _ => return None,
+        };
+        match error {
+            AgentError::HttpError(payload) if is_ingress_expiry(&String::from_utf8_lossy(&payload.content)) => {
+                Some(ErrorClass::IngressExpiry)
+            }
+            AgentError::HttpError(payload) if payload.status == 429 => Some(ErrorClass::Overload),
+            AgentError::HttpError(payload) if payload.status >= 500 => Some(ErrorClass::ServerError),
+            AgentError::ReplicaError { reject_message, .. } if is_ingress_expiry(reject_message) => Some(ErrorClass::IngressExpiry),
+            AgentError::ReplicaError { reject_code, .. } if *reject_code == SYS_TRANSIENT => Some(ErrorClass::Overload),
+            AgentError::TimeoutWaitingForResponse() => Some(ErrorClass::Timeout),
+            AgentError::TransportError(_) => Some(ErrorClass::Transport),
+            _ => None,
+        }
+    }
+}
+
+fn is_ingress_expiry(message: &str) -> bool {
+    message.contains("ingress_expiry") || message.contains("ingress expiry")
+}
+
+/// How often and how patiently a failed call is retried
+///
+/// Attempt `n` (from 1) waits `initial_backoff * multiplier ^ (n - 1)`, capped at `max_backoff`,
+/// before retrying. With jitter the wait is drawn uniformly between zero and that value, so many
+/// clients failing together do not retry together.
+///
+/// Queries are retried on every class of `retryable`. Updates are only retried when the replica
+/// never accepted the call (`Overload`, `IngressExpiry`) : after an [ambiguous](ErrorClass::is_ambiguous)
+/// failure the call may have run, and a retry is a new call, so it would run twice. Set
+/// `retry_ambiguous_updates` for methods where that is harmless, like `put` of a chunk : a chunk
+/// sent again replaces the same bytes, and once the file is complete the box answers `FileRepeat`
+/// (see `Transfer::with_idempotent`). `createBox`, `topUpBox`, `deleteBox`, `clearall`,
+/// `transferOutICP` and `mintBox` only honour it from a policy set for the method itself.
+///
+/// Example code :
+/// ```
+/// use std::time::Duration;
+/// use metabox_sdk::agent::{ErrorClass, RetryPolicy};
+///
+/// let policy = RetryPolicy {
+///     max_attempts: 6,
+///     initial_backoff: Duration::from_millis(200),
+///     retryable: vec![ErrorClass::ServerError, ErrorClass::Overload],
+///     jitter: false,
+///     ..RetryPolicy::default()
+/// };
+/// assert_eq!(policy.backoff(1), Duration::from_millis(200));
+/// assert_eq!(policy.backoff(3), Duration::from_millis(800));
+/// ```
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Attempts in total, 1 never retries
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+    pub jitter: bool,
+    pub retryable: Vec<ErrorClass>,
+    /// Retry updates after a failure which may have been applied, `false` by default
+    pub retry_ambiguous_updates: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: true,
+            retryable: ErrorClass::ALL.to_vec(),
+            retry_ambiguous_updates: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Single attempt, errors are returned right away
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// Wait before retry number `retry` (from 1), without jitter
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = self.multiplier.max(1.0).powi(retry.saturating_sub(1) as i32);
+        let backoff = self.initial_backoff.as_secs_f64() * factor;
+        Duration::from_secs_f64(backoff.min(self.max_backoff.as_secs_f64()))
+    }
+
+    /// Whether a query which failed with `error` on attempt `attempt` (from 1) is tried again
+    pub fn should_retry(&self, error: &CallError, attempt: u32) -> bool {
+        attempt < self.max_attempts && ErrorClass::of(error).is_some_and(|class| self.retryable.contains(&class))
+    }
+
+    /// Whether an update which failed with `error` on attempt `attempt` (from 1) is tried again
+    ///
+    /// Example code :
+    /// ```
+    /// use ic_agent::AgentError;
+    /// use metabox_sdk::agent::{CallError, RetryPolicy};
+    ///
+    /// let timeout = CallError::from(AgentError::TimeoutWaitingForResponse());
+    /// assert!(RetryPolicy::default().should_retry(&timeout, 1));
+    /// assert!(!RetryPolicy::default().should_retry_update(&timeout, 1));
+    /// let put_policy = RetryPolicy { retry_ambiguous_updates: true, ..RetryPolicy::default() };
+    /// assert!(put_policy.should_retry_update(&timeout, 1));
+    /// ```
+    pub fn should_retry_update(&self, error: &CallError, attempt: u32) -> bool {
+        self.should_retry(error, attempt) && ErrorClass::of(error).is_some_and(|class| self.retry_ambiguous_updates || !class.is_ambiguous())
+    }
+
+    fn delay(&self, retry: u32) -> Duration {
+        let backoff = self.backoff(retry);
+        if self.jitter && !backoff.is_zero() {
+            Duration::from_secs_f64(rand::thread_rng().gen_range(0.0..=backoff.as_secs_f64()))
+        } else {
+            backoff
+        }
+    }
+
+    /// Run `call` until it succeeds, fails for good or runs out of attempts, reporting retries
+    pub(crate) async fn run<T, F, Fut>(
+        &self,
+        method_name: &str,
+        update: bool,
+        observer: Option<&dyn ProgressObserver>,
+        mut call: F,
+    ) -> Result<T, CallError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, CallError>>,
+    {
+        let retry = |error: &CallError, attempt| if update { self.should_retry_update(error, attempt) } else { self.should_retry(error, attempt) };
+        let mut attempt = 1;
+        loop {
+            match call().await {
+                Err(error) if retry(&error, attempt) => {
+                    let delay = self.delay(attempt);
+                    if let Some(observer) = observer {
+                        observer.on_event(&TransferEvent::Retry {
+                            method_name: method_name.to_string(),
+                            attempt,
+                            delay,
+                            error: error.to_string(),
+                        });
+                    }
+                    if !delay.is_zero() {
+                        // Runtime agnostic sleep, like the agent 's own waiter
+                        let _ = ThrottleWaiter::new(delay).async_wait().await;
+                    }
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// The retry policy of a client, with overrides per canister method
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RetryPolicies {
+    pub(crate) default: RetryPolicy,
+    pub(crate) methods: HashMap<String, RetryPolicy>,
+}
+
+impl RetryPolicies {
+    pub(crate) fn for_method(&self, method_name: &str) -> &RetryPolicy {
+        self.methods.get(method_name).unwrap_or(&self.default)
+    }
+
+    /// The policy of an update, which only retries ambiguous failures of a method which is not
+    /// idempotent when its own policy says so
+    pub(crate) fn for_update(&self, method_name: &str) -> Cow<'_, RetryPolicy> {
+        match self.methods.get(method_name) {
+            Some(policy) => Cow::Borrowed(policy),
+            None if self.default.retry_ambiguous_updates && NOT_IDEMPOTENT.contains(&method_name) => Cow::Owned(RetryPolicy {
+                retry_ambiguous_updates: false,
+                ..self.default.clone()
+            }),
+            None => Cow::Borrowed(&self.default),
+        }
+    }
+}
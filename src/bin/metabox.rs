@@ -0,0 +1,564 @@
+//! `metabox` command line tool
+//!
+//! ```text
+//! metabox --identity identities/identity.pem box list
+//! metabox --network local file put 4radi-oqaaa-aaaan-qapwa-cai source/bitcoin.pdf
+//! metabox --output json file ls 4radi-oqaaa-aaaan-qapwa-cai
+//! metabox --cache ~/.cache/metabox --offline file ls 4radi-oqaaa-aaaan-qapwa-cai
+//! ```
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+use candid::Principal;
+use clap::{Parser, Subcommand, ValueEnum};
+use serde_json::{json, Value};
+use metabox_sdk::agent::{self, CallError, Network};
+use metabox_sdk::databox::cache::InventoryCache;
+use metabox_sdk::databox::capacity::CapacityPolicy;
+use metabox_sdk::databox::{
+    AvlSMResult, CanisterStateResult, ClearAllResult, CycleBalanceResult, DataBoxClient, DataErr, DeleteKeyResult,
+    FileExt, PutPlainFileResult, UploadStatus,
+};
+use metabox_sdk::metabox::{BoxInfo, CreateBoxResult, DeleteBoxResult, MetaBoxClient, TopUpBoxResult};
+use metabox_sdk::mime;
+use metabox_sdk::transfer::{Transfer, TransferEvent};
+
+#[derive(Parser)]
+#[command(name = "metabox", version, about = "Easy to use MetaBox Tool")]
+struct Cli {
+    /// Pem identity file, secp256k1 or ed25519
+    #[arg(long, short, global = true, env = "METABOX_IDENTITY", default_value = "identities/identity.pem")]
+    identity: String,
+
+    /// `ic`, `local` or a replica url
+    #[arg(long, short, global = true, env = "METABOX_NETWORK", default_value = "ic")]
+    network: Network,
+
+    /// MetaBox canister id, for self-hosted deployments
+    #[arg(long, global = true, env = "METABOX_CANISTER")]
+    metabox: Option<Principal>,
+
+    #[arg(long, global = true, value_enum, default_value_t = Output::Human)]
+    output: Output,
+
+    /// Directory caching the file listings of boxes
+    #[arg(long, global = true, env = "METABOX_CACHE")]
+    cache: Option<PathBuf>,
+
+    /// Seconds a cached listing is used for
+    #[arg(long, global = true, default_value_t = 300)]
+    cache_ttl: u64,
+
+    /// List and show files from the cache only
+    #[arg(long, global = true, requires = "cache")]
+    offline: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Output {
+    Human,
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Capacity {
+    Reject,
+    Fit,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Manage boxes through MetaBox
+    #[command(subcommand)]
+    Box(BoxCommand),
+    /// Manage files in a DataBox
+    #[command(subcommand)]
+    File(FileCommand),
+    /// Mount a DataBox as a filesystem, until it is unmounted
+    #[cfg(all(feature = "fuse", target_os = "linux"))]
+    Mount {
+        canister: Principal,
+        mountpoint: PathBuf,
+        /// Refuse creating and deleting files
+        #[arg(long)]
+        read_only: bool,
+    },
+    /// Serve the files of DataBoxes over HTTP, at /<canister>/<file_key>
+    #[cfg(feature = "gateway")]
+    Serve {
+        #[arg(required = true)]
+        canisters: Vec<Principal>,
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        address: std::net::SocketAddr,
+    },
+}
+
+#[derive(Subcommand)]
+enum BoxCommand {
+    /// Create a DataBox paid with ICP (e8s)
+    Create {
+        #[arg(long)]
+        name: String,
+        #[arg(long)]
+        icp: u64,
+        #[arg(long)]
+        private: bool,
+    },
+    /// List the boxes of a user, the caller by default
+    List {
+        #[arg(long)]
+        owner: Option<Principal>,
+    },
+    /// Delete a DataBox
+    Delete {
+        canister: Principal,
+        /// Where the remaining cycles go
+        #[arg(long)]
+        cycle_to: Option<Principal>,
+    },
+    /// Top up a box with ICP (e8s)
+    TopUp {
+        canister: Principal,
+        #[arg(long)]
+        icp: u64,
+    },
+    /// Show the canister state of a DataBox
+    State { canister: Principal },
+    /// Show the cycle balance of a DataBox
+    Cycles { canister: Principal },
+}
+
+#[derive(Subcommand)]
+enum FileCommand {
+    /// Upload files, directories are uploaded file by file
+    Put {
+        canister: Principal,
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// Check the box has room first : `reject` the upload or send the files which `fit`
+        #[arg(long, value_enum)]
+        capacity: Option<Capacity>,
+    },
+    /// Download a file
+    Get {
+        canister: Principal,
+        file_key: String,
+        /// Output path, the stored file name by default
+        #[arg(long, short = 'o')]
+        out: Option<PathBuf>,
+    },
+    /// List the plain files
+    Ls { canister: Principal },
+    /// Delete a file, or every file with `--all`
+    Rm {
+        canister: Principal,
+        #[arg(required_unless_present = "all")]
+        file_key: Option<String>,
+        #[arg(long, conflicts_with = "file_key")]
+        all: bool,
+    },
+    /// Show a file 's information
+    Info { canister: Principal, file_key: String },
+}
+
+enum CliError {
+    Call(CallError),
+    Data(DataErr),
+    MetaBox(String),
+    Io(std::io::Error),
+}
+
+impl From<CallError> for CliError {
+    fn from(error: CallError) -> Self {
+        CliError::Call(error)
+    }
+}
+
+impl From<DataErr> for CliError {
+    fn from(error: DataErr) -> Self {
+        CliError::Data(error)
+    }
+}
+
+impl From<std::io::Error> for CliError {
+    fn from(error: std::io::Error) -> Self {
+        CliError::Io(error)
+    }
+}
+
+impl CliError {
+    fn message(&self) -> String {
+        match self {
+            CliError::Call(error) => error.to_string(),
+            CliError::Data(error) => format!("data box error: {:?}", error),
+            CliError::MetaBox(error) => format!("metabox error: {}", error),
+            CliError::Io(error) => format!("io error: {}", error),
+        }
+    }
+}
+
+struct Printer {
+    output: Output,
+}
+
+impl Printer {
+    fn print(&self, human: String, json: Value) {
+        match self.output {
+            Output::Human => println!("{}", human),
+            Output::Json => println!("{}", serde_json::to_string_pretty(&json).unwrap()),
+        }
+    }
+
+    /// Progress on stderr for humans, nothing for json
+    fn transfer(&self) -> Transfer {
+        match self.output {
+            Output::Human => Transfer::new().with_observer(Arc::new(print_progress)),
+            Output::Json => Transfer::new(),
+        }
+    }
+}
+
+fn print_progress(event: &TransferEvent) {
+    match event {
+        TransferEvent::FileStarted { file_name, index, count, total_size, .. } if *count > 1 => {
+            eprintln!("[{}/{}] {} ({} bytes)", index + 1, count, file_name, total_size)
+        }
+        TransferEvent::ChunkAcked { transferred, total_size, .. } => eprint!("\r{} / {} bytes", transferred, total_size),
+        TransferEvent::Retry { method_name, attempt, delay, error } => {
+            eprintln!("\n{} failed ({}), retry {} in {:?}", method_name, error, attempt, delay)
+        }
+        TransferEvent::FileFinished { .. } => eprintln!(),
+        _ => {}
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    let printer = Printer { output: cli.output };
+    let result = match agent::build_agent_for(&cli.identity, &cli.network).await {
+        Ok(agent) => match cli.command {
+            Command::Box(command) => {
+                let client = match cli.metabox {
+                    Some(canister_id) => MetaBoxClient::with_canister_id(agent.clone(), canister_id),
+                    None => MetaBoxClient::new(agent.clone()),
+                };
+                run_box(&printer, &client, agent, command).await
+            }
+            Command::File(command) => match &cli.cache {
+                Some(dir) => match InventoryCache::new(dir) {
+                    Ok(cache) => {
+                        let cache = cache.with_ttl(Duration::from_secs(cli.cache_ttl)).with_offline(cli.offline);
+                        run_file(&printer, agent, Some(cache), command).await
+                    }
+                    Err(error) => Err(error.into()),
+                },
+                None => run_file(&printer, agent, None, command).await,
+            },
+            #[cfg(all(feature = "fuse", target_os = "linux"))]
+            Command::Mount { canister, mountpoint, read_only } => run_mount(agent, canister, mountpoint, read_only).await,
+            #[cfg(feature = "gateway")]
+            Command::Serve { canisters, address } => run_serve(agent, canisters, address).await,
+        },
+        Err(error) => Err(CliError::Call(error)),
+    };
+    if let Err(error) = result {
+        match printer.output {
+            Output::Human => eprintln!("error: {}", error.message()),
+            Output::Json => eprintln!("{}", json!({ "error": error.message() })),
+        }
+        process::exit(1);
+    }
+}
+
+async fn run_box(printer: &Printer, client: &MetaBoxClient, agent: ic_agent::Agent, command: BoxCommand) -> Result<(), CliError> {
+    match command {
+        BoxCommand::Create { name, icp, private } => match client.create_data_box(icp, name, private).await? {
+            CreateBoxResult::ok(canister_id) => printer.print(
+                format!("created data box {}", canister_id),
+                json!({ "canister_id": canister_id.to_text() }),
+            ),
+            CreateBoxResult::err(error) => return Err(CliError::MetaBox(format!("{:?}", error))),
+        },
+        BoxCommand::List { owner } => {
+            let owner = owner.unwrap_or_else(|| client.caller());
+            let boxes = client.get_boxes(owner).await?;
+            let human = boxes.iter().map(box_human).collect::<Vec<_>>().join("\n");
+            printer.print(human, Value::Array(boxes.iter().map(box_json).collect()));
+        }
+        BoxCommand::Delete { canister, cycle_to } => match client.delete_data_box(canister, cycle_to).await? {
+            DeleteBoxResult::ok(message) => printer.print(
+                format!("deleted data box {}: {}", canister, message),
+                json!({ "canister_id": canister.to_text(), "message": message }),
+            ),
+            DeleteBoxResult::err(error) => return Err(CliError::MetaBox(format!("{:?}", error))),
+        },
+        BoxCommand::TopUp { canister, icp } => match client.top_up_box(canister, icp).await? {
+            TopUpBoxResult::ok => printer.print(
+                format!("topped up {} with {} e8s", canister, icp),
+                json!({ "canister_id": canister.to_text(), "icp_amount": icp }),
+            ),
+            TopUpBoxResult::err(error) => return Err(CliError::MetaBox(format!("{:?}", error))),
+        },
+        BoxCommand::State { canister } => match DataBoxClient::new(agent, canister).get_canister_state().await? {
+            CanisterStateResult::ok(state) => printer.print(
+                format!(
+                    "cycle balance: {}\nmemory size: {}\nstable memory size: {}",
+                    state.balance, state.memory_size, state.stable_memory_size
+                ),
+                json!({
+                    "balance": state.balance.to_string(),
+                    "memory_size": state.memory_size.to_string(),
+                    "stable_memory_size": state.stable_memory_size,
+                }),
+            ),
+            CanisterStateResult::err(data_err) => return Err(data_err.into()),
+        },
+        BoxCommand::Cycles { canister } => match DataBoxClient::new(agent, canister).get_cycle_balance().await? {
+            CycleBalanceResult::ok(balance) => printer.print(
+                format!("{}", balance),
+                json!({ "canister_id": canister.to_text(), "balance": balance.to_string() }),
+            ),
+            CycleBalanceResult::err(data_err) => return Err(data_err.into()),
+        },
+    }
+    Ok(())
+}
+
+// The file written in the current directory for a stored name : its last component, which must be
+// a plain name (not `..`, not empty), and the extension of its MIME type
+fn output_path(file_name: &str, file_extension: &str) -> Result<PathBuf, CliError> {
+    let name = match Path::new(file_name).file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => {
+            let message = format!("file name {:?} is not a valid path, use --out", file_name);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, message).into());
+        }
+    };
+    Ok(PathBuf::from(match mime::to_extension(file_extension) {
+        Some(extension) => format!("{}.{}", name, extension),
+        None => name,
+    }))
+}
+
+async fn run_file(printer: &Printer, agent: ic_agent::Agent, cache: Option<InventoryCache>, command: FileCommand) -> Result<(), CliError> {
+    let offline = cache.as_ref().is_some_and(InventoryCache::is_offline);
+    let data_box_client = |canister| {
+        let client = DataBoxClient::new(agent.clone(), canister);
+        match &cache {
+            Some(cache) => client.with_cache(cache.clone()),
+            None => client,
+        }
+    };
+    match command {
+        FileCommand::Put { canister, paths, capacity } => {
+            let client = data_box_client(canister);
+            let policy = match capacity {
+                Some(Capacity::Reject) => CapacityPolicy::Reject,
+                Some(Capacity::Fit) => CapacityPolicy::FitOnly,
+                None => CapacityPolicy::Unchecked,
+            };
+            let transfer = printer.transfer().with_capacity_policy(policy);
+            let results = client.put_plain_paths_with(&expand_paths(paths)?, &transfer).await?;
+            let human = results.iter().map(put_human).collect::<Vec<_>>().join("\n");
+            printer.print(human, Value::Array(results.iter().map(put_json).collect()));
+        }
+        FileCommand::Get { canister, file_key, out } => {
+            let client = data_box_client(canister);
+            let out = match out {
+                Some(out) => out,
+                None => match client.get_file_info(&file_key).await?? {
+                    FileExt::PlainFileExt(asset_ext) => output_path(&asset_ext.file_name, &asset_ext.file_extension)?,
+                    _ => return Err(DataErr::FileKeyErr.into()),
+                },
+            };
+            let data = client.get_plain_file_with(&file_key, &printer.transfer()).await??;
+            fs::write(&out, &data)?;
+            printer.print(
+                format!("wrote {} bytes to {}", data.len(), out.display()),
+                json!({ "file_key": file_key, "path": out.display().to_string(), "size": data.len() }),
+            );
+        }
+        FileCommand::Ls { canister } => {
+            let client = data_box_client(canister);
+            let files = client.get_all_plain_files_info().await??;
+            let human = files.iter().map(file_human).collect::<Vec<_>>().join("\n");
+            printer.print(human, Value::Array(files.iter().map(file_json).collect()));
+        }
+        FileCommand::Rm { canister, file_key, all } => {
+            let client = data_box_client(canister);
+            if all {
+                match client.clear_data_box().await? {
+                    ClearAllResult::ok(message) => printer.print(message.clone(), json!({ "message": message })),
+                    ClearAllResult::err(data_err) => return Err(data_err.into()),
+                }
+            } else {
+                let file_key = file_key.unwrap();
+                match client.delete_file(file_key.clone()).await? {
+                    DeleteKeyResult::ok(message) => printer.print(
+                        format!("deleted {}", file_key),
+                        json!({ "file_key": file_key, "message": message }),
+                    ),
+                    DeleteKeyResult::err(data_err) => return Err(data_err.into()),
+                }
+            }
+        }
+        FileCommand::Info { canister, file_key } => {
+            let client = data_box_client(canister);
+            let file_ext = client.get_file_info(&file_key).await??;
+            // Offline, the box is not asked
+            let available = if offline {
+                None
+            } else {
+                match client.get_avl_sm().await? {
+                    AvlSMResult::ok(available) => Some(available),
+                    AvlSMResult::err(_) => None,
+                }
+            };
+            let mut json = file_json(&file_ext);
+            json["box_available_stable_memory"] = json!(available);
+            printer.print(file_human(&file_ext), json);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(feature = "fuse", target_os = "linux"))]
+async fn run_mount(agent: ic_agent::Agent, canister: Principal, mountpoint: PathBuf, read_only: bool) -> Result<(), CliError> {
+    use metabox_sdk::fuse::{self, MountOptions};
+
+    let client = DataBoxClient::new(agent, canister);
+    let options = MountOptions { read_only, ..MountOptions::default() };
+    let runtime = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || fuse::mount(client, mountpoint, runtime, options))
+        .await
+        .expect("mount task panicked")?;
+    Ok(())
+}
+
+#[cfg(feature = "gateway")]
+async fn run_serve(agent: ic_agent::Agent, canisters: Vec<Principal>, address: std::net::SocketAddr) -> Result<(), CliError> {
+    use metabox_sdk::gateway::{GatewayOptions, HttpGateway};
+
+    let gateway = canisters
+        .into_iter()
+        .fold(HttpGateway::new(GatewayOptions::default()), |gateway, canister| gateway.with_box(DataBoxClient::new(agent.clone(), canister)));
+    eprintln!("serving on http://{}", address);
+    gateway.serve(address, std::future::pending()).await.map_err(std::io::Error::other)?;
+    Ok(())
+}
+
+fn expand_paths(paths: Vec<PathBuf>) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            for entry in fs::read_dir(&path)? {
+                let entry = entry?;
+                if entry.file_type()?.is_file() {
+                    files.push(entry.path());
+                }
+            }
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+fn box_human(box_info: &BoxInfo) -> String {
+    format!(
+        "{}\t{}\t{:?}\t{:?}\t{}",
+        box_info.canister_id,
+        box_info.box_name,
+        box_info.box_type,
+        box_info.status,
+        if box_info.is_private { "private" } else { "public" }
+    )
+}
+
+fn box_json(box_info: &BoxInfo) -> Value {
+    json!({
+        "canister_id": box_info.canister_id.to_text(),
+        "box_name": box_info.box_name,
+        "box_type": format!("{:?}", box_info.box_type),
+        "status": format!("{:?}", box_info.status),
+        "is_private": box_info.is_private,
+    })
+}
+
+fn put_human(result: &PutPlainFileResult) -> String {
+    match &result.upload_status {
+        UploadStatus::Ok => format!("{}\t{}\t{} bytes", result.file_key, result.file_name, result.total_size),
+        UploadStatus::AlreadyExists => format!("{}\t{}\talready stored", result.file_key, result.file_name),
+        UploadStatus::Err(data_err) => format!("{}\t{}\tfailed: {:?}", result.file_key, result.file_name, data_err),
+        UploadStatus::Cancelled { committed_chunks } => format!(
+            "{}\t{}\tcancelled: {} of {} chunks stored",
+            result.file_key,
+            result.file_name,
+            committed_chunks.len(),
+            result.chunk_number
+        ),
+    }
+}
+
+fn put_json(result: &PutPlainFileResult) -> Value {
+    json!({
+        "file_name": result.file_name,
+        "file_extension": result.file_extension,
+        "file_key": result.file_key,
+        "upload_status": match &result.upload_status {
+            UploadStatus::Ok => "ok".to_string(),
+            UploadStatus::AlreadyExists => "already_exists".to_string(),
+            UploadStatus::Err(data_err) => format!("{:?}", data_err),
+            UploadStatus::Cancelled { .. } => "cancelled".to_string(),
+        },
+        "databox_canister_id": result.databox_canister_id.to_text(),
+        "total_size": result.total_size,
+        "chunk_number": result.chunk_number,
+    })
+}
+
+fn file_human(file_ext: &FileExt) -> String {
+    match file_ext {
+        FileExt::PlainFileExt(asset_ext) | FileExt::EncryptFileExt(asset_ext) => format!(
+            "{}\t{}\t{}\t{} bytes{}",
+            asset_ext.file_key,
+            asset_ext.file_name,
+            asset_ext.file_extension,
+            asset_ext.total_size,
+            if asset_ext.upload_status { "" } else { "\t(incomplete)" }
+        ),
+        FileExt::SharedFileExt { file_key, file_name, file_extension, other, .. } => {
+            format!("{}\t{}\t{}\tshared by {}", file_key, file_name, file_extension, other)
+        }
+    }
+}
+
+fn file_json(file_ext: &FileExt) -> Value {
+    match file_ext {
+        FileExt::PlainFileExt(asset_ext) | FileExt::EncryptFileExt(asset_ext) => json!({
+            "kind": if matches!(file_ext, FileExt::PlainFileExt(_)) { "plain" } else { "encrypt" },
+            "file_key": asset_ext.file_key,
+            "file_name": asset_ext.file_name,
+            "file_extension": asset_ext.file_extension,
+            "total_size": asset_ext.total_size,
+            "upload_status": asset_ext.upload_status,
+            "bucket_id": asset_ext.bucket_id.to_text(),
+            "need_query_times": asset_ext.need_query_times.to_string(),
+        }),
+        FileExt::SharedFileExt { file_key, file_name, file_extension, other, description, isPublic } => json!({
+            "kind": "shared",
+            "file_key": file_key,
+            "file_name": file_name,
+            "file_extension": file_extension,
+            "other": other.to_text(),
+            "description": description,
+            "is_public": isPublic,
+        }),
+    }
+}
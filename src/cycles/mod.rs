@@ -0,0 +1,159 @@
+use candid::{Nat, Principal};
+use ic_agent::Identity;
+use ic_agent::identity::Secp256k1Identity;
+use std::time::{Duration, Instant};
+use crate::databox::{self, CycleBalanceResult, DataErr};
+use crate::metabox::{self, BoxInfo, BoxStatus, BoxType, Error, TopUpBoxResult};
+
+/// Top up policy for the user 's DataBoxes
+///
+/// Every box whose cycle balance is below `threshold` is topped up with `icp_amount` e8s,
+/// as long as the ICP spent within the last `period` stays under `max_spend_per_period`.
+/// The policy remembers what it spent, so keep the same object around between runs.
+#[derive(Debug)]
+pub struct TopUpPolicy {
+    pub threshold: Nat,
+    pub icp_amount: u64,
+    pub max_spend_per_period: u64,
+    pub period: Duration,
+    pub dry_run: bool,
+    spent: Vec<(Instant, u64)>,
+}
+
+#[derive(Debug)]
+pub enum TopUpOutcome {
+    /// Cycle balance is above the threshold
+    Sufficient,
+    /// The box was topped up with `icp_amount` e8s
+    ToppedUp { icp_amount: u64 },
+    /// The box is below the threshold but was not topped up because of `dry_run`
+    WouldTopUp { icp_amount: u64 },
+    /// Topping up would exceed `max_spend_per_period`
+    BudgetExhausted { spent_in_period: u64 },
+    /// The box is stopped, its cycle balance can not be queried
+    Stopped,
+    /// The DataBox refused to report its cycle balance
+    BalanceUnavailable(DataErr),
+    /// MetaBox refused the top up
+    TopUpFailed(Error),
+}
+
+#[derive(Debug)]
+pub struct TopUpAction {
+    pub canister_id: Principal,
+    pub box_name: String,
+    pub cycle_balance: Option<Nat>,
+    pub outcome: TopUpOutcome,
+}
+
+#[derive(Debug, Default)]
+pub struct TopUpReport {
+    pub actions: Vec<TopUpAction>,
+    pub icp_spent: u64,
+}
+
+impl TopUpReport {
+    pub fn topped_up(&self) -> impl Iterator<Item = &TopUpAction> {
+        self.actions.iter().filter(|action| matches!(action.outcome, TopUpOutcome::ToppedUp { .. }))
+    }
+}
+
+impl TopUpPolicy {
+    pub fn new(threshold: Nat, icp_amount: u64, max_spend_per_period: u64, period: Duration) -> Self {
+        TopUpPolicy {
+            threshold,
+            icp_amount,
+            max_spend_per_period,
+            period,
+            dry_run: false,
+            spent: Vec::new(),
+        }
+    }
+
+    /// ICP (e8s) spent by this policy within the current period
+    pub fn spent_in_period(&self) -> u64 {
+        self.spent
+            .iter()
+            .filter(|(at, _)| at.elapsed() < self.period)
+            .fold(0u64, |spent, (_, amount)| spent.saturating_add(*amount))
+    }
+
+    /// Check every DataBox of the pem identity and top up those below the threshold
+    ///
+    /// Example code :
+    /// ``` no_run
+    /// use std::time::Duration;
+    /// use candid::Nat;
+    /// use metabox_sdk::cycles::TopUpPolicy;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let mut policy = TopUpPolicy::new(Nat::from(1_000_000_000_000u64), 10_000_000, 50_000_000, Duration::from_secs(60 * 60 * 24));
+    ///     let report = policy.run("identities/identity.pem").await;
+    ///     for action in &report.actions {
+    ///         println!("box {} ({}): {:?} -> {:?}", action.box_name, action.canister_id.to_text(), action.cycle_balance, action.outcome);
+    ///     }
+    ///     println!("icp spent: {:?}", report.icp_spent);
+    /// }
+    /// ```
+    pub async fn run(&mut self, pem_identity_path: &str) -> TopUpReport {
+        let identity = Secp256k1Identity::from_pem_file(String::from(pem_identity_path)).unwrap();
+        let user_principal = identity.sender().unwrap();
+        let boxes = metabox::get_boxes(pem_identity_path, user_principal).await;
+        let period = self.period;
+        self.spent.retain(|(at, _)| at.elapsed() < period);
+        let mut report = TopUpReport::default();
+        for box_info in boxes.into_iter().filter(|b| b.box_type == BoxType::data_box) {
+            let action = self.check_box(pem_identity_path, box_info).await;
+            if let TopUpOutcome::ToppedUp { icp_amount } = action.outcome {
+                report.icp_spent = report.icp_spent.saturating_add(icp_amount);
+            }
+            report.actions.push(action);
+        }
+        report
+    }
+
+    async fn check_box(&mut self, pem_identity_path: &str, box_info: BoxInfo) -> TopUpAction {
+        let mut action = TopUpAction {
+            canister_id: box_info.canister_id,
+            box_name: box_info.box_name,
+            cycle_balance: None,
+            outcome: TopUpOutcome::Sufficient,
+        };
+        if let BoxStatus::stopped = box_info.status {
+            action.outcome = TopUpOutcome::Stopped;
+            return action;
+        }
+        let balance = match databox::get_cycle_balance(pem_identity_path, &box_info.canister_id.to_text()).await {
+            CycleBalanceResult::ok(balance) => balance,
+            CycleBalanceResult::err(data_err) => {
+                action.outcome = TopUpOutcome::BalanceUnavailable(data_err);
+                return action;
+            }
+        };
+        let below_threshold = balance < self.threshold;
+        action.cycle_balance = Some(balance);
+        if !below_threshold {
+            return action;
+        }
+
+        let spent_in_period = self.spent_in_period();
+        // Past `u64` is past any budget
+        if spent_in_period.checked_add(self.icp_amount).is_none_or(|spent| spent > self.max_spend_per_period) {
+            action.outcome = TopUpOutcome::BudgetExhausted { spent_in_period };
+            return action;
+        }
+        if self.dry_run {
+            action.outcome = TopUpOutcome::WouldTopUp { icp_amount: self.icp_amount };
+            return action;
+        }
+        action.outcome = match metabox::top_up_box(pem_identity_path, action.canister_id, self.icp_amount).await {
+            TopUpBoxResult::ok => {
+                self.spent.push((Instant::now(), self.icp_amount));
+                TopUpOutcome::ToppedUp { icp_amount: self.icp_amount }
+            }
+            TopUpBoxResult::err(error) => TopUpOutcome::TopUpFailed(error),
+        };
+        action
+    }
+}
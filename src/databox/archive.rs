@@ -0,0 +1,505 @@
+//! Export of a box to a tar archive, and import back
+//!
+//! An archive holds `inventory.json` first, then each plain file of the box as `files/<file_key>`,
+//! the [internal](super::is_internal) files aside.
+//! The inventory lists the files with their key, name, MIME type and size :
+//!
+//! ```text
+//! { "version": 1, "canister_id": "4radi-oqaaa-aaaan-qapwa-cai",
+//!   "files": [ { "file_key": "...", "file_name": "bitcoin", "file_extension": "application/pdf",
+//!                "total_size": 184292, "path": "files/..." } ] }
+//! ```
+//!
+//! The key of each file is checked against its content when it is exported and when it is
+//! imported, and the file uploaded on import must be stored under the same key. A name longer than
+//! the 100 bytes of a tar header, as `files/` and a long key, is given by a pax extended header.
+//!
+//! Archives are plain tar. With the `zstd` feature, `export_box_compressed` writes a `.tar.zst`
+//! and [`import_box`] reads both, a compressed archive is recognized by its magic number. Without
+//! the feature, a compressed archive fails with [`ArchiveError::Format`].
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use bytes::Bytes;
+use serde_json::{json, Value};
+use crate::agent::CallError;
+use crate::transfer::Transfer;
+use super::key;
+use super::{is_internal, DataBoxBackend, DataBoxClient, DataErr, FileExt, PutPlainFileResult, UploadStatus};
+
+/// Name of the inventory in an archive
+pub const INVENTORY_NAME: &str = "inventory.json";
+
+/// Version of the inventory format
+pub const INVENTORY_VERSION: u64 = 1;
+
+const BLOCK_SIZE: usize = 512;
+
+// Names longer than the name field of a header go in a pax extended header
+const NAME_SIZE: usize = 100;
+const MAX_PAX_SIZE: u64 = 64 * 1024;
+
+// Sizes in headers are not trusted : the inventory is bounded, a file must have its size in the
+// inventory, and data is allocated as it is read
+const MAX_INVENTORY_SIZE: u64 = 64 * 1024 * 1024;
+const READ_CAPACITY: u64 = 1024 * 1024;
+
+// Name and data of an archive entry
+// Name and size of an entry
+type Header = (String, u64);
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// A file of an archive, as listed in its inventory
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchivedFile {
+    pub file_key: String,
+    pub file_name: String,
+    pub file_extension: String,
+    pub total_size: u64,
+}
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    Data(DataErr),
+    /// The content of a file does not give its key
+    KeyMismatch { file_key: String },
+    /// Not an archive of a box, or a damaged one
+    Format(String),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveError::Data(data_err) => write!(f, "data box error: {:?}", data_err),
+            ArchiveError::KeyMismatch { file_key } => write!(f, "content of file {} does not match its key", file_key),
+            ArchiveError::Format(error) => write!(f, "invalid archive: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
+
+impl From<DataErr> for ArchiveError {
+    fn from(data_err: DataErr) -> Self {
+        ArchiveError::Data(data_err)
+    }
+}
+
+/// Write every complete plain file of the box to `writer`, the files archived are answered
+pub async fn export_box<W: Write>(client: &DataBoxClient, writer: W) -> Result<Result<Vec<ArchivedFile>, ArchiveError>, CallError> {
+    export_box_with(client, writer, &Transfer::default()).await
+}
+
+/// [`export_box`] reporting the downloads to the transfer 's observer
+///
+/// Example code :
+/// ```
+/// use candid::Principal;
+/// use metabox_sdk::databox::DataBoxClient;
+/// use metabox_sdk::databox::archive::{export_box, import_box};
+/// use metabox_sdk::databox::mock::MockDataBox;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let owner = Principal::anonymous();
+///     let source = DataBoxClient::mock(&MockDataBox::new(Principal::from_slice(&[1; 10]), owner), owner);
+///     let put = source.put_bytes(b"hello".to_vec(), "hello.txt", "text/plain").await.unwrap();
+///
+///     let mut archive = Vec::new();
+///     let exported = export_box(&source, &mut archive).await.unwrap().unwrap();
+///     assert_eq!(exported[0].file_key, put.file_key);
+///
+///     let target = DataBoxClient::mock(&MockDataBox::new(Principal::from_slice(&[2; 10]), owner), owner);
+///     let imported = import_box(archive.as_slice(), &target).await.unwrap().unwrap();
+///     assert_eq!(imported[0].file_key, put.file_key);
+///     assert_eq!(target.get_plain_file(&put.file_key).await.unwrap().unwrap(), b"hello");
+/// }
+/// ```
+pub async fn export_box_with<W: Write>(
+    client: &DataBoxClient,
+    mut writer: W,
+    transfer: &Transfer,
+) -> Result<Result<Vec<ArchivedFile>, ArchiveError>, CallError> {
+    // Listed from the box and not from a cache, an archive holds what the box holds
+    let files: Vec<ArchivedFile> = match client.list().await? {
+        Ok(files) => files
+            .into_iter()
+            .filter(|file| !is_internal(file))
+            .filter_map(|file| match file {
+                FileExt::PlainFileExt(asset_ext) if asset_ext.upload_status => Some(ArchivedFile {
+                    file_key: asset_ext.file_key,
+                    file_name: asset_ext.file_name,
+                    file_extension: asset_ext.file_extension,
+                    total_size: asset_ext.total_size,
+                }),
+                _ => None,
+            })
+            .collect(),
+        Err(data_err) => return Ok(Err(data_err.into())),
+    };
+    let inventory = json!({
+        "version": INVENTORY_VERSION,
+        "canister_id": client.canister_id().to_text(),
+        "files": files.iter().map(|file| json!({
+            "file_key": file.file_key,
+            "file_name": file.file_name,
+            "file_extension": file.file_extension,
+            "total_size": file.total_size,
+            "path": file_path(&file.file_key),
+        })).collect::<Vec<_>>(),
+    });
+    let inventory = serde_json::to_vec_pretty(&inventory).map_err(io::Error::from)?;
+    write_entry(&mut writer, INVENTORY_NAME, &inventory)?;
+
+    for (index, file) in files.iter().enumerate() {
+        let data = match client.get_plain_file_with(&file.file_key, &transfer.in_batch(index, files.len())).await? {
+            Ok(data) => data,
+            Err(data_err) => return Ok(Err(data_err.into())),
+        };
+        if key::verify_file_key(data.as_slice(), &file.file_key)?.is_none() {
+            return Ok(Err(ArchiveError::KeyMismatch { file_key: file.file_key.clone() }));
+        }
+        write_entry(&mut writer, &file_path(&file.file_key), &data)?;
+    }
+    // The end of an archive is two zero blocks
+    writer.write_all(&[0; 2 * BLOCK_SIZE])?;
+    writer.flush()?;
+    Ok(Ok(files))
+}
+
+/// Upload the files of an archive written by [`export_box`] to the box
+pub async fn import_box<R: Read>(reader: R, client: &DataBoxClient) -> Result<Result<Vec<PutPlainFileResult>, ArchiveError>, CallError> {
+    import_box_with(reader, client, &Transfer::default()).await
+}
+
+/// [`export_box`] compressed with zstd at `level` (1 to 22, 0 for the default of zstd)
+///
+/// Example code :
+/// ```
+/// use candid::Principal;
+/// use metabox_sdk::databox::DataBoxClient;
+/// use metabox_sdk::databox::archive::{export_box_compressed, import_box};
+/// use metabox_sdk::databox::mock::MockDataBox;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let owner = Principal::anonymous();
+///     let source = DataBoxClient::mock(&MockDataBox::new(Principal::from_slice(&[1; 10]), owner), owner);
+///     let put = source.put_bytes(vec![7; 100_000], "sevens.bin", "application/octet-stream").await.unwrap();
+///
+///     let mut archive = Vec::new();
+///     export_box_compressed(&source, &mut archive, 3).await.unwrap().unwrap();
+///     assert!(archive.len() < 10_000);
+///
+///     let target = DataBoxClient::mock(&MockDataBox::new(Principal::from_slice(&[2; 10]), owner), owner);
+///     let imported = import_box(archive.as_slice(), &target).await.unwrap().unwrap();
+///     assert_eq!(imported[0].file_key, put.file_key);
+/// }
+/// ```
+#[cfg(feature = "zstd")]
+pub async fn export_box_compressed<W: Write>(
+    client: &DataBoxClient,
+    writer: W,
+    level: i32,
+) -> Result<Result<Vec<ArchivedFile>, ArchiveError>, CallError> {
+    export_box_compressed_with(client, writer, level, &Transfer::default()).await
+}
+
+/// [`export_box_compressed`] reporting the downloads to the transfer 's observer
+#[cfg(feature = "zstd")]
+pub async fn export_box_compressed_with<W: Write>(
+    client: &DataBoxClient,
+    writer: W,
+    level: i32,
+    transfer: &Transfer,
+) -> Result<Result<Vec<ArchivedFile>, ArchiveError>, CallError> {
+    let mut encoder = zstd::Encoder::new(writer, level)?;
+    let files = match export_box_with(client, &mut encoder, transfer).await? {
+        Ok(files) => files,
+        Err(error) => return Ok(Err(error)),
+    };
+    encoder.finish()?.flush()?;
+    Ok(Ok(files))
+}
+
+/// [`import_box`] reporting the uploads to the transfer 's observer
+///
+/// The files are uploaded as they are read, those the box already has are not sent again. Each
+/// must have the size the inventory gives it, it is checked before the file is read.
+///
+/// Example code :
+/// ```
+/// use candid::Principal;
+/// use metabox_sdk::databox::DataBoxClient;
+/// use metabox_sdk::databox::archive::{export_box, import_box, ArchiveError};
+/// use metabox_sdk::databox::mock::MockDataBox;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let owner = Principal::anonymous();
+///     let source = DataBoxClient::mock(&MockDataBox::new(Principal::from_slice(&[1; 10]), owner), owner);
+///     source.put_bytes(b"hello".to_vec(), "hello.txt", "text/plain").await.unwrap();
+///     let mut archive = Vec::new();
+///     export_box(&source, &mut archive).await.unwrap().unwrap();
+///
+///     // The inventory says 6 bytes, the entry holds 5
+///     let at = archive.windows(15).position(|window| window == b"\"total_size\": 5").unwrap();
+///     archive[at + 14] = b'6';
+///     let target = DataBoxClient::mock(&MockDataBox::new(Principal::from_slice(&[2; 10]), owner), owner);
+///     let imported = import_box(archive.as_slice(), &target).await.unwrap();
+///     assert!(matches!(imported, Err(ArchiveError::Format(_))));
+///     assert!(target.get_all_plain_files_info().await.unwrap().unwrap().is_empty());
+/// }
+/// ```
+pub async fn import_box_with<R: Read>(
+    mut reader: R,
+    client: &DataBoxClient,
+    transfer: &Transfer,
+) -> Result<Result<Vec<PutPlainFileResult>, ArchiveError>, CallError> {
+    // The magic number is read ahead, then given back to the reader
+    let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+    (&mut reader).take(ZSTD_MAGIC.len() as u64).read_to_end(&mut magic)?;
+    let compressed = magic == ZSTD_MAGIC;
+    let reader = io::Cursor::new(magic).chain(reader);
+    if compressed {
+        #[cfg(feature = "zstd")]
+        return import_entries(zstd::Decoder::new(reader)?, client, transfer).await;
+        #[cfg(not(feature = "zstd"))]
+        return Ok(Err(ArchiveError::Format("zstd compressed, the zstd feature is not enabled".to_string())));
+    }
+    import_entries(reader, client, transfer).await
+}
+
+async fn import_entries<R: Read>(
+    mut reader: R,
+    client: &DataBoxClient,
+    transfer: &Transfer,
+) -> Result<Result<Vec<PutPlainFileResult>, ArchiveError>, CallError> {
+    let inventory = match checked(read_header(&mut reader))? {
+        Ok(Some((name, size))) if name == INVENTORY_NAME && size <= MAX_INVENTORY_SIZE => {
+            let data = match checked(read_data(&mut reader, size))? {
+                Ok(data) => data,
+                Err(error) => return Ok(Err(error)),
+            };
+            match parse_inventory(&data) {
+                Ok(inventory) => inventory,
+                Err(error) => return Ok(Err(error)),
+            }
+        }
+        Ok(Some((name, size))) if name == INVENTORY_NAME => {
+            return Ok(Err(ArchiveError::Format(format!("{} of {} bytes", INVENTORY_NAME, size))))
+        }
+        Ok(Some((name, _))) => return Ok(Err(ArchiveError::Format(format!("{} before {}", name, INVENTORY_NAME)))),
+        Ok(None) => return Ok(Err(ArchiveError::Format("no inventory".to_string()))),
+        Err(error) => return Ok(Err(error)),
+    };
+    let mut results = Vec::new();
+    loop {
+        let (name, size) = match checked(read_header(&mut reader))? {
+            Ok(Some(header)) => header,
+            Ok(None) => break,
+            Err(error) => return Ok(Err(error)),
+        };
+        let file = match inventory.iter().find(|file| file_path(&file.file_key) == name) {
+            Some(file) => file,
+            None => return Ok(Err(ArchiveError::Format(format!("{} is not in the inventory", name)))),
+        };
+        if size != file.total_size {
+            let message = format!("{} of {} bytes, {} in the inventory", name, size, file.total_size);
+            return Ok(Err(ArchiveError::Format(message)));
+        }
+        let data = match checked(read_data(&mut reader, size))? {
+            Ok(data) => data,
+            Err(error) => return Ok(Err(error)),
+        };
+        // Uploaded with the scheme the key was computed with, the box stores it under the same key
+        let key_scheme = match key::verify_file_key(data.as_slice(), &file.file_key)? {
+            Some(key_scheme) => key_scheme,
+            None => return Ok(Err(ArchiveError::KeyMismatch { file_key: file.file_key.clone() })),
+        };
+        let target = client.clone().with_key_scheme(key_scheme);
+        let transfer = transfer.in_batch(results.len(), inventory.len());
+        let result = target.put_bytes_with(Bytes::from(data), &file.file_name, Some(&file.file_extension), &transfer).await?;
+        match &result.upload_status {
+            UploadStatus::Err(data_err) => return Ok(Err(data_err.clone().into())),
+            UploadStatus::Cancelled { .. } => return Err(CallError::Cancelled),
+            UploadStatus::Ok | UploadStatus::AlreadyExists if result.file_key != file.file_key => {
+                return Ok(Err(ArchiveError::KeyMismatch { file_key: file.file_key.clone() }))
+            }
+            UploadStatus::Ok | UploadStatus::AlreadyExists => results.push(result),
+        }
+    }
+    if results.len() != inventory.len() {
+        let message = format!("{} files in the inventory, {} in the archive", inventory.len(), results.len());
+        return Ok(Err(ArchiveError::Format(message)));
+    }
+    Ok(Ok(results))
+}
+
+// A damaged archive is an `ArchiveError` and a failed read a `CallError`
+fn checked<T>(read: io::Result<T>) -> Result<Result<T, ArchiveError>, CallError> {
+    match read {
+        Ok(value) => Ok(Ok(value)),
+        Err(error) if error.kind() == io::ErrorKind::InvalidData => Ok(Err(ArchiveError::Format(error.to_string()))),
+        Err(error) => Err(error.into()),
+    }
+}
+
+fn file_path(file_key: &str) -> String {
+    format!("files/{}", file_key)
+}
+
+fn parse_inventory(data: &[u8]) -> Result<Vec<ArchivedFile>, ArchiveError> {
+    let format = |message: &str| ArchiveError::Format(format!("{} : {}", INVENTORY_NAME, message));
+    let inventory: Value = serde_json::from_slice(data).map_err(|error| format(&error.to_string()))?;
+    if inventory["version"].as_u64() != Some(INVENTORY_VERSION) {
+        return Err(format("unknown version"));
+    }
+    let files = inventory["files"].as_array().ok_or_else(|| format("no files"))?;
+    files.iter().map(|file| archived_file(file).ok_or_else(|| format("incomplete file"))).collect()
+}
+
+fn archived_file(file: &Value) -> Option<ArchivedFile> {
+    Some(ArchivedFile {
+        file_key: file["file_key"].as_str()?.to_string(),
+        file_name: file["file_name"].as_str()?.to_string(),
+        file_extension: file["file_extension"].as_str()?.to_string(),
+        total_size: file["total_size"].as_u64()?,
+    })
+}
+
+// A ustar entry : a header block, then the data padded to whole blocks. A longer name than the
+// header holds is given by a pax extended header before it, the header keeps its beginning.
+fn write_entry<W: Write>(writer: &mut W, name: &str, data: &[u8]) -> io::Result<()> {
+    if name.len() > NAME_SIZE {
+        let record = pax_record("path", name);
+        write_header(writer, &format!("PaxHeaders/{}", name), b'x', record.len())?;
+        writer.write_all(&record)?;
+        writer.write_all(&vec![0; padding(record.len())])?;
+    }
+    write_header(writer, name, b'0', data.len())?;
+    writer.write_all(data)?;
+    writer.write_all(&vec![0; padding(data.len())])
+}
+
+fn write_header<W: Write>(writer: &mut W, name: &str, typeflag: u8, size: usize) -> io::Result<()> {
+    let mut header = [0u8; BLOCK_SIZE];
+    let name = &name.as_bytes()[..name.len().min(NAME_SIZE)];
+    header[..name.len()].copy_from_slice(name);
+    write_octal(&mut header[100..108], 0o644);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size as u64);
+    write_octal(&mut header[136..148], SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+    header[156] = typeflag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // The checksum is computed with its own field as spaces
+    header[148..156].copy_from_slice(&[b' '; 8]);
+    let checksum: u64 = header.iter().map(|byte| *byte as u64).sum();
+    write_octal(&mut header[148..155], checksum);
+    writer.write_all(&header)
+}
+
+// `<length> <key>=<value>\n`, the length counting its own digits
+fn pax_record(key: &str, value: &str) -> Vec<u8> {
+    let body = format!(" {}={}\n", key, value);
+    let mut length = body.len() + 1;
+    while length.to_string().len() + body.len() != length {
+        length = length.to_string().len() + body.len();
+    }
+    format!("{}{}", length, body).into_bytes()
+}
+
+// The `path` of pax extended header records
+fn pax_path(mut records: &[u8]) -> io::Result<Option<String>> {
+    let mut path = None;
+    while !records.is_empty() {
+        let space = records.iter().position(|byte| *byte == b' ').ok_or_else(|| invalid("bad pax record"))?;
+        let length: usize = std::str::from_utf8(&records[..space]).ok().and_then(|length| length.parse().ok()).ok_or_else(|| invalid("bad pax record"))?;
+        if length <= space + 1 || length > records.len() || records[length - 1] != b'\n' {
+            return Err(invalid("bad pax record"));
+        }
+        let record = String::from_utf8_lossy(&records[space + 1..length - 1]);
+        if let Some(value) = record.strip_prefix("path=") {
+            path = Some(value.to_string());
+        }
+        records = &records[length..];
+    }
+    Ok(path)
+}
+
+// The header of the next file entry, `None` at the end of the archive. Its data is read next by
+// `read_data`.
+fn read_header<R: Read>(reader: &mut R) -> io::Result<Option<Header>> {
+    let mut path = None;
+    loop {
+        let mut header = [0u8; BLOCK_SIZE];
+        reader.read_exact(&mut header)?;
+        if header.iter().all(|byte| *byte == 0) {
+            return Ok(None);
+        }
+        if &header[257..262] != b"ustar" {
+            return Err(invalid("not a tar archive"));
+        }
+        let expected = read_octal(&header[148..156])?;
+        header[148..156].copy_from_slice(&[b' '; 8]);
+        if header.iter().map(|byte| *byte as u64).sum::<u64>() != expected {
+            return Err(invalid("bad header checksum"));
+        }
+        let size = read_octal(&header[124..136])?;
+        match header[156] {
+            b'x' if size <= MAX_PAX_SIZE => {
+                let records = read_data(reader, size)?;
+                path = pax_path(&records)?.or(path);
+            }
+            b'x' => return Err(invalid("pax header too large")),
+            b'0' | 0 => {
+                let name = match path {
+                    Some(path) => path,
+                    None => {
+                        let name_end = header[..NAME_SIZE].iter().position(|byte| *byte == 0).unwrap_or(NAME_SIZE);
+                        String::from_utf8_lossy(&header[..name_end]).into_owned()
+                    }
+                };
+                return Ok(Some((name, size)));
+            }
+            // Directories and other entries are not files of the box
+            _ => {
+                io::copy(&mut reader.take(size + padding(size as usize) as u64), &mut io::sink())?;
+            }
+        }
+    }
+}
+
+// The `size` bytes of data of an entry and their padding, a shorter archive is an error
+fn read_data<R: Read>(reader: &mut R, size: u64) -> io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(size.min(READ_CAPACITY) as usize);
+    reader.take(size).read_to_end(&mut data)?;
+    if (data.len() as u64) < size {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    io::copy(&mut reader.take(padding(data.len()) as u64), &mut io::sink())?;
+    Ok(data)
+}
+
+fn padding(size: usize) -> usize {
+    (BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE
+}
+
+// Octal digits filling the field but its last byte, which ends it
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+fn read_octal(field: &[u8]) -> io::Result<u64> {
+    let digits = String::from_utf8_lossy(field);
+    let digits = digits.trim_matches(|c: char| c == '\0' || c == ' ');
+    u64::from_str_radix(digits, 8).map_err(|_| invalid("bad number in header"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
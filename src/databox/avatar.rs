@@ -0,0 +1,123 @@
+//! Avatars of boxes
+//!
+//! An avatar is prepared before it is sent with `upload` : the image (PNG, JPEG, GIF, WebP or
+//! BMP) is decoded, cropped to its centered square, scaled down to [`AvatarOptions::size`] and
+//! encoded again, as JPEG when it is opaque and as PNG when it has transparency.
+//!
+//! The DataBox interface has no query of the avatar : the client keeps a copy of it in the box,
+//! as the file [`AVATAR_NAME`], which [`DataBoxClient::get_avatar`](super::DataBoxClient::get_avatar)
+//! reads.
+use std::fmt;
+use super::image::{self, ImageError};
+use super::{Avatar, DataErr, UPDATE_SIZE};
+
+/// Name of the copy of the avatar kept in the box
+pub const AVATAR_NAME: &str = ".metabox-avatar";
+
+/// Side of avatars, in pixels
+pub const DEFAULT_SIZE: u32 = 256;
+
+/// Size of avatars, in bytes
+pub const DEFAULT_MAX_BYTES: usize = 1024 * 1024;
+
+/// Quality of opaque avatars, encoded as JPEG
+pub const JPEG_QUALITY: u8 = 85;
+
+#[derive(Clone, Debug)]
+pub struct AvatarOptions {
+    /// Larger images are scaled down to `size` x `size` pixels, at least 1
+    pub size: u32,
+    /// Larger avatars are refused before they are sent, at most the size of one call
+    pub max_bytes: usize,
+}
+
+impl Default for AvatarOptions {
+    fn default() -> Self {
+        AvatarOptions {
+            size: DEFAULT_SIZE,
+            max_bytes: DEFAULT_MAX_BYTES,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AvatarError {
+    Image(ImageError),
+    /// The avatar is `size` bytes, more than `limit`
+    TooLarge { size: usize, limit: usize },
+    Data(DataErr),
+}
+
+impl fmt::Display for AvatarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AvatarError::Image(error) => write!(f, "{}", error),
+            AvatarError::TooLarge { size, limit } => write!(f, "avatar of {} bytes, the limit is {}", size, limit),
+            AvatarError::Data(data_err) => write!(f, "data box error: {:?}", data_err),
+        }
+    }
+}
+
+impl std::error::Error for AvatarError {}
+
+impl From<ImageError> for AvatarError {
+    fn from(error: ImageError) -> Self {
+        AvatarError::Image(error)
+    }
+}
+
+impl From<DataErr> for AvatarError {
+    fn from(data_err: DataErr) -> Self {
+        AvatarError::Data(data_err)
+    }
+}
+
+/// The avatar `upload` is called with for the image `data`
+///
+/// Example code :
+/// ```
+/// use std::io::Cursor;
+/// use metabox_sdk::databox::avatar::{prepare_avatar, AvatarOptions};
+/// use metabox_sdk::databox::image::{self, DynamicImage};
+///
+/// // A photo as a camera writes it, JPEG and not square
+/// let photo = DynamicImage::ImageRgb8(::image::RgbImage::from_fn(640, 480, |x, y| ::image::Rgb([x as u8, y as u8, 90])));
+/// let mut jpeg = Vec::new();
+/// photo.write_to(&mut Cursor::new(&mut jpeg), ::image::ImageOutputFormat::Jpeg(90)).unwrap();
+///
+/// let options = AvatarOptions { size: 128, ..AvatarOptions::default() };
+/// let avatar = prepare_avatar(&jpeg, &options).unwrap();
+/// assert_eq!(avatar.data_type, "image/jpeg");
+/// assert_eq!(image::dimensions(&avatar.data), Some((128, 128)));
+///
+/// // Transparency is kept, as PNG
+/// let logo = image::encode_png(&DynamicImage::new_rgba8(300, 300)).unwrap();
+/// let avatar = prepare_avatar(&logo, &options).unwrap();
+/// assert_eq!(avatar.data_type, "image/png");
+/// assert_eq!(image::dimensions(&avatar.data), Some((128, 128)));
+/// assert!(prepare_avatar(&logo, &AvatarOptions { size: 0, ..options }).is_err());
+/// ```
+pub fn prepare_avatar(data: &[u8], options: &AvatarOptions) -> Result<Avatar, AvatarError> {
+    if options.size == 0 {
+        return Err(ImageError::Invalid("avatar size of 0 pixels".to_string()).into());
+    }
+    let square = image::square_crop(&image::decode(data)?);
+    let side = square.width().min(options.size);
+    let square = if side == square.width() { square } else { image::resize(&square, side, side) };
+    let avatar = if image::is_opaque(&square) {
+        Avatar {
+            data: image::encode_jpeg(&square, JPEG_QUALITY)?,
+            data_type: "image/jpeg".to_string(),
+        }
+    } else {
+        Avatar {
+            data: image::encode_png(&square)?,
+            data_type: "image/png".to_string(),
+        }
+    };
+    let limit = options.max_bytes.min(UPDATE_SIZE);
+    if avatar.data.len() > limit {
+        return Err(AvatarError::TooLarge { size: avatar.data.len(), limit });
+    }
+    Ok(avatar)
+}
@@ -0,0 +1,464 @@
+//! Storage operations of a DataBox, independent of where the box lives
+//!
+//! [`DataBoxBackend`] is the small set of canister calls everything else is built on. The
+//! [`DataBoxClient`] (Internet Computer or mock) is one implementation, [`LocalDirBackend`]
+//! keeps the box in a local directory. Code written against the trait, like the chunked
+//! upload and download below or the FUSE mount, works with any of them.
+//!
+//! [`LocalDirBackend`]: super::local::LocalDirBackend
+use async_trait::async_trait;
+use bytes::Bytes;
+use candid::{Encode, Nat, Principal};
+use crate::agent::CallError;
+use crate::transfer::{Direction, Transfer, TransferEvent, TransferOutcome};
+use super::capacity::{self, CapacityPolicy, PlannedFile};
+use super::key::{self, KeyScheme};
+use super::{
+    build_put_plain_arg, slice_data, AvlSMResult, CanisterStateResult, DataBoxClient, DataErr, DeleteKeyResult, FileExt,
+    FilePut, GetAssetExtKeyResult, GetAssetExtsResult, GetPlainResult, PutPlainFileResult, PutResult, UploadStatus, GET,
+    UPDATE_SIZE,
+};
+
+#[async_trait]
+pub trait DataBoxBackend: Send + Sync {
+    /// Id of the box, reported in upload results
+    fn canister_id(&self) -> Principal;
+
+    /// Scheme of the keys of the files uploaded to the box
+    fn key_scheme(&self) -> KeyScheme {
+        KeyScheme::V1
+    }
+
+    /// Store one segment (chunk) of a file, as part of `transfer`
+    async fn put_segment(&self, put: &FilePut, transfer: &Transfer) -> Result<PutResult, CallError>;
+
+    /// Get chunk `index` of a plain file, as part of `transfer`
+    async fn get_chunk(&self, file_key: &str, index: u64, transfer: &Transfer) -> Result<GetPlainResult, CallError>;
+
+    /// Information of every plain file
+    async fn list(&self) -> Result<Result<Vec<FileExt>, DataErr>, CallError>;
+
+    /// Information of one file
+    async fn info(&self, file_key: &str) -> Result<Result<FileExt, DataErr>, CallError>;
+
+    async fn delete(&self, file_key: &str) -> Result<DeleteKeyResult, CallError>;
+
+    async fn state(&self) -> Result<CanisterStateResult, CallError>;
+
+    /// Available stable memory, in bytes
+    async fn available_memory(&self) -> Result<AvlSMResult, CallError>;
+}
+
+#[async_trait]
+impl DataBoxBackend for DataBoxClient {
+    fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+
+    fn key_scheme(&self) -> KeyScheme {
+        self.key_scheme
+    }
+
+    async fn put_segment(&self, put: &FilePut, transfer: &Transfer) -> Result<PutResult, CallError> {
+        let result = self.update_observed("put", Encode!(put)?, transfer.observer()).await;
+        self.invalidate_cache();
+        result
+    }
+
+    async fn get_chunk(&self, file_key: &str, index: u64, transfer: &Transfer) -> Result<GetPlainResult, CallError> {
+        let arg = GET {
+            flag: Nat::from(index),
+            file_key: file_key.to_string(),
+        };
+        self.update_observed("getPlain", Encode!(&arg)?, transfer.observer()).await
+    }
+
+    async fn list(&self) -> Result<Result<Vec<FileExt>, DataErr>, CallError> {
+        let response: GetAssetExtsResult = self.query("getAssetexts", Encode!()?).await?;
+        Ok(match response {
+            GetAssetExtsResult::ok(plain_assets, ..) => Ok(plain_assets),
+            GetAssetExtsResult::err(data_err) => Err(data_err),
+        })
+    }
+
+    async fn info(&self, file_key: &str) -> Result<Result<FileExt, DataErr>, CallError> {
+        let response: GetAssetExtKeyResult = self.query("getAssetextkey", Encode!(&file_key)?).await?;
+        Ok(match response {
+            GetAssetExtKeyResult::ok(file_ext) => Ok(file_ext),
+            GetAssetExtKeyResult::err(data_err) => Err(data_err),
+        })
+    }
+
+    async fn delete(&self, file_key: &str) -> Result<DeleteKeyResult, CallError> {
+        let result = self.update("deletekey", Encode!(&file_key)?).await;
+        self.invalidate_cache();
+        result
+    }
+
+    async fn state(&self) -> Result<CanisterStateResult, CallError> {
+        self.query("canisterState", Encode!()?).await
+    }
+
+    async fn available_memory(&self) -> Result<AvlSMResult, CallError> {
+        self.query("avlSM", Encode!()?).await
+    }
+}
+
+/// Upload `data` as a plain file, segment by segment, stopping at the first `DataErr`
+///
+/// The box is first asked for the file key : a complete file of the same size is not uploaded
+/// again (`UploadStatus::AlreadyExists`) and an incomplete one is completed, see [`Transfer`].
+///
+/// A cancelled `transfer` stops before the next segment and answers `UploadStatus::Cancelled`.
+/// The box does not tell which segments of an incomplete file it holds : the upload skips the
+/// `committed_chunks` of that status when they are given to `Transfer::with_committed_chunks`,
+/// else the incomplete copy is deleted and the file uploaded again. Empty data is not a file of
+/// the box, it answers `UploadStatus::Err(DataErr::BlobSizeError)`.
+///
+/// Example code :
+/// ```
+/// use metabox_sdk::databox::{backend, UploadStatus};
+/// use metabox_sdk::databox::local::LocalDirBackend;
+/// use metabox_sdk::transfer::Transfer;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let root = std::env::temp_dir().join("metabox-backend-example");
+///     let _ = std::fs::remove_dir_all(&root);
+///     let local = LocalDirBackend::new(&root).unwrap();
+///     let transfer = Transfer::new();
+///     let put = backend::put_plain_data(&local, "hello".to_string(), "text/plain".to_string(), b"hello".to_vec(), &transfer).await.unwrap();
+///     assert!(matches!(put.upload_status, UploadStatus::Ok));
+///     assert_eq!(backend::get_plain_data(&local, &put.file_key, &transfer).await.unwrap().unwrap(), b"hello");
+/// }
+/// ```
+///
+/// Empty data is refused before anything is sent :
+/// ```
+/// use candid::Principal;
+/// use metabox_sdk::databox::{backend, DataBoxClient, DataErr, UploadStatus};
+/// use metabox_sdk::databox::mock::MockDataBox;
+/// use metabox_sdk::transfer::Transfer;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let owner = Principal::anonymous();
+///     let data_box = MockDataBox::new(Principal::management_canister(), owner);
+///     let client = DataBoxClient::mock(&data_box, owner);
+///     let put = backend::put_plain_data(&client, "empty".to_string(), "text/plain".to_string(), Vec::new(), &Transfer::new()).await.unwrap();
+///     assert!(matches!(put.upload_status, UploadStatus::Err(DataErr::BlobSizeError)));
+///     assert!(client.get_file_info(&put.file_key).await.unwrap().is_err());
+///     assert_eq!(data_box.file_count(), 0);
+/// }
+/// ```
+pub async fn put_plain_data<B: DataBoxBackend + ?Sized>(
+    backend: &B,
+    file_name: String,
+    file_extension: String,
+    data: impl Into<Bytes>,
+    transfer: &Transfer,
+) -> Result<PutPlainFileResult, CallError> {
+    put_slices(backend, file_name, file_extension, data.into(), transfer).await
+}
+
+pub(crate) async fn put_slices<B: DataBoxBackend + ?Sized>(
+    backend: &B,
+    file_name: String,
+    file_extension: String,
+    data: Bytes,
+    transfer: &Transfer,
+) -> Result<PutPlainFileResult, CallError> {
+    let (file_size, slice_size, data_slice) = slice_data(data);
+    let file_key = key::key_of_chunks(&data_slice, backend.key_scheme());
+    let total_size = file_size as u64;
+    let (index, count) = transfer.position();
+    transfer.emit(TransferEvent::FileStarted {
+        direction: Direction::Upload,
+        file_name: file_name.clone(),
+        file_key: file_key.clone(),
+        index,
+        count,
+        total_size,
+        chunk_number: slice_size as u64,
+    });
+
+    let result = |upload_status| PutPlainFileResult {
+        file_name: file_name.clone(),
+        file_extension: file_extension.clone(),
+        file_key: file_key.clone(),
+        upload_status,
+        databox_canister_id: backend.canister_id(),
+        total_size,
+        chunk_number: slice_size as u64,
+    };
+    // A file has at least one segment
+    if data_slice.is_empty() {
+        finished(transfer, Direction::Upload, &file_key, 0, TransferOutcome::Failed(format!("{:?}", DataErr::BlobSizeError)));
+        return Ok(result(UploadStatus::Err(DataErr::BlobSizeError)));
+    }
+
+    // Chunks stored by an earlier transfer of the file, as it reported them, are not sent again
+    let mut committed_chunks = Vec::new();
+    if transfer.dedup() {
+        match backend.info(&file_key).await {
+            Ok(Ok(FileExt::PlainFileExt(asset_ext))) if asset_ext.total_size == total_size => {
+                if asset_ext.upload_status {
+                    finished(transfer, Direction::Upload, &file_key, 0, TransferOutcome::AlreadyExists);
+                    return Ok(result(UploadStatus::AlreadyExists));
+                }
+                committed_chunks = transfer.committed_chunks().to_vec();
+                // Which chunks the box holds is unknown, the incomplete copy is started over
+                if committed_chunks.is_empty() {
+                    match backend.delete(&file_key).await {
+                        Ok(DeleteKeyResult::ok(_)) => {}
+                        Ok(DeleteKeyResult::err(data_err)) => {
+                            finished(transfer, Direction::Upload, &file_key, 0, TransferOutcome::Failed(format!("{:?}", data_err)));
+                            return Ok(result(UploadStatus::Err(data_err)));
+                        }
+                        Err(error) => {
+                            finished(transfer, Direction::Upload, &file_key, 0, TransferOutcome::Failed(error.to_string()));
+                            return Err(error);
+                        }
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(error) => {
+                finished(transfer, Direction::Upload, &file_key, 0, TransferOutcome::Failed(error.to_string()));
+                return Err(error);
+            }
+        }
+    }
+
+    let policy = transfer.capacity_policy();
+    if policy != CapacityPolicy::Unchecked {
+        let planned = PlannedFile {
+            file_name: file_name.clone(),
+            file_extension: file_extension.clone(),
+            file_key: file_key.clone(),
+            total_size,
+            chunk_number: slice_size as u64,
+            footprint: capacity::footprint(total_size, slice_size as u64),
+        };
+        let rejection = match capacity::plan_upload(backend, vec![planned]).await {
+            Ok(Ok(plan)) if plan.rejected.is_empty() => None,
+            Ok(Ok(plan)) if policy == CapacityPolicy::Reject => Some(Err(CallError::Capacity(Box::new(plan.error())))),
+            Ok(Ok(_)) => Some(Ok(UploadStatus::Err(DataErr::MemoryInsufficient))),
+            Ok(Err(data_err)) => Some(Ok(UploadStatus::Err(data_err))),
+            Err(error) => Some(Err(error)),
+        };
+        match rejection {
+            None => {}
+            Some(Ok(upload_status)) => {
+                finished(transfer, Direction::Upload, &file_key, 0, TransferOutcome::Failed(format!("{:?}", upload_status)));
+                return Ok(result(upload_status));
+            }
+            Some(Err(error)) => {
+                finished(transfer, Direction::Upload, &file_key, 0, TransferOutcome::Failed(error.to_string()));
+                return Err(error);
+            }
+        }
+    }
+
+    let mut upload_status = UploadStatus::Ok;
+    let mut transferred = 0;
+    for (order, data) in data_slice.iter().enumerate() {
+        if committed_chunks.contains(&(order as u64)) {
+            continue;
+        }
+        if transfer.is_cancelled() {
+            committed_chunks.sort_unstable();
+            upload_status = UploadStatus::Cancelled { committed_chunks };
+            break;
+        }
+        let put = build_put_plain_arg(&file_key, &file_name, &file_extension, total_size, slice_size as u64, order as u64, data);
+        let bytes = data.len() as u64;
+        transfer.emit(TransferEvent::ChunkSent {
+            file_key: file_key.clone(),
+            index: order as u64,
+            bytes,
+        });
+        match backend.put_segment(&put, transfer).await {
+            Ok(PutResult::ok(_)) => {
+                transferred += bytes;
+                committed_chunks.push(order as u64);
+                transfer.emit(TransferEvent::ChunkAcked {
+                    file_key: file_key.clone(),
+                    index: order as u64,
+                    bytes,
+                    transferred,
+                    total_size,
+                });
+            }
+            // The file is complete : stored meanwhile, or by a first attempt of this put
+            Ok(PutResult::err(DataErr::FileRepeat)) if transfer.idempotent() => break,
+            Ok(PutResult::err(data_err)) => {
+                upload_status = UploadStatus::Err(data_err);
+                break;
+            }
+            Err(error) => {
+                finished(transfer, Direction::Upload, &file_key, transferred, TransferOutcome::Failed(error.to_string()));
+                return Err(error);
+            }
+        }
+    }
+    let outcome = match &upload_status {
+        UploadStatus::Ok => TransferOutcome::Completed,
+        UploadStatus::AlreadyExists => TransferOutcome::AlreadyExists,
+        UploadStatus::Err(data_err) => TransferOutcome::Failed(format!("{:?}", data_err)),
+        UploadStatus::Cancelled { .. } => TransferOutcome::Cancelled,
+    };
+    finished(transfer, Direction::Upload, &file_key, transferred, outcome);
+    Ok(result(upload_status))
+}
+
+/// Download a whole plain file, chunk by chunk, a cancelled `transfer` answers `CallError::Cancelled`
+pub async fn get_plain_data<B: DataBoxBackend + ?Sized>(
+    backend: &B,
+    file_key: &str,
+    transfer: &Transfer,
+) -> Result<Result<Vec<u8>, DataErr>, CallError> {
+    let asset_ext = match backend.info(file_key).await? {
+        Ok(FileExt::PlainFileExt(asset_ext)) => asset_ext,
+        Ok(_) => return Ok(Err(DataErr::FileKeyErr)),
+        Err(data_err) => return Ok(Err(data_err)),
+    };
+    let (index, count) = transfer.position();
+    transfer.emit(TransferEvent::FileStarted {
+        direction: Direction::Download,
+        file_name: asset_ext.file_name.clone(),
+        file_key: file_key.to_string(),
+        index,
+        count,
+        total_size: asset_ext.total_size,
+        chunk_number: super::nat_to_u64(&asset_ext.need_query_times).unwrap_or(0),
+    });
+
+    let mut ans: Vec<u8> = Vec::new();
+    let mut i: u64 = 0;
+    while asset_ext.need_query_times > i {
+        if transfer.is_cancelled() {
+            finished(transfer, Direction::Download, file_key, ans.len() as u64, TransferOutcome::Cancelled);
+            return Err(CallError::Cancelled);
+        }
+        let response = match backend.get_chunk(file_key, i, transfer).await {
+            Ok(response) => response,
+            Err(error) => {
+                finished(transfer, Direction::Download, file_key, ans.len() as u64, TransferOutcome::Failed(error.to_string()));
+                return Err(error);
+            }
+        };
+        match response {
+            GetPlainResult::ok(mut payload) => {
+                let bytes = payload.len() as u64;
+                ans.append(&mut payload);
+                transfer.emit(TransferEvent::ChunkAcked {
+                    file_key: file_key.to_string(),
+                    index: i,
+                    bytes,
+                    transferred: ans.len() as u64,
+                    total_size: asset_ext.total_size,
+                });
+            }
+            GetPlainResult::err(data_err) => {
+                finished(transfer, Direction::Download, file_key, ans.len() as u64, TransferOutcome::Failed(format!("{:?}", data_err)));
+                return Ok(Err(data_err));
+            }
+        }
+        i += 1;
+    }
+    finished(transfer, Direction::Download, file_key, ans.len() as u64, TransferOutcome::Completed);
+    Ok(Ok(ans))
+}
+
+/// Download `len` bytes of a plain file from `offset`, fetching only the chunks covering them
+///
+/// Chunk `i` holds the bytes from `i * UPDATE_SIZE`. The range is cut at the end of the file, a
+/// range past it answers no bytes. A cancelled `transfer` answers `CallError::Cancelled`, a chunk
+/// shorter than its place in the file `DataErr::BlobSizeError`.
+pub async fn get_plain_range<B: DataBoxBackend + ?Sized>(
+    backend: &B,
+    file_key: &str,
+    offset: u64,
+    len: u64,
+    transfer: &Transfer,
+) -> Result<Result<Vec<u8>, DataErr>, CallError> {
+    let asset_ext = match backend.info(file_key).await? {
+        Ok(FileExt::PlainFileExt(asset_ext)) => asset_ext,
+        Ok(_) => return Ok(Err(DataErr::FileKeyErr)),
+        Err(data_err) => return Ok(Err(data_err)),
+    };
+    let end = offset.saturating_add(len).min(asset_ext.total_size);
+    if offset >= end {
+        return Ok(Ok(Vec::new()));
+    }
+    let chunk_size = UPDATE_SIZE as u64;
+    let chunks = offset / chunk_size..=(end - 1) / chunk_size;
+    let (index, count) = transfer.position();
+    transfer.emit(TransferEvent::FileStarted {
+        direction: Direction::Download,
+        file_name: asset_ext.file_name.clone(),
+        file_key: file_key.to_string(),
+        index,
+        count,
+        total_size: end - offset,
+        chunk_number: chunks.end() - chunks.start() + 1,
+    });
+
+    let mut ans: Vec<u8> = Vec::with_capacity((end - offset) as usize);
+    for i in chunks {
+        if transfer.is_cancelled() {
+            finished(transfer, Direction::Download, file_key, ans.len() as u64, TransferOutcome::Cancelled);
+            return Err(CallError::Cancelled);
+        }
+        let response = match backend.get_chunk(file_key, i, transfer).await {
+            Ok(response) => response,
+            Err(error) => {
+                finished(transfer, Direction::Download, file_key, ans.len() as u64, TransferOutcome::Failed(error.to_string()));
+                return Err(error);
+            }
+        };
+        match response {
+            GetPlainResult::ok(payload) => {
+                let chunk_start = i * chunk_size;
+                let expected = chunk_size.min(asset_ext.total_size - chunk_start);
+                if (payload.len() as u64) < expected {
+                    // The file was not cut as expected, the range would miss bytes
+                    let message = format!("chunk {} of {} bytes, {} expected", i, payload.len(), expected);
+                    finished(transfer, Direction::Download, file_key, ans.len() as u64, TransferOutcome::Failed(message));
+                    return Ok(Err(DataErr::BlobSizeError));
+                }
+                let from = (offset.max(chunk_start) - chunk_start) as usize;
+                let to = (end - chunk_start).min(expected) as usize;
+                ans.extend_from_slice(&payload[from..to]);
+                transfer.emit(TransferEvent::ChunkAcked {
+                    file_key: file_key.to_string(),
+                    index: i,
+                    bytes: (to - from) as u64,
+                    transferred: ans.len() as u64,
+                    total_size: end - offset,
+                });
+            }
+            GetPlainResult::err(data_err) => {
+                finished(transfer, Direction::Download, file_key, ans.len() as u64, TransferOutcome::Failed(format!("{:?}", data_err)));
+                return Ok(Err(data_err));
+            }
+        }
+    }
+    if ans.len() as u64 != end - offset {
+        let message = format!("{} bytes of {}", ans.len(), end - offset);
+        finished(transfer, Direction::Download, file_key, ans.len() as u64, TransferOutcome::Failed(message));
+        return Ok(Err(DataErr::BlobSizeError));
+    }
+    finished(transfer, Direction::Download, file_key, ans.len() as u64, TransferOutcome::Completed);
+    Ok(Ok(ans))
+}
+
+pub(crate) fn finished(transfer: &Transfer, direction: Direction, file_key: &str, transferred: u64, outcome: TransferOutcome) {
+    transfer.emit(TransferEvent::FileFinished {
+        direction,
+        file_key: file_key.to_string(),
+        transferred,
+        outcome,
+    });
+}
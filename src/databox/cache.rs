@@ -0,0 +1,149 @@
+//! On-disk cache of box inventories
+//!
+//! `getAssetexts` answers the whole inventory of a box on every call. An [`InventoryCache`] keeps
+//! the last inventory of each box in a directory, one file per canister id, and a client with
+//! [`DataBoxClient::with_cache`](super::DataBoxClient::with_cache) answers
+//! `get_all_plain_files_info`, `get_all_files_info` and `get_file_info` from it while it is younger
+//! than the TTL.
+//!
+//! The client drops the inventory of its box on each of its own mutations (`put`, `deletekey`,
+//! `clearall`). Changes made by other clients show once the inventory expires.
+//!
+//! When the box can not be reached, or when the cache is offline, the last inventory is answered
+//! whatever its age : listing and stat work read-only without a network.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use super::FileExt;
+
+/// How long an inventory is used before the box is listed again
+pub const DEFAULT_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Debug)]
+pub struct InventoryCache {
+    dir: PathBuf,
+    ttl: Duration,
+    offline: bool,
+}
+
+/// The files of a box, plain, encrypted and shared, as listed at `fetched_at`
+#[derive(Clone, Debug)]
+pub struct Inventory {
+    pub fetched_at: SystemTime,
+    pub files: Vec<FileExt>,
+}
+
+impl Inventory {
+    pub fn age(&self) -> Duration {
+        SystemTime::now().duration_since(self.fetched_at).unwrap_or_default()
+    }
+
+    pub fn find(&self, file_key: &str) -> Option<&FileExt> {
+        self.files.iter().find(|file| file_key_of(file) == file_key)
+    }
+}
+
+// An inventory as written to disk
+#[derive(CandidType, Deserialize)]
+struct Entry {
+    fetched_at: u64,
+    files: Vec<FileExt>,
+}
+
+impl InventoryCache {
+    /// Cache in `dir`, created when missing
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(InventoryCache {
+            dir,
+            ttl: DEFAULT_TTL,
+            offline: false,
+        })
+    }
+
+    /// Time an inventory is used for, [`DEFAULT_TTL`] by default
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Answer from the cache only, whatever the age of the inventories, and never list a box
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    /// Whether `inventory` is used without listing the box again
+    pub fn is_fresh(&self, inventory: &Inventory) -> bool {
+        self.offline || inventory.age() < self.ttl
+    }
+
+    /// The last inventory of a box, `None` when there is none or it can not be read
+    pub fn load(&self, canister_id: &Principal) -> Option<Inventory> {
+        let bytes = fs::read(self.path(canister_id)).ok()?;
+        let entry = Decode!(&bytes, Entry).ok()?;
+        Some(Inventory {
+            fetched_at: UNIX_EPOCH + Duration::from_secs(entry.fetched_at),
+            files: entry.files,
+        })
+    }
+
+    /// Keep `files` as the inventory of a box, listed now
+    pub fn store(&self, canister_id: &Principal, files: &[FileExt]) -> io::Result<()> {
+        let entry = Entry {
+            fetched_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            files: files.to_vec(),
+        };
+        let bytes = Encode!(&entry).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))?;
+        // Written aside then renamed, a reader never sees half an inventory
+        let path = self.path(canister_id);
+        let temporary = path.with_extension("inventory.tmp");
+        fs::write(&temporary, bytes)?;
+        fs::rename(temporary, path)
+    }
+
+    /// Drop the inventory of a box, it is listed again on next use
+    pub fn invalidate(&self, canister_id: &Principal) -> io::Result<()> {
+        match fs::remove_file(self.path(canister_id)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    /// Drop the inventories of every box
+    pub fn clear(&self) -> io::Result<()> {
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "inventory") {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn path(&self, canister_id: &Principal) -> PathBuf {
+        self.dir.join(format!("{}.inventory", canister_id.to_text()))
+    }
+}
+
+pub(crate) fn file_key_of(file: &FileExt) -> &str {
+    match file {
+        FileExt::PlainFileExt(asset_ext) | FileExt::EncryptFileExt(asset_ext) => &asset_ext.file_key,
+        FileExt::SharedFileExt { file_key, .. } => file_key,
+    }
+}
@@ -0,0 +1,180 @@
+//! Capacity checks before uploads
+//!
+//! A box which runs out of stable memory answers `MemoryInsufficient` in the middle of an upload.
+//! Before sending anything, the space the files will take is compared with what the box has left
+//! (`avlSM`), and by [`CapacityPolicy`] the upload is rejected or only the files which fit are sent.
+use std::fmt;
+use crate::agent::CallError;
+use crate::transfer::Transfer;
+use super::backend::DataBoxBackend;
+use super::key::{self, KeyScheme};
+use super::{mime, slice_data, stored_name, AvlSMResult, CanisterStateResult, DataErr, FileExt};
+use bytes::Bytes;
+
+/// Bytes a file takes in a box besides its chunks (name, key, type, ...)
+pub const FILE_OVERHEAD: u64 = 1024;
+
+/// Bytes a chunk takes in a box besides its data
+pub const CHUNK_OVERHEAD: u64 = 256;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CapacityPolicy {
+    /// No check, the box answers `MemoryInsufficient` when it is full
+    #[default]
+    Unchecked,
+    /// Nothing is sent unless every file fits, the upload fails with `CallError::Capacity`
+    Reject,
+    /// The files which fit are sent, in order, the others end `Err(DataErr::MemoryInsufficient)`
+    FitOnly,
+}
+
+/// A file about to be uploaded
+#[derive(Clone, Debug)]
+pub struct PlannedFile {
+    pub file_name: String,
+    pub file_extension: String,
+    pub file_key: String,
+    pub total_size: u64,
+    pub chunk_number: u64,
+    /// Bytes it will take in the box, 0 when the box already has it
+    pub footprint: u64,
+}
+
+impl PlannedFile {
+    /// Plan the upload of `data` under `name`, the type is detected when `content_type` is `None`
+    pub fn new(data: &Bytes, name: &str, content_type: Option<&str>, key_scheme: KeyScheme) -> Self {
+        let file_extension = match content_type {
+            Some(content_type) => content_type.to_string(),
+            None => mime::detect(name, data),
+        };
+        let (total_size, chunk_number, data_slice) = slice_data(data.clone());
+        PlannedFile {
+            file_name: stored_name(name, &file_extension),
+            file_extension,
+            file_key: key::key_of_chunks(&data_slice, key_scheme),
+            total_size: total_size as u64,
+            chunk_number: chunk_number as u64,
+            footprint: footprint(total_size as u64, chunk_number as u64),
+        }
+    }
+}
+
+/// Bytes a file of `total_size` bytes in `chunk_number` chunks takes in a box
+pub fn footprint(total_size: u64, chunk_number: u64) -> u64 {
+    total_size + FILE_OVERHEAD + chunk_number * CHUNK_OVERHEAD
+}
+
+/// Files which fit in a box, and those which do not
+#[derive(Clone, Debug)]
+pub struct UploadPlan {
+    /// Available memory of the box, from `avlSM`
+    pub available: u64,
+    /// Stable memory the box uses, from `canisterState`
+    pub used: u64,
+    pub fits: Vec<PlannedFile>,
+    pub rejected: Vec<PlannedFile>,
+}
+
+impl UploadPlan {
+    /// Bytes all the files take
+    pub fn required(&self) -> u64 {
+        self.fits.iter().chain(&self.rejected).map(|file| file.footprint).sum()
+    }
+
+    pub fn error(&self) -> CapacityError {
+        CapacityError {
+            available: self.available,
+            used: self.used,
+            required: self.required(),
+            rejected: self.rejected.clone(),
+        }
+    }
+}
+
+/// Files which do not fit in a box
+#[derive(Clone, Debug)]
+pub struct CapacityError {
+    pub available: u64,
+    pub used: u64,
+    pub required: u64,
+    pub rejected: Vec<PlannedFile>,
+}
+
+impl fmt::Display for CapacityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "not enough memory in the box : {} bytes needed, {} available ({} used), does not fit :",
+            self.required, self.available, self.used
+        )?;
+        for file in &self.rejected {
+            write!(f, " {} ({} bytes)", file.file_name, file.total_size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Which of `files` fit in the box, first come first served
+///
+/// Example code :
+/// ```
+/// use bytes::Bytes;
+/// use metabox_sdk::databox::capacity::{plan_upload, PlannedFile};
+/// use metabox_sdk::databox::key::KeyScheme;
+/// use metabox_sdk::databox::local::LocalDirBackend;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let root = std::env::temp_dir().join("metabox-capacity-example");
+///     let _ = std::fs::remove_dir_all(&root);
+///     let local = LocalDirBackend::new(&root).unwrap().with_capacity(10_000);
+///     let files = vec![
+///         PlannedFile::new(&Bytes::from(vec![1; 6_000]), "a.bin", None, KeyScheme::V1),
+///         PlannedFile::new(&Bytes::from(vec![2; 6_000]), "b.bin", None, KeyScheme::V1),
+///     ];
+///     let plan = plan_upload(&local, files).await.unwrap().unwrap();
+///     assert_eq!(plan.fits.len(), 1);
+///     assert_eq!(plan.rejected[0].file_name, "b");
+///     println!("{}", plan.error());
+/// }
+/// ```
+pub async fn plan_upload<B: DataBoxBackend + ?Sized>(
+    backend: &B,
+    files: Vec<PlannedFile>,
+) -> Result<Result<UploadPlan, DataErr>, CallError> {
+    let available = match backend.available_memory().await? {
+        AvlSMResult::ok(available) => available,
+        AvlSMResult::err(data_err) => return Ok(Err(data_err)),
+    };
+    let used = match backend.state().await? {
+        CanisterStateResult::ok(state) => state.stable_memory_size,
+        CanisterStateResult::err(data_err) => return Ok(Err(data_err)),
+    };
+    let mut left = available;
+    let (mut fits, mut rejected) = (Vec::new(), Vec::new());
+    for file in files {
+        if file.footprint <= left {
+            left -= file.footprint;
+            fits.push(file);
+        } else {
+            rejected.push(file);
+        }
+    }
+    Ok(Ok(UploadPlan { available, used, fits, rejected }))
+}
+
+/// Nothing to send for a file the box has complete, when the transfer deduplicates
+pub(crate) async fn exclude_stored<B: DataBoxBackend + ?Sized>(
+    backend: &B,
+    file: &mut PlannedFile,
+    transfer: &Transfer,
+) -> Result<(), CallError> {
+    if transfer.dedup() {
+        if let Ok(FileExt::PlainFileExt(asset_ext)) = backend.info(&file.file_key).await? {
+            if asset_ext.upload_status && asset_ext.total_size == file.total_size {
+                file.footprint = 0;
+            }
+        }
+    }
+    Ok(())
+}
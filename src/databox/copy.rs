@@ -0,0 +1,230 @@
+//! Copy of files from a box to another
+//!
+//! Each file is streamed chunk by chunk : a chunk read from the source with `getPlain` is put in
+//! the target as the segment of the same order, so one chunk at a time is held in memory and
+//! nothing is written to disk. The file keeps its name, type, chunks and key.
+//!
+//! The key is checked against the chunks as they go through, then the target is asked for the
+//! file : only a file the target has complete is deleted from the source, when asked to.
+use sha2::{Digest, Sha256};
+use crate::agent::CallError;
+use crate::search::{FileMatch, FileQuery};
+use crate::transfer::{Direction, Transfer, TransferEvent, TransferOutcome};
+use super::backend::{self, DataBoxBackend};
+use super::key::KeyScheme;
+use super::{build_put_plain_arg, is_internal, nat_to_u64, AssetExt, DataErr, DeleteKeyResult, FileExt, GetPlainResult, PutResult};
+
+/// Which files to copy, and what to do with them after
+#[derive(Clone, Debug, Default)]
+pub struct CopyOptions {
+    filter: FileQuery,
+    delete_source: bool,
+}
+
+impl CopyOptions {
+    /// Every file, kept in the source
+    pub fn new() -> Self {
+        CopyOptions::default()
+    }
+
+    /// The files the query finds in the source, in its order and page
+    pub fn with_filter(mut self, filter: FileQuery) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Delete each file from the source once the target has it complete
+    pub fn with_delete_source(mut self, delete_source: bool) -> Self {
+        self.delete_source = delete_source;
+        self
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum CopyStatus {
+    /// Copied, the target has the file complete
+    Copied,
+    /// The target had the file complete already
+    AlreadyExists,
+    /// The source answered an error, nothing more was sent
+    SourceErr(DataErr),
+    /// The target answered an error
+    TargetErr(DataErr),
+    /// The chunks read do not give the key of the file, the copy was deleted from the target
+    KeyMismatch,
+    /// The transfer was cancelled, the following files were not copied
+    Cancelled,
+}
+
+/// What became of a file
+#[derive(Clone, Debug)]
+pub struct CopyOutcome {
+    pub file_key: String,
+    pub file_name: String,
+    pub file_extension: String,
+    pub total_size: u64,
+    pub status: CopyStatus,
+    pub source_deleted: bool,
+}
+
+/// Copy the complete plain files of `source` chosen by the options to `target`, in order
+///
+/// Example code :
+/// ```
+/// use candid::Principal;
+/// use metabox_sdk::databox::DataBoxClient;
+/// use metabox_sdk::databox::copy::{copy_files, CopyOptions, CopyStatus};
+/// use metabox_sdk::databox::mock::MockDataBox;
+/// use metabox_sdk::search::FileQuery;
+/// use metabox_sdk::transfer::Transfer;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let owner = Principal::anonymous();
+///     let source = DataBoxClient::mock(&MockDataBox::new(Principal::from_slice(&[1; 10]), owner), owner);
+///     let target = DataBoxClient::mock(&MockDataBox::new(Principal::from_slice(&[2; 10]), owner), owner);
+///     let notes = source.put_bytes(vec![1; 3_000_000], "notes.txt", "text/plain").await.unwrap();
+///     source.put_bytes(vec![2; 100], "photo.png", "image/png").await.unwrap();
+///
+///     let options = CopyOptions::new().with_filter(FileQuery::new().with_mime_type("text/*")).with_delete_source(true);
+///     let outcomes = copy_files(&source, &target, &options, &Transfer::new()).await.unwrap().unwrap();
+///     assert_eq!(outcomes.len(), 1);
+///     assert!(matches!(outcomes[0].status, CopyStatus::Copied) && outcomes[0].source_deleted);
+///     assert_eq!(target.get_plain_file(&notes.file_key).await.unwrap().unwrap(), vec![1; 3_000_000]);
+///     assert!(source.get_file_info(&notes.file_key).await.unwrap().is_err());
+/// }
+/// ```
+pub async fn copy_files<S, T>(
+    source: &S,
+    target: &T,
+    options: &CopyOptions,
+    transfer: &Transfer,
+) -> Result<Result<Vec<CopyOutcome>, DataErr>, CallError>
+where
+    S: DataBoxBackend + ?Sized,
+    T: DataBoxBackend + ?Sized,
+{
+    let files = match source.list().await? {
+        Ok(files) => files,
+        Err(data_err) => return Ok(Err(data_err)),
+    };
+    let box_id = source.canister_id();
+    // Incomplete files can not be read, they are left out, like the files of the SDK
+    let complete = files
+        .into_iter()
+        .filter(|file| matches!(file, FileExt::PlainFileExt(asset_ext) if asset_ext.upload_status) && !is_internal(file))
+        .map(|file| FileMatch { box_id, file })
+        .collect();
+    let chosen = options.filter.apply(complete).matches;
+
+    let mut outcomes = Vec::new();
+    for (index, found) in chosen.iter().enumerate() {
+        let asset_ext = match &found.file {
+            FileExt::PlainFileExt(asset_ext) => asset_ext,
+            _ => continue,
+        };
+        let status = copy_file(source, target, asset_ext, &transfer.in_batch(index, chosen.len())).await?;
+        let mut source_deleted = false;
+        if options.delete_source && matches!(status, CopyStatus::Copied | CopyStatus::AlreadyExists) {
+            source_deleted = matches!(source.delete(&asset_ext.file_key).await?, DeleteKeyResult::ok(_));
+        }
+        let cancelled = matches!(status, CopyStatus::Cancelled);
+        outcomes.push(CopyOutcome {
+            file_key: asset_ext.file_key.clone(),
+            file_name: asset_ext.file_name.clone(),
+            file_extension: asset_ext.file_extension.clone(),
+            total_size: asset_ext.total_size,
+            status,
+            source_deleted,
+        });
+        if cancelled {
+            break;
+        }
+    }
+    Ok(Ok(outcomes))
+}
+
+/// Move every file of `source` to `target`, deleting each from the source once it is copied
+pub async fn migrate_box<S, T>(source: &S, target: &T, transfer: &Transfer) -> Result<Result<Vec<CopyOutcome>, DataErr>, CallError>
+where
+    S: DataBoxBackend + ?Sized,
+    T: DataBoxBackend + ?Sized,
+{
+    copy_files(source, target, &CopyOptions::new().with_delete_source(true), transfer).await
+}
+
+async fn copy_file<S, T>(source: &S, target: &T, asset_ext: &AssetExt, transfer: &Transfer) -> Result<CopyStatus, CallError>
+where
+    S: DataBoxBackend + ?Sized,
+    T: DataBoxBackend + ?Sized,
+{
+    let file_key = &asset_ext.file_key;
+    let total_size = asset_ext.total_size;
+    let chunk_number = nat_to_u64(&asset_ext.need_query_times).unwrap_or(0);
+    let (index, count) = transfer.position();
+    transfer.emit(TransferEvent::FileStarted {
+        direction: Direction::Upload,
+        file_name: asset_ext.file_name.clone(),
+        file_key: file_key.clone(),
+        index,
+        count,
+        total_size,
+        chunk_number,
+    });
+    if has_complete(target, asset_ext).await? {
+        backend::finished(transfer, Direction::Upload, file_key, 0, TransferOutcome::AlreadyExists);
+        return Ok(CopyStatus::AlreadyExists);
+    }
+
+    let mut digests = Vec::new();
+    let mut transferred = 0;
+    for order in 0..chunk_number {
+        if transfer.is_cancelled() {
+            backend::finished(transfer, Direction::Upload, file_key, transferred, TransferOutcome::Cancelled);
+            return Ok(CopyStatus::Cancelled);
+        }
+        let data = match source.get_chunk(file_key, order, transfer).await? {
+            GetPlainResult::ok(data) => data,
+            GetPlainResult::err(data_err) => return Ok(failed(transfer, file_key, transferred, CopyStatus::SourceErr(data_err))),
+        };
+        digests.push(Sha256::digest(&data).into());
+        let put = build_put_plain_arg(file_key, &asset_ext.file_name, &asset_ext.file_extension, total_size, chunk_number, order, &data);
+        match target.put_segment(&put, transfer).await? {
+            // A segment the target has from an earlier copy
+            PutResult::ok(_) | PutResult::err(DataErr::FileRepeat) => {}
+            PutResult::err(data_err) => return Ok(failed(transfer, file_key, transferred, CopyStatus::TargetErr(data_err))),
+        }
+        transferred += data.len() as u64;
+        transfer.emit(TransferEvent::ChunkAcked {
+            file_key: file_key.clone(),
+            index: order,
+            bytes: data.len() as u64,
+            transferred,
+            total_size,
+        });
+    }
+
+    if !KeyScheme::ALL.iter().any(|scheme| scheme.key_of_digests(&digests) == *file_key) {
+        // The target must not keep content under a key which is not its own
+        target.delete(file_key).await?;
+        return Ok(failed(transfer, file_key, transferred, CopyStatus::KeyMismatch));
+    }
+    if !has_complete(target, asset_ext).await? {
+        return Ok(failed(transfer, file_key, transferred, CopyStatus::TargetErr(DataErr::FileKeyErr)));
+    }
+    backend::finished(transfer, Direction::Upload, file_key, transferred, TransferOutcome::Completed);
+    Ok(CopyStatus::Copied)
+}
+
+// Whether `target` has the file complete, of the same size
+async fn has_complete<T: DataBoxBackend + ?Sized>(target: &T, asset_ext: &AssetExt) -> Result<bool, CallError> {
+    Ok(match target.info(&asset_ext.file_key).await? {
+        Ok(FileExt::PlainFileExt(stored)) => stored.upload_status && stored.total_size == asset_ext.total_size,
+        _ => false,
+    })
+}
+
+fn failed(transfer: &Transfer, file_key: &str, transferred: u64, status: CopyStatus) -> CopyStatus {
+    backend::finished(transfer, Direction::Upload, file_key, transferred, TransferOutcome::Failed(format!("{:?}", status)));
+    status
+}
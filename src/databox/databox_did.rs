@@ -4,12 +4,12 @@ use candid::{Nat, Principal};
 
 #[derive(CandidType, Deserialize, Debug)]
 pub struct State {
-    balance: Nat,
-    memory_size: Nat,
-    stable_memory_size: u64,
+    pub balance: Nat,
+    pub memory_size: Nat,
+    pub stable_memory_size: u64,
 }
 
-#[derive(CandidType, Deserialize, Debug)]
+#[derive(CandidType, Deserialize, Debug, Clone)]
 pub struct AssetExt {
     pub file_extension: String,
     pub upload_status: bool,
@@ -39,7 +39,7 @@ struct ThumbNail { file_extension: String, image: Vec<u8> }
 #[derive(CandidType, Deserialize, Debug)]
 pub struct Avatar { pub data: Vec<u8>, pub data_type: String }
 
-#[derive(CandidType, Deserialize, Debug)]
+#[derive(CandidType, Deserialize, Debug, Clone)]
 pub enum DataErr {
     FileKeyErr,
     FilePublic,
@@ -89,7 +89,7 @@ pub enum ClearAllResult { ok(String), err(DataErr) }
 #[derive(CandidType, Deserialize, Debug)]
 pub enum CycleBalanceResult { ok(Nat), err(DataErr) }
 
-#[derive(CandidType, Deserialize, Debug)]
+#[derive(CandidType, Deserialize, Debug, Clone)]
 pub enum FileExt {
     EncryptFileExt(AssetExt),
     SharedFileExt{
@@ -0,0 +1,130 @@
+//! Images for avatars
+//!
+//! Decoding, cropping, scaling and encoding go through the `image` crate : PNG (every color type
+//! and bit depth), JPEG, GIF (its first frame), WebP and BMP images are read. Decoding is bounded
+//! by [`MAX_SIDE`] and [`MAX_DECODED_BYTES`], so a small file can not expand without limit in memory.
+use std::fmt;
+use std::io::Cursor;
+use ::image::error::{ImageError as DecodeError, LimitErrorKind};
+use ::image::imageops::FilterType;
+use ::image::io::{Limits, Reader};
+use ::image::{GenericImageView, ImageOutputFormat};
+pub use ::image::DynamicImage;
+
+/// Images wider or higher are refused before they are decoded
+pub const MAX_SIDE: u32 = 16_384;
+
+/// Memory a decoder may allocate for one image
+pub const MAX_DECODED_BYTES: u64 = 256 * 1024 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ImageError {
+    /// A format, or a variant of it, which can not be decoded
+    Unsupported(String),
+    /// Damaged data
+    Invalid(String),
+    /// The image is over [`MAX_SIDE`] or would take more than [`MAX_DECODED_BYTES`] decoded
+    TooLarge(String),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Unsupported(error) => write!(f, "unsupported image: {}", error),
+            ImageError::Invalid(error) => write!(f, "invalid image: {}", error),
+            ImageError::TooLarge(error) => write!(f, "image too large: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+impl From<DecodeError> for ImageError {
+    fn from(error: DecodeError) -> Self {
+        match &error {
+            DecodeError::Unsupported(_) => ImageError::Unsupported(error.to_string()),
+            DecodeError::Limits(limit) if matches!(limit.kind(), LimitErrorKind::DimensionError | LimitErrorKind::InsufficientMemory) => {
+                ImageError::TooLarge(error.to_string())
+            }
+            _ => ImageError::Invalid(error.to_string()),
+        }
+    }
+}
+
+/// Width and height of an image, read from its header
+pub fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    Reader::new(Cursor::new(data)).with_guessed_format().ok()?.into_dimensions().ok()
+}
+
+/// Decode an image, of a format guessed from its content
+///
+/// Example code :
+/// ```
+/// use std::io::Cursor;
+/// use metabox_sdk::databox::image::{self, DynamicImage, ImageError};
+///
+/// // 16 bit grey PNG and GIF, written by the encoders of the `image` crate
+/// let mut png = Vec::new();
+/// DynamicImage::new_luma16(300, 200).write_to(&mut Cursor::new(&mut png), ::image::ImageOutputFormat::Png).unwrap();
+/// assert_eq!(image::decode(&png).unwrap().width(), 300);
+/// let mut gif = Vec::new();
+/// DynamicImage::new_rgba8(40, 30).write_to(&mut Cursor::new(&mut gif), ::image::ImageOutputFormat::Gif).unwrap();
+/// assert_eq!(image::dimensions(&gif), Some((40, 30)));
+/// assert_eq!(image::decode(&gif).unwrap().height(), 30);
+///
+/// // A few bytes of PNG which would decode to more pixels than allowed
+/// let mut wide = Vec::new();
+/// DynamicImage::new_luma8(image::MAX_SIDE + 1, 1).write_to(&mut Cursor::new(&mut wide), ::image::ImageOutputFormat::Png).unwrap();
+/// assert!(matches!(image::decode(&wide), Err(ImageError::TooLarge(_))));
+/// assert!(matches!(image::decode(b"not an image"), Err(ImageError::Unsupported(_))));
+/// ```
+pub fn decode(data: &[u8]) -> Result<DynamicImage, ImageError> {
+    let mut reader = Reader::new(Cursor::new(data)).with_guessed_format().map_err(|error| ImageError::Invalid(error.to_string()))?;
+    if reader.format().is_none() {
+        return Err(ImageError::Unsupported("unknown format".to_string()));
+    }
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_SIDE);
+    limits.max_image_height = Some(MAX_SIDE);
+    limits.max_alloc = Some(MAX_DECODED_BYTES);
+    reader.limits(limits);
+    Ok(reader.decode()?)
+}
+
+/// The centered square of an image, as large as its shortest side
+pub fn square_crop(image: &DynamicImage) -> DynamicImage {
+    let (width, height) = image.dimensions();
+    let side = width.min(height);
+    image.crop_imm((width - side) / 2, (height - side) / 2, side, side)
+}
+
+/// An image scaled to `width` x `height` pixels
+pub fn resize(image: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    image.resize_exact(width, height, FilterType::Lanczos3)
+}
+
+/// Whether every pixel of an image is opaque
+pub fn is_opaque(image: &DynamicImage) -> bool {
+    !image.color().has_alpha() || image.to_rgba8().pixels().all(|pixel| pixel[3] == u8::MAX)
+}
+
+/// Encode an image as 8 bit PNG, keeping its transparency
+pub fn encode_png(image: &DynamicImage) -> Result<Vec<u8>, ImageError> {
+    let image = if image.color().has_alpha() {
+        DynamicImage::ImageRgba8(image.to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+    };
+    encode(&image, ImageOutputFormat::Png)
+}
+
+/// Encode an image as JPEG of `quality` (1 to 100), dropping its transparency
+pub fn encode_jpeg(image: &DynamicImage, quality: u8) -> Result<Vec<u8>, ImageError> {
+    encode(&DynamicImage::ImageRgb8(image.to_rgb8()), ImageOutputFormat::Jpeg(quality.clamp(1, 100)))
+}
+
+fn encode(image: &DynamicImage, format: ImageOutputFormat) -> Result<Vec<u8>, ImageError> {
+    let mut data = Vec::new();
+    image.write_to(&mut Cursor::new(&mut data), format)?;
+    Ok(data)
+}
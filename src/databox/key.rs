@@ -0,0 +1,130 @@
+//! File keys
+//!
+//! A file is stored under a key computed from its content, so the key of a file is known before
+//! it is uploaded : to look it up in a box, to skip an upload or to check a download. The content
+//! is cut in chunks of [`CHUNK_SIZE`] bytes, the chunks of the upload (the last one is shorter),
+//! and every chunk is hashed with SHA-256. The key is then, as lowercase hex :
+//!
+//! * [`KeyScheme::V1`], the scheme of the SDK : the SHA-256 of `32 * n` zero bytes followed by
+//!   the first 32 characters of the lowercase hex digest of each of the `n` chunks, as ascii.
+//! * [`KeyScheme::V2`] : the SHA-256 of the raw 32 bytes digests of the `n` chunks, concatenated.
+//!
+//! Both keys are 64 hex characters. A box does not check keys, a client chooses its scheme with
+//! [`DataBoxClient::with_key_scheme`](super::DataBoxClient::with_key_scheme) and files uploaded
+//! with either can be read by any client.
+use std::io::{self, Read};
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use super::UPDATE_SIZE;
+
+/// Size of the chunks a file is hashed (and uploaded) in
+pub const CHUNK_SIZE: usize = UPDATE_SIZE;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum KeyScheme {
+    /// The scheme of the SDK and of the files uploaded by earlier versions
+    #[default]
+    V1,
+    /// Over the raw chunk digests
+    V2,
+}
+
+impl KeyScheme {
+    pub const ALL: [KeyScheme; 2] = [KeyScheme::V1, KeyScheme::V2];
+
+    /// Key of content whose chunks have the SHA-256 `digests`, in order
+    pub fn key_of_digests(&self, digests: &[[u8; 32]]) -> String {
+        let mut hasher = Sha256::new();
+        match self {
+            KeyScheme::V1 => {
+                hasher.update(vec![0u8; 32 * digests.len()]);
+                for digest in digests {
+                    hasher.update(hex::encode(&digest[..16]));
+                }
+            }
+            KeyScheme::V2 => {
+                for digest in digests {
+                    hasher.update(digest);
+                }
+            }
+        }
+        hex::encode(hasher.finalize())
+    }
+}
+
+/// The key the SDK stores content read from `reader` under
+///
+/// The content is read chunk by chunk, it is never held in memory as a whole.
+///
+/// Example code :
+/// ```
+/// use candid::Principal;
+/// use metabox_sdk::databox::DataBoxClient;
+/// use metabox_sdk::databox::key::compute_file_key;
+/// use metabox_sdk::databox::mock::MockDataBox;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let data = b"some content".to_vec();
+///     let file_key = compute_file_key(data.as_slice()).unwrap();
+///
+///     let owner = Principal::anonymous();
+///     let client = DataBoxClient::mock(&MockDataBox::new(Principal::management_canister(), owner), owner);
+///     let put = client.put_bytes(data, "content", "text/plain").await.unwrap();
+///     assert_eq!(put.file_key, file_key);
+/// }
+/// ```
+pub fn compute_file_key<R: Read>(reader: R) -> io::Result<String> {
+    compute_file_key_with(reader, KeyScheme::V1)
+}
+
+/// [`compute_file_key`] with another scheme
+pub fn compute_file_key_with<R: Read>(reader: R, scheme: KeyScheme) -> io::Result<String> {
+    Ok(scheme.key_of_digests(&chunk_digests(reader)?))
+}
+
+/// The scheme `file_key` was computed with from the content of `reader`, `None` when it is not
+/// the key of this content
+///
+/// Example code :
+/// ```
+/// use metabox_sdk::databox::key::{compute_file_key_with, verify_file_key, KeyScheme};
+///
+/// let file_key = compute_file_key_with(&b"some content"[..], KeyScheme::V2).unwrap();
+/// assert_eq!(verify_file_key(&b"some content"[..], &file_key).unwrap(), Some(KeyScheme::V2));
+/// assert_eq!(verify_file_key(&b"other content"[..], &file_key).unwrap(), None);
+/// ```
+pub fn verify_file_key<R: Read>(reader: R, file_key: &str) -> io::Result<Option<KeyScheme>> {
+    let digests = chunk_digests(reader)?;
+    let file_key = file_key.to_ascii_lowercase();
+    Ok(KeyScheme::ALL.into_iter().find(|scheme| scheme.key_of_digests(&digests) == file_key))
+}
+
+/// Key of chunks held in memory, hashed in parallel
+pub(crate) fn key_of_chunks<T: AsRef<[u8]> + Sync>(chunks: &[T], scheme: KeyScheme) -> String {
+    let digests: Vec<[u8; 32]> = chunks.par_iter().map(|chunk| Sha256::digest(chunk.as_ref()).into()).collect();
+    scheme.key_of_digests(&digests)
+}
+
+fn chunk_digests<R: Read>(mut reader: R) -> io::Result<Vec<[u8; 32]>> {
+    let mut digests = Vec::new();
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    loop {
+        let mut filled = 0;
+        while filled < CHUNK_SIZE {
+            match reader.read(&mut chunk[filled..]) {
+                Ok(0) => break,
+                Ok(read) => filled += read,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            }
+        }
+        if filled == 0 {
+            return Ok(digests);
+        }
+        digests.push(Sha256::digest(&chunk[..filled]).into());
+        if filled < CHUNK_SIZE {
+            return Ok(digests);
+        }
+    }
+}
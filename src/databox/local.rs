@@ -0,0 +1,291 @@
+//! A DataBox kept in a local directory
+//!
+//! Every file is a directory named after its file key, holding a candid encoded `meta` record and
+//! one `<order>.chunk` file per segment. The `DataErr` cases follow the canister : a complete file
+//! can not be put again (`FileRepeat`), an unknown key is a `FileKeyErr` and a capacity can be set
+//! to get `MemoryInsufficient`.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use async_trait::async_trait;
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
+use crate::agent::CallError;
+use crate::transfer::Transfer;
+use super::backend::DataBoxBackend;
+use super::{
+    nat_to_u64, AssetExt, AvlSMResult, CanisterStateResult, DataErr, DeleteKeyResult, FileExt, FilePut, GetPlainResult, PutResult, State,
+    PUT, UPDATE_SIZE,
+};
+
+const META_FILE: &str = "meta";
+
+#[derive(CandidType, Deserialize)]
+struct FileMeta {
+    file_name: String,
+    file_extension: String,
+    aes_pub_key: Option<String>,
+    encrypted: bool,
+    total_size: u64,
+    chunk_number: u64,
+}
+
+/// [`DataBoxBackend`] storing the box in a local directory
+///
+/// Example code :
+/// ```
+/// use metabox_sdk::databox::{backend, DataBoxBackend, FileExt};
+/// use metabox_sdk::databox::local::LocalDirBackend;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let root = std::env::temp_dir().join("metabox-local-example");
+///     let _ = std::fs::remove_dir_all(&root);
+///     let local = LocalDirBackend::new(&root).unwrap();
+///     backend::put_plain_data(&local, "notes".to_string(), "text/plain".to_string(), b"a local box".to_vec(), &Default::default()).await.unwrap();
+///     for file in local.list().await.unwrap().unwrap() {
+///         if let FileExt::PlainFileExt(asset_ext) = file {
+///             println!("{} {} bytes", asset_ext.file_name, asset_ext.total_size);
+///         }
+///     }
+/// }
+/// ```
+pub struct LocalDirBackend {
+    root: PathBuf,
+    canister_id: Principal,
+    capacity: Option<u64>,
+    lock: Mutex<()>,
+}
+
+impl LocalDirBackend {
+    /// Use (and create if needed) the directory `root`
+    pub fn new(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(LocalDirBackend {
+            root,
+            canister_id: Principal::anonymous(),
+            capacity: None,
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Id reported as the files' bucket, the anonymous principal by default
+    pub fn with_canister_id(mut self, canister_id: Principal) -> Self {
+        self.canister_id = canister_id;
+        self
+    }
+
+    /// Limit the bytes of chunks the directory may hold
+    pub fn with_capacity(mut self, capacity: u64) -> Self {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Keys are hex digests, anything else could escape the root
+    fn file_dir(&self, file_key: &str) -> Option<PathBuf> {
+        if file_key.is_empty() || !file_key.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return None;
+        }
+        Some(self.root.join(file_key))
+    }
+
+    fn read_meta(&self, file_key: &str) -> io::Result<Option<FileMeta>> {
+        let file_dir = match self.file_dir(file_key) {
+            Some(file_dir) => file_dir,
+            None => return Ok(None),
+        };
+        match fs::read(file_dir.join(META_FILE)) {
+            Ok(bytes) => Decode!(&bytes, FileMeta).map(Some).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn chunk_path(file_dir: &Path, order: u64) -> PathBuf {
+        file_dir.join(format!("{}.chunk", order))
+    }
+
+    fn stored_chunks(file_dir: &Path, meta: &FileMeta) -> u64 {
+        (0..meta.chunk_number).filter(|order| Self::chunk_path(file_dir, *order).exists()).count() as u64
+    }
+
+    fn file_ext(&self, file_key: &str, meta: FileMeta) -> FileExt {
+        let file_dir = self.root.join(file_key);
+        let asset_ext = AssetExt {
+            upload_status: Self::stored_chunks(&file_dir, &meta) == meta.chunk_number,
+            file_extension: meta.file_extension,
+            bucket_id: self.canister_id,
+            aes_pub_key: meta.aes_pub_key,
+            file_name: meta.file_name,
+            file_key: file_key.to_string(),
+            total_size: meta.total_size,
+            need_query_times: Nat::from(meta.chunk_number),
+        };
+        if meta.encrypted {
+            FileExt::EncryptFileExt(asset_ext)
+        } else {
+            FileExt::PlainFileExt(asset_ext)
+        }
+    }
+
+    fn used(&self) -> io::Result<u64> {
+        let mut used = 0;
+        for file_dir in fs::read_dir(&self.root)? {
+            let file_dir = file_dir?;
+            if !file_dir.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(file_dir.path())? {
+                let entry = entry?;
+                if entry.file_name() != META_FILE {
+                    used += entry.metadata()?.len();
+                }
+            }
+        }
+        Ok(used)
+    }
+
+    fn put(&self, file_put: &FilePut) -> io::Result<PutResult> {
+        let (encrypted, put) = match file_put {
+            FilePut::PlainFilePut(put) => (false, put),
+            FilePut::EncryptFilePut(put) => (true, put),
+            FilePut::SharedFilePut { .. } => return Ok(PutResult::err(DataErr::FlagErr)),
+        };
+        let (file_extension, order, chunk_number, chunk, aes_pub_key, file_name, file_key, total_size) = match put {
+            PUT::segment { file_extension, order, chunk_number, chunk, aes_pub_key, file_name, file_key, total_size } => {
+                (file_extension, order, chunk_number, chunk, aes_pub_key, file_name, file_key, *total_size)
+            }
+            PUT::thumb_nail { .. } => return Ok(PutResult::err(DataErr::FlagErr)),
+        };
+        let (order, chunk_number) = match (nat_to_u64(order), nat_to_u64(chunk_number)) {
+            (Some(order), Some(chunk_number)) if chunk_number > 0 && order < chunk_number => (order, chunk_number),
+            _ => return Ok(PutResult::err(DataErr::FlagErr)),
+        };
+        if chunk.data.len() > UPDATE_SIZE {
+            return Ok(PutResult::err(DataErr::BlobSizeError));
+        }
+        let file_dir = match self.file_dir(file_key) {
+            Some(file_dir) => file_dir,
+            None => return Ok(PutResult::err(DataErr::FileKeyErr)),
+        };
+
+        let _guard = self.lock.lock().unwrap();
+        let meta = match self.read_meta(file_key)? {
+            Some(meta) => meta,
+            None => {
+                let meta = FileMeta {
+                    file_name: file_name.clone(),
+                    file_extension: file_extension.clone(),
+                    aes_pub_key: aes_pub_key.clone(),
+                    encrypted,
+                    total_size,
+                    chunk_number,
+                };
+                fs::create_dir_all(&file_dir)?;
+                write_atomic(&file_dir.join(META_FILE), &Encode!(&meta).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?)?;
+                meta
+            }
+        };
+        if Self::stored_chunks(&file_dir, &meta) == meta.chunk_number {
+            return Ok(PutResult::err(DataErr::FileRepeat));
+        }
+        if meta.chunk_number != chunk_number || meta.total_size != total_size || meta.encrypted != encrypted {
+            return Ok(PutResult::err(DataErr::FlagErr));
+        }
+        if let Some(capacity) = self.capacity {
+            // A segment sent again replaces the previous one
+            let replaced = fs::metadata(Self::chunk_path(&file_dir, order)).map(|metadata| metadata.len()).unwrap_or(0);
+            if self.used()? + (chunk.data.len() as u64).saturating_sub(replaced) > capacity {
+                return Ok(PutResult::err(DataErr::MemoryInsufficient));
+            }
+        }
+        write_atomic(&Self::chunk_path(&file_dir, order), &chunk.data)?;
+        Ok(PutResult::ok(self.file_ext(file_key, meta)))
+    }
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, data)?;
+    fs::rename(temp, path)
+}
+
+#[async_trait]
+impl DataBoxBackend for LocalDirBackend {
+    fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+
+    async fn put_segment(&self, put: &FilePut, _transfer: &Transfer) -> Result<PutResult, CallError> {
+        Ok(self.put(put)?)
+    }
+
+    async fn get_chunk(&self, file_key: &str, index: u64, _transfer: &Transfer) -> Result<GetPlainResult, CallError> {
+        let meta = match self.read_meta(file_key)? {
+            Some(meta) if !meta.encrypted => meta,
+            _ => return Ok(GetPlainResult::err(DataErr::FileKeyErr)),
+        };
+        let file_dir = self.root.join(file_key);
+        if Self::stored_chunks(&file_dir, &meta) != meta.chunk_number {
+            return Ok(GetPlainResult::err(DataErr::FileKeyErr));
+        }
+        match fs::read(Self::chunk_path(&file_dir, index)) {
+            Ok(data) => Ok(GetPlainResult::ok(data)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(GetPlainResult::err(DataErr::FlagErr)),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    async fn list(&self) -> Result<Result<Vec<FileExt>, DataErr>, CallError> {
+        let mut files = Vec::new();
+        let mut entries = fs::read_dir(&self.root)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let file_key = entry.file_name().to_string_lossy().into_owned();
+            if let Some(meta) = self.read_meta(&file_key)? {
+                if !meta.encrypted {
+                    files.push(self.file_ext(&file_key, meta));
+                }
+            }
+        }
+        Ok(Ok(files))
+    }
+
+    async fn info(&self, file_key: &str) -> Result<Result<FileExt, DataErr>, CallError> {
+        Ok(match self.read_meta(file_key)? {
+            Some(meta) => Ok(self.file_ext(file_key, meta)),
+            None => Err(DataErr::FileKeyErr),
+        })
+    }
+
+    async fn delete(&self, file_key: &str) -> Result<DeleteKeyResult, CallError> {
+        let _guard = self.lock.lock().unwrap();
+        if self.read_meta(file_key)?.is_none() {
+            return Ok(DeleteKeyResult::err(DataErr::FileKeyErr));
+        }
+        fs::remove_dir_all(self.root.join(file_key))?;
+        Ok(DeleteKeyResult::ok(file_key.to_string()))
+    }
+
+    async fn state(&self) -> Result<CanisterStateResult, CallError> {
+        let used = self.used()?;
+        Ok(CanisterStateResult::ok(State {
+            balance: Nat::from(0u64),
+            memory_size: Nat::from(used),
+            stable_memory_size: used,
+        }))
+    }
+
+    async fn available_memory(&self) -> Result<AvlSMResult, CallError> {
+        let available = match self.capacity {
+            Some(capacity) => capacity.saturating_sub(self.used()?),
+            None => u64::MAX,
+        };
+        Ok(AvlSMResult::ok(available))
+    }
+}
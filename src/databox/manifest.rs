@@ -0,0 +1,303 @@
+//! Metadata of files, kept in the box
+//!
+//! A box stores a name, a MIME type and a size for each file. A [`Manifest`] attaches more to file
+//! keys : tags, key / value metadata, the upload time and the local path a file came from. It is
+//! a file of the box itself, of type [`MANIFEST_TYPE`], named `.metabox-manifest.<version>`, an
+//! [internal](super::is_internal) file which listings, search, copy and export leave out.
+//!
+//! Saving uploads the next version, then deletes the older ones : a reader always finds a complete
+//! manifest, the highest version. A save from a manifest which is no longer the last one is
+//! refused with [`ManifestError::Conflict`], the changes are made again on the last version.
+//!
+//! Two saves of the same version may both upload it. Each lists the box again after its upload and
+//! the file with the lowest key wins, readers take it too : the other save deletes its file and is
+//! refused. A save also loses when the version it started from was deleted meanwhile, by a save
+//! which already won.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use bytes::Bytes;
+use candid::{CandidType, Decode, Deserialize, Encode};
+use crate::agent::CallError;
+use super::{DataBoxBackend, DataBoxClient, DataErr, FileExt, PutPlainFileResult, UploadStatus};
+
+/// Name of the manifest files, followed by `.<version>`
+pub const MANIFEST_NAME: &str = ".metabox-manifest";
+
+/// Type of the manifest files
+pub const MANIFEST_TYPE: &str = "application/x-metabox-manifest";
+
+/// What the manifest knows of a file
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileMeta {
+    pub tags: BTreeSet<String>,
+    pub metadata: BTreeMap<String, String>,
+    /// Seconds since the Unix epoch
+    pub uploaded_at: Option<u64>,
+    pub local_path: Option<String>,
+}
+
+impl FileMeta {
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.contains(tag)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Manifest {
+    /// 0 when the box has no manifest yet
+    version: u64,
+    pub files: BTreeMap<String, FileMeta>,
+}
+
+// A manifest as stored in the box
+#[derive(CandidType, Deserialize)]
+struct StoredManifest {
+    version: u64,
+    files: BTreeMap<String, FileMeta>,
+}
+
+#[derive(Debug)]
+pub enum ManifestError {
+    Data(DataErr),
+    /// The manifest was saved by someone else since it was read
+    Conflict { expected: u64, found: u64 },
+    /// The manifest file can not be decoded
+    Corrupt(String),
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestError::Data(data_err) => write!(f, "data box error: {:?}", data_err),
+            ManifestError::Conflict { expected, found } => {
+                write!(f, "manifest version {} was saved over version {}", found, expected)
+            }
+            ManifestError::Corrupt(error) => write!(f, "corrupt manifest: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {}
+
+impl From<DataErr> for ManifestError {
+    fn from(data_err: DataErr) -> Self {
+        ManifestError::Data(data_err)
+    }
+}
+
+impl Manifest {
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn get(&self, file_key: &str) -> Option<&FileMeta> {
+        self.files.get(file_key)
+    }
+
+    /// The metadata of a file, empty until it is set
+    pub fn entry(&mut self, file_key: &str) -> &mut FileMeta {
+        self.files.entry(file_key.to_string()).or_default()
+    }
+
+    pub fn remove(&mut self, file_key: &str) -> Option<FileMeta> {
+        self.files.remove(file_key)
+    }
+
+    /// Note an upload : its time and the local path it came from
+    pub fn record_upload(&mut self, result: &PutPlainFileResult, local_path: Option<&Path>) {
+        let file_meta = self.entry(&result.file_key);
+        file_meta.uploaded_at = Some(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+        if let Some(local_path) = local_path {
+            file_meta.local_path = Some(local_path.display().to_string());
+        }
+    }
+
+    /// Keys of the files with a tag
+    pub fn tagged(&self, tag: &str) -> Vec<&str> {
+        self.filter(|file_meta| file_meta.has_tag(tag))
+    }
+
+    /// Keys of the files whose metadata `key` is `value`
+    pub fn with_metadata(&self, key: &str, value: &str) -> Vec<&str> {
+        self.filter(|file_meta| file_meta.metadata.get(key).map(String::as_str) == Some(value))
+    }
+
+    /// Keys of the files whose metadata matches `predicate`
+    pub fn filter<P: Fn(&FileMeta) -> bool>(&self, predicate: P) -> Vec<&str> {
+        self.files.iter().filter(|(_, file_meta)| predicate(file_meta)).map(|(file_key, _)| file_key.as_str()).collect()
+    }
+}
+
+/// The last manifest of the box, an empty one of version 0 when it has none
+pub async fn load_manifest(client: &DataBoxClient) -> Result<Result<Manifest, ManifestError>, CallError> {
+    let versions = match manifest_files(client).await? {
+        Ok(versions) => versions,
+        Err(data_err) => return Ok(Err(data_err.into())),
+    };
+    let (version, file_key) = match last_version(&versions) {
+        Some(last) => last,
+        None => return Ok(Ok(Manifest::default())),
+    };
+    let data = match client.get_plain_file(file_key).await? {
+        Ok(data) => data,
+        Err(data_err) => return Ok(Err(data_err.into())),
+    };
+    Ok(match Decode!(&data, StoredManifest) {
+        Ok(stored) if stored.version == *version => Ok(Manifest {
+            version: stored.version,
+            files: stored.files,
+        }),
+        Ok(stored) => Err(ManifestError::Corrupt(format!("version {} stored as version {}", stored.version, version))),
+        Err(error) => Err(ManifestError::Corrupt(error.to_string())),
+    })
+}
+
+/// Save `manifest` as the next version, the saved manifest is answered
+///
+/// Example code :
+/// ```
+/// use candid::Principal;
+/// use metabox_sdk::databox::DataBoxClient;
+/// use metabox_sdk::databox::manifest::{load_manifest, save_manifest, ManifestError};
+/// use metabox_sdk::databox::mock::MockDataBox;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let owner = Principal::anonymous();
+///     let client = DataBoxClient::mock(&MockDataBox::new(Principal::management_canister(), owner), owner);
+///     let put = client.put_bytes(b"hello".to_vec(), "hello.txt", "text/plain").await.unwrap();
+///
+///     let mut manifest = load_manifest(&client).await.unwrap().unwrap();
+///     manifest.record_upload(&put, None);
+///     manifest.entry(&put.file_key).tags.insert("greeting".to_string());
+///     let saved = save_manifest(&client, &manifest).await.unwrap().unwrap();
+///     assert_eq!(saved.version(), 1);
+///
+///     let manifest = load_manifest(&client).await.unwrap().unwrap();
+///     assert_eq!(manifest.tagged("greeting"), vec![put.file_key.as_str()]);
+///     // The manifest read first is no longer the last one
+///     let stale = save_manifest(&client, &Default::default()).await.unwrap();
+///     assert!(matches!(stale, Err(ManifestError::Conflict { expected: 0, found: 1 })));
+/// }
+/// ```
+///
+/// Two saves of the same version at once, one is refused :
+/// ```
+/// use candid::Principal;
+/// use metabox_sdk::databox::DataBoxClient;
+/// use metabox_sdk::databox::manifest::{load_manifest, save_manifest, ManifestError};
+/// use metabox_sdk::databox::mock::MockDataBox;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let owner = Principal::anonymous();
+///     let data_box = MockDataBox::new(Principal::management_canister(), owner);
+///     let client = DataBoxClient::mock(&data_box, owner);
+///     let mut first = load_manifest(&client).await.unwrap().unwrap();
+///     first.entry("a").tags.insert("first".to_string());
+///     let mut second = load_manifest(&client).await.unwrap().unwrap();
+///     second.entry("a").tags.insert("second".to_string());
+///
+///     let (first, second) = tokio::join!(save_manifest(&client, &first), save_manifest(&client, &second));
+///     let (saved, refused) = match (first.unwrap(), second.unwrap()) {
+///         (Ok(saved), Err(refused)) | (Err(refused), Ok(saved)) => (saved, refused),
+///         _ => panic!("one save must win"),
+///     };
+///     assert!(matches!(refused, ManifestError::Conflict { expected: 0, found: 1 }));
+///     let manifest = load_manifest(&client).await.unwrap().unwrap();
+///     assert_eq!(manifest.get("a"), saved.get("a"));
+///     assert_eq!(data_box.file_count(), 1);
+/// }
+/// ```
+pub async fn save_manifest(client: &DataBoxClient, manifest: &Manifest) -> Result<Result<Manifest, ManifestError>, CallError> {
+    let versions = match manifest_files(client).await? {
+        Ok(versions) => versions,
+        Err(data_err) => return Ok(Err(data_err.into())),
+    };
+    let found = versions.last().map_or(0, |(version, _)| *version);
+    if found != manifest.version {
+        return Ok(Err(ManifestError::Conflict { expected: manifest.version, found }));
+    }
+    let stored = StoredManifest {
+        version: manifest.version + 1,
+        files: manifest.files.clone(),
+    };
+    let data = Encode!(&stored)?;
+    let file_name = format!("{}.{}", MANIFEST_NAME, stored.version);
+    let result = client.put_shared_bytes(Bytes::from(data), &file_name, MANIFEST_TYPE).await?;
+    match result.upload_status {
+        UploadStatus::Ok | UploadStatus::AlreadyExists => {}
+        UploadStatus::Err(data_err) => return Ok(Err(data_err.into())),
+        UploadStatus::Cancelled { .. } => return Err(CallError::Cancelled),
+    }
+    // Another save of the same version may have uploaded it too
+    let versions = match manifest_files(client).await? {
+        Ok(versions) => versions,
+        Err(data_err) => return Ok(Err(data_err.into())),
+    };
+    let winner = last_version(&versions);
+    let based = manifest.version == 0 || versions.iter().any(|(version, _)| *version == manifest.version);
+    if !based || winner != Some((&stored.version, &result.file_key)) {
+        let _ = client.delete_file(result.file_key).await;
+        let found = winner.map_or(stored.version, |(version, _)| *version);
+        return Ok(Err(ManifestError::Conflict { expected: manifest.version, found }));
+    }
+    // The new version is complete, readers find it first : a failed delete only leaves a file behind
+    for (_, file_key) in versions.into_iter().filter(|(_, file_key)| *file_key != result.file_key) {
+        let _ = client.delete_file(file_key).await;
+    }
+    Ok(Ok(Manifest {
+        version: stored.version,
+        files: stored.files,
+    }))
+}
+
+/// Load the last manifest, change it and save it
+pub async fn update_manifest<F: FnOnce(&mut Manifest)>(client: &DataBoxClient, change: F) -> Result<Result<Manifest, ManifestError>, CallError> {
+    let mut manifest = match load_manifest(client).await? {
+        Ok(manifest) => manifest,
+        Err(error) => return Ok(Err(error)),
+    };
+    change(&mut manifest);
+    save_manifest(client, &manifest).await
+}
+
+/// Whether a file of the box is a manifest
+pub fn is_manifest(file: &FileExt) -> bool {
+    manifest_version(file).is_some()
+}
+
+// Version of a complete manifest file
+fn manifest_version(file: &FileExt) -> Option<u64> {
+    match file {
+        FileExt::PlainFileExt(asset_ext) if asset_ext.upload_status && asset_ext.file_extension == MANIFEST_TYPE => {
+            asset_ext.file_name.strip_prefix(MANIFEST_NAME)?.strip_prefix('.')?.parse().ok()
+        }
+        _ => None,
+    }
+}
+
+// The manifest readers take : the highest version, of lowest key when it was saved twice
+fn last_version(versions: &[(u64, String)]) -> Option<(&u64, &String)> {
+    let last = versions.last()?.0;
+    versions.iter().find(|(version, _)| *version == last).map(|(version, file_key)| (version, file_key))
+}
+
+// The complete manifest files of the box, by version, listed from the box and not from a cache
+async fn manifest_files(client: &DataBoxClient) -> Result<Result<Vec<(u64, String)>, DataErr>, CallError> {
+    let files = match client.list().await? {
+        Ok(files) => files,
+        Err(data_err) => return Ok(Err(data_err)),
+    };
+    let mut versions: Vec<(u64, String)> = files
+        .iter()
+        .filter_map(|file| match file {
+            FileExt::PlainFileExt(asset_ext) => manifest_version(file).map(|version| (version, asset_ext.file_key.clone())),
+            _ => None,
+        })
+        .collect();
+    versions.sort();
+    Ok(Ok(versions))
+}
@@ -0,0 +1,362 @@
+//! In-memory DataBox for offline tests
+//!
+//! [`MockDataBox`] answers the same candid calls as a DataBox canister, so a
+//! [`DataBoxClient::mock`](super::DataBoxClient::mock) runs exactly the code paths of a client
+//! talking to the Internet Computer : chunked `put` with ordering, `getAssetexts`, `getPlain`
+//! paging, `deletekey`, `clearall`, the state queries, and the `DataErr` cases of the canister.
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use candid::{Decode, Encode, Nat, Principal};
+use ic_agent::AgentError;
+use crate::agent::{CallError, MockCanister};
+use super::databox_did::OtherFile;
+use super::{
+    nat_to_u64, AssetExt, AvlSMResult, Avatar, CanisterStateResult, ClearAllResult, CycleBalanceResult, DataErr, DeleteKeyResult,
+    FileExt, FilePut, GetAssetExtKeyResult, GetAssetExtsResult, GetPlainResult, PutResult, State, UploadResult, GET, PUT,
+    UPDATE_SIZE,
+};
+
+/// Stable memory of a fresh mock box, 4 GiB like a DataBox canister
+pub const DEFAULT_CAPACITY: u64 = 4 * 1024 * 1024 * 1024;
+/// Cycles of a fresh mock box
+pub const DEFAULT_CYCLES: u64 = 2_000_000_000_000;
+const VERSION: u64 = 1;
+
+/// In-memory DataBox, cheap to clone : clones share the same box
+///
+/// Example code :
+/// ```
+/// use candid::Principal;
+/// use metabox_sdk::databox::{DataBoxClient, UploadStatus};
+/// use metabox_sdk::databox::mock::MockDataBox;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let owner = Principal::anonymous();
+///     let data_box = MockDataBox::new(Principal::from_text("4radi-oqaaa-aaaan-qapwa-cai").unwrap(), owner);
+///     let client = DataBoxClient::mock(&data_box, owner);
+///
+///     let path = std::env::temp_dir().join("metabox-mock-example.txt");
+///     std::fs::write(&path, b"hello databox").unwrap();
+///     let put = client.put_plain_file(path.to_str().unwrap()).await.unwrap();
+///     assert!(matches!(put.upload_status, UploadStatus::Ok));
+///
+///     let data = client.get_plain_file(&put.file_key).await.unwrap().unwrap();
+///     assert_eq!(data, b"hello databox");
+///     assert_eq!(data_box.file_count(), 1);
+/// }
+/// ```
+#[derive(Clone)]
+pub struct MockDataBox {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    canister_id: Principal,
+    state: Mutex<BoxState>,
+}
+
+struct BoxState {
+    owner: Principal,
+    capacity: u64,
+    cycles: u64,
+    used: u64,
+    files: BTreeMap<String, StoredFile>,
+    avatar: Option<Avatar>,
+    failures: u32,
+}
+
+struct StoredFile {
+    file_name: String,
+    file_extension: String,
+    aes_pub_key: Option<String>,
+    encrypted: bool,
+    total_size: u64,
+    chunk_number: u64,
+    chunks: BTreeMap<u64, Vec<u8>>,
+}
+
+impl StoredFile {
+    fn is_complete(&self) -> bool {
+        self.chunks.len() as u64 == self.chunk_number
+    }
+
+    fn asset_ext(&self, file_key: &str, bucket_id: Principal) -> AssetExt {
+        AssetExt {
+            file_extension: self.file_extension.clone(),
+            upload_status: self.is_complete(),
+            bucket_id,
+            aes_pub_key: self.aes_pub_key.clone(),
+            file_name: self.file_name.clone(),
+            file_key: file_key.to_string(),
+            total_size: self.total_size,
+            need_query_times: Nat::from(self.chunk_number),
+        }
+    }
+
+    fn file_ext(&self, file_key: &str, bucket_id: Principal) -> FileExt {
+        let asset_ext = self.asset_ext(file_key, bucket_id);
+        if self.encrypted {
+            FileExt::EncryptFileExt(asset_ext)
+        } else {
+            FileExt::PlainFileExt(asset_ext)
+        }
+    }
+}
+
+impl MockDataBox {
+    pub fn new(canister_id: Principal, owner: Principal) -> Self {
+        MockDataBox {
+            inner: Arc::new(Inner {
+                canister_id,
+                state: Mutex::new(BoxState {
+                    owner,
+                    capacity: DEFAULT_CAPACITY,
+                    cycles: DEFAULT_CYCLES,
+                    used: 0,
+                    files: BTreeMap::new(),
+                    avatar: None,
+                    failures: 0,
+                }),
+            }),
+        }
+    }
+
+    /// Limit the stable memory of the box, to exercise `MemoryInsufficient`
+    pub fn with_capacity(self, capacity: u64) -> Self {
+        self.inner.state.lock().unwrap().capacity = capacity;
+        self
+    }
+
+    pub fn canister_id(&self) -> Principal {
+        self.inner.canister_id
+    }
+
+    pub fn owner(&self) -> Principal {
+        self.inner.state.lock().unwrap().owner
+    }
+
+    /// Number of files, complete or not
+    pub fn file_count(&self) -> usize {
+        self.inner.state.lock().unwrap().files.len()
+    }
+
+    /// Bytes of stable memory used by the stored chunks
+    pub fn used_memory(&self) -> u64 {
+        self.inner.state.lock().unwrap().used
+    }
+
+    pub fn cycle_balance(&self) -> u64 {
+        self.inner.state.lock().unwrap().cycles
+    }
+
+    pub fn add_cycles(&self, cycles: u64) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.cycles = state.cycles.saturating_add(cycles);
+    }
+
+    /// The last uploaded avatar, as `(data, data_type)`
+    pub fn avatar(&self) -> Option<(Vec<u8>, String)> {
+        let state = self.inner.state.lock().unwrap();
+        state.avatar.as_ref().map(|avatar| (avatar.data.clone(), avatar.data_type.clone()))
+    }
+
+    /// Make the next `count` calls fail with a transient reject, as an overloaded subnet would
+    ///
+    /// Example code :
+    /// ```
+    /// use std::time::Duration;
+    /// use candid::Principal;
+    /// use metabox_sdk::agent::RetryPolicy;
+    /// use metabox_sdk::databox::DataBoxClient;
+    /// use metabox_sdk::databox::mock::MockDataBox;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let owner = Principal::anonymous();
+    ///     let data_box = MockDataBox::new(Principal::from_text("4radi-oqaaa-aaaan-qapwa-cai").unwrap(), owner);
+    ///     let policy = RetryPolicy { initial_backoff: Duration::from_millis(1), ..RetryPolicy::default() };
+    ///
+    ///     data_box.fail_next_calls(2);
+    ///     let client = DataBoxClient::mock(&data_box, owner).with_retry_policy(policy);
+    ///     assert!(client.get_version().await.is_ok());
+    ///
+    ///     data_box.fail_next_calls(1);
+    ///     let client = client.with_retry_policy(RetryPolicy::none());
+    ///     assert!(client.get_version().await.is_err());
+    /// }
+    /// ```
+    pub fn fail_next_calls(&self, count: u32) {
+        self.inner.state.lock().unwrap().failures = count;
+    }
+
+    pub(crate) fn canister(&self) -> Arc<dyn MockCanister> {
+        Arc::new(self.clone())
+    }
+
+    fn put(&self, state: &mut BoxState, file_put: FilePut) -> PutResult {
+        let (encrypted, put) = match file_put {
+            FilePut::PlainFilePut(put) => (false, put),
+            FilePut::EncryptFilePut(put) => (true, put),
+            FilePut::SharedFilePut { .. } => return PutResult::err(DataErr::FlagErr),
+        };
+        let (file_extension, order, chunk_number, chunk, aes_pub_key, file_name, file_key, total_size) = match put {
+            PUT::segment { file_extension, order, chunk_number, chunk, aes_pub_key, file_name, file_key, total_size } => {
+                (file_extension, order, chunk_number, chunk, aes_pub_key, file_name, file_key, total_size)
+            }
+            PUT::thumb_nail { .. } => return PutResult::err(DataErr::FlagErr),
+        };
+        let (order, chunk_number) = match (nat_to_u64(&order), nat_to_u64(&chunk_number)) {
+            (Some(order), Some(chunk_number)) if chunk_number > 0 && order < chunk_number => (order, chunk_number),
+            _ => return PutResult::err(DataErr::FlagErr),
+        };
+        if chunk.data.len() > UPDATE_SIZE {
+            return PutResult::err(DataErr::BlobSizeError);
+        }
+
+        let file = state.files.entry(file_key.clone()).or_insert_with(|| StoredFile {
+            file_name,
+            file_extension,
+            aes_pub_key,
+            encrypted,
+            total_size,
+            chunk_number,
+            chunks: BTreeMap::new(),
+        });
+        if file.is_complete() {
+            return PutResult::err(DataErr::FileRepeat);
+        }
+        if file.chunk_number != chunk_number || file.total_size != total_size || file.encrypted != encrypted {
+            return PutResult::err(DataErr::FlagErr);
+        }
+        // A segment sent again replaces the previous one
+        let replaced = file.chunks.get(&order).map(|data| data.len() as u64).unwrap_or(0);
+        let needed = (chunk.data.len() as u64).saturating_sub(replaced);
+        if state.used + needed > state.capacity {
+            if file.chunks.is_empty() {
+                state.files.remove(&file_key);
+            }
+            return PutResult::err(DataErr::MemoryInsufficient);
+        }
+        state.used = state.used + chunk.data.len() as u64 - replaced;
+        file.chunks.insert(order, chunk.data);
+        if file.is_complete() && file.chunks.values().map(|data| data.len() as u64).sum::<u64>() != file.total_size {
+            let freed: u64 = file.chunks.values().map(|data| data.len() as u64).sum();
+            state.files.remove(&file_key);
+            state.used -= freed;
+            return PutResult::err(DataErr::BlobSizeError);
+        }
+        PutResult::ok(file.file_ext(&file_key, self.inner.canister_id))
+    }
+
+    fn get_plain(&self, state: &BoxState, get: GET) -> GetPlainResult {
+        let file = match state.files.get(&get.file_key) {
+            Some(file) if file.is_complete() && !file.encrypted => file,
+            _ => return GetPlainResult::err(DataErr::FileKeyErr),
+        };
+        match nat_to_u64(&get.flag).and_then(|flag| file.chunks.get(&flag)) {
+            Some(data) => GetPlainResult::ok(data.clone()),
+            None => GetPlainResult::err(DataErr::FlagErr),
+        }
+    }
+
+    fn dispatch(&self, caller: Principal, method_name: &str, arg: &[u8]) -> Result<Vec<u8>, CallError> {
+        let mut state = self.inner.state.lock().unwrap();
+        let canister_id = self.inner.canister_id;
+        if state.failures > 0 {
+            state.failures -= 1;
+            return Err(CallError::from(AgentError::ReplicaError {
+                reject_code: 2,
+                reject_message: format!("Canister {} is overloaded", canister_id),
+            }));
+        }
+        let is_owner = caller == state.owner;
+        let response = match method_name {
+            "put" => {
+                let file_put = Decode!(arg, FilePut)?;
+                let result = if is_owner { self.put(&mut state, file_put) } else { PutResult::err(DataErr::PermissionDenied) };
+                Encode!(&result)?
+            }
+            "getPlain" => {
+                let get = Decode!(arg, GET)?;
+                Encode!(&self.get_plain(&state, get))?
+            }
+            "getAssetextkey" => {
+                let file_key = Decode!(arg, String)?;
+                let result = match state.files.get(&file_key) {
+                    Some(file) => GetAssetExtKeyResult::ok(file.file_ext(&file_key, canister_id)),
+                    None => GetAssetExtKeyResult::err(DataErr::FileKeyErr),
+                };
+                Encode!(&result)?
+            }
+            "getAssetexts" => {
+                let result = if is_owner {
+                    let (encrypted, plain): (Vec<_>, Vec<_>) = state.files.iter().partition(|(_, file)| file.encrypted);
+                    let plain = plain.into_iter().map(|(key, file)| file.file_ext(key, canister_id)).collect();
+                    let encrypted = encrypted.into_iter().map(|(key, file)| file.file_ext(key, canister_id)).collect();
+                    GetAssetExtsResult::ok(plain, encrypted, Vec::new(), Vec::<OtherFile>::new(), Vec::<OtherFile>::new())
+                } else {
+                    GetAssetExtsResult::err(DataErr::PermissionDenied)
+                };
+                Encode!(&result)?
+            }
+            "deletekey" => {
+                let file_key = Decode!(arg, String)?;
+                let result = if !is_owner {
+                    DeleteKeyResult::err(DataErr::PermissionDenied)
+                } else if let Some(file) = state.files.remove(&file_key) {
+                    state.used -= file.chunks.values().map(|data| data.len() as u64).sum::<u64>();
+                    DeleteKeyResult::ok(file_key)
+                } else {
+                    DeleteKeyResult::err(DataErr::FileKeyErr)
+                };
+                Encode!(&result)?
+            }
+            "clearall" => {
+                let result = if is_owner {
+                    state.files.clear();
+                    state.used = 0;
+                    ClearAllResult::ok("clear all success".to_string())
+                } else {
+                    ClearAllResult::err(DataErr::PermissionDenied)
+                };
+                Encode!(&result)?
+            }
+            "upload" => {
+                let avatar = Decode!(arg, Avatar)?;
+                let result = if is_owner {
+                    state.avatar = Some(avatar);
+                    UploadResult::ok
+                } else {
+                    UploadResult::err(DataErr::PermissionDenied)
+                };
+                Encode!(&result)?
+            }
+            "getVersion" => Encode!(&Nat::from(VERSION))?,
+            "getOwner" => Encode!(&state.owner)?,
+            "canisterState" => Encode!(&CanisterStateResult::ok(State {
+                balance: Nat::from(state.cycles),
+                memory_size: Nat::from(state.used),
+                stable_memory_size: state.used,
+            }))?,
+            "cycleBalance" => Encode!(&CycleBalanceResult::ok(Nat::from(state.cycles)))?,
+            "avlSM" => Encode!(&AvlSMResult::ok(state.capacity.saturating_sub(state.used)))?,
+            other => return Err(reject(canister_id, other)),
+        };
+        Ok(response)
+    }
+}
+
+impl MockCanister for MockDataBox {
+    fn call(&self, caller: Principal, method_name: &str, arg: &[u8]) -> Result<Vec<u8>, CallError> {
+        self.dispatch(caller, method_name, arg)
+    }
+}
+
+/// The reject of a replica for a method the canister does not export
+pub(crate) fn reject(canister_id: Principal, method_name: &str) -> CallError {
+    CallError::from(AgentError::ReplicaError {
+        reject_code: 3,
+        reject_message: format!("Canister {} has no update or query method '{}'", canister_id, method_name),
+    })
+}
@@ -1,21 +1,46 @@
-use ic_agent::{Agent, identity::Secp256k1Identity};
-use ic_agent::agent::http_transport::ReqwestHttpReplicaV2Transport;
-use garcon::Delay;
+use ic_agent::Agent;
 use std::fs;
-use std::path::Path;
-use std::ffi::OsStr;
-use sha256::digest_bytes;
-use rayon::prelude::*;
-use candid::{Encode, Decode, Nat, Principal};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use candid::{CandidType, Encode, Decode, Nat, Principal};
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use crate::agent::{build_agent, CallError, RetryPolicies, RetryPolicy, Transport};
+use crate::mime;
+use crate::transfer::{ProgressObserver, Transfer};
 mod databox_did;
-pub use databox_did::{ClearAllResult, DeleteKeyResult, UploadResult, Avatar, PUT, Chunk, FilePut, PutResult, DataErr, FileExt, GetAssetExtKeyResult, GET, GetPlainResult, CanisterStateResult, CycleBalanceResult, AvlSMResult, GetAssetExtsResult};
+pub mod archive;
+pub mod avatar;
+pub mod backend;
+pub mod cache;
+pub mod capacity;
+pub mod copy;
+pub mod image;
+pub mod key;
+pub mod local;
+pub mod manifest;
+pub mod mock;
+pub mod url;
+pub use backend::DataBoxBackend;
+use avatar::{AvatarError, AvatarOptions, AVATAR_NAME};
+use cache::{Inventory, InventoryCache};
+use capacity::{CapacityPolicy, PlannedFile};
+use key::KeyScheme;
+use mock::MockDataBox;
+pub use databox_did::{State, AssetExt, ClearAllResult, DeleteKeyResult, UploadResult, Avatar, PUT, Chunk, FilePut, PutResult, DataErr, FileExt, GetAssetExtKeyResult, GET, GetPlainResult, CanisterStateResult, CycleBalanceResult, AvlSMResult, GetAssetExtsResult};
 
-const UPDATE_SIZE: usize = 1992288;
+pub(crate) const UPDATE_SIZE: usize = 1992288;
 
 #[derive(Debug)]
 pub enum UploadStatus {
     Ok,
+    /// The box already had the complete file, nothing was sent
+    AlreadyExists,
     Err(DataErr),
+    /// The transfer was cancelled, the segments of `committed_chunks` (by order) are stored and the
+    /// file reads `upload_status: false` until the others are put, see `Transfer::with_committed_chunks`
+    Cancelled { committed_chunks: Vec<u64> },
 }
 
 #[derive(Debug)]
@@ -58,75 +83,10 @@ pub struct PutPlainFileResult {
 /// }
 /// ```
 pub async fn put_plain_files(pem_identity_path: &str, folder_path: &str, data_box_canister_id_text: &str,) -> Vec<PutPlainFileResult> {
-    let canister_id = Principal::from_text(data_box_canister_id_text).unwrap();
-    let mut ans: Vec<PutPlainFileResult> = Vec::new();
-    let paths = fs::read_dir(&folder_path).unwrap();
-    for path in paths {
-        let file_path = path.unwrap().file_name().into_string().unwrap();
-        let pos: Vec<&str> = file_path.split(".").collect();
-        let file_name = String::from(pos[0]);
-        let file_extension = String::from(get_file_type(&String::from(pos[1])));
-        let s = folder_path.to_owned() + &file_path;
-
-        let (file_size, slice_size, data_slice) = get_file_from_source(&s);
-
-        let puts = build_put_plain_args(
-            file_name.clone(),
-            file_extension.clone(),
-            file_size.try_into().unwrap(),
-            slice_size.try_into().unwrap(),
-            &data_slice,
-        );
-        let file_key = match &puts[0] {
-            FilePut::PlainFilePut(put) => {
-                match put {
-                    PUT::segment {file_extension, order, chunk_number, chunk, aes_pub_key, file_name, file_key, total_size} => {
-                        file_key.clone()
-                    }
-                    _ => {"".to_string()}
-                }
-            }
-            _ => {"".to_string()}
-        };
-
-        let mut flag = false;
-        for put in &puts {
-            let _response_blob = build_agent(pem_identity_path)
-                .update(&canister_id, "put")
-                .with_arg(Encode!(&put).expect("encode piece failed"))
-                .call_and_wait(get_waiter())
-                .await
-                .expect("response error");
-            let _response = Decode!(&_response_blob, PutResult).unwrap();
-            match _response {
-                PutResult::ok(..) => {
-                },
-                PutResult::err(data_err) => {
-                    ans.push(PutPlainFileResult {
-                        file_name: file_name.clone(),
-                        file_extension: file_extension.clone(),
-                        file_key: file_key.clone(),
-                        upload_status: UploadStatus::Err(data_err),
-                        databox_canister_id: canister_id,
-                        total_size: file_size.try_into().unwrap(),
-                        chunk_number: slice_size.try_into().unwrap(),
-                    });
-                    flag = true;
-                    break;
-                }
-            }
-        }
-        if !flag { ans.push(PutPlainFileResult {
-            file_name: file_name.clone(),
-            file_extension: file_extension.clone(),
-            file_key: file_key.clone(),
-            upload_status: UploadStatus::Ok,
-            databox_canister_id: canister_id,
-            total_size: file_size.try_into().unwrap(),
-            chunk_number: slice_size.try_into().unwrap(),
-        }); }
-    }
-    ans
+    build_client(pem_identity_path, data_box_canister_id_text)
+        .put_plain_files(folder_path)
+        .await
+        .expect("response error")
 }
 
 /// Put a plain file
@@ -152,64 +112,10 @@ pub async fn put_plain_files(pem_identity_path: &str, folder_path: &str, data_bo
 /// }
 /// ```
 pub async fn put_plain_file(pem_identity_path: &str, file_path_str: &str, data_box_canister_id_text: &str,) -> PutPlainFileResult {
-    let canister_id = Principal::from_text(data_box_canister_id_text).unwrap();
-    let file_path = Path::new(file_path_str);
-    let file_name = file_path.file_stem().unwrap().to_str().unwrap().to_owned();
-    let file_extension = String::from(get_file_type(file_path.extension().unwrap().to_str().unwrap()));
-
-    let (file_size, slice_size, data_slice) = get_file_from_source(file_path_str);
-    let puts = build_put_plain_args(
-        file_name.clone(),
-        file_extension.clone(),
-        file_size.try_into().unwrap(),
-        slice_size.try_into().unwrap(),
-        &data_slice,
-    );
-    let file_key = match &puts[0] {
-        FilePut::PlainFilePut(put) => {
-            match put {
-                PUT::segment {file_extension, order, chunk_number, chunk, aes_pub_key, file_name, file_key, total_size} => {
-                    file_key.clone()
-                }
-                _ => {"".to_string()}
-            }
-        }
-        _ => {"".to_string()}
-    };
-
-    for put in &puts {
-        let _response_blob = build_agent(pem_identity_path)
-            .update(&canister_id, "put")
-            .with_arg(Encode!(&put).expect("encode piece failed"))
-            .call_and_wait(get_waiter())
-            .await
-            .expect("response error");
-        let _response = Decode!(&_response_blob, PutResult).unwrap();
-        match _response {
-            PutResult::ok(..) => {
-            },
-            PutResult::err(data_err) => {
-                return PutPlainFileResult {
-                    file_name: file_name.clone(),
-                    file_extension: file_extension.clone(),
-                    file_key: file_key.clone(),
-                    upload_status: UploadStatus::Err(data_err),
-                    databox_canister_id: canister_id,
-                    total_size: file_size.try_into().unwrap(),
-                    chunk_number: slice_size.try_into().unwrap(),
-                };
-            }
-        }
-    }
-    PutPlainFileResult {
-        file_name: file_name.clone(),
-        file_extension: file_extension.clone(),
-        file_key: file_key.clone(),
-        upload_status: UploadStatus::Ok,
-        databox_canister_id: canister_id,
-        total_size: file_size.try_into().unwrap(),
-        chunk_number: slice_size.try_into().unwrap(),
-    }
+    build_client(pem_identity_path, data_box_canister_id_text)
+        .put_plain_file(file_path_str)
+        .await
+        .expect("response error")
 }
 
 /// Upload avatar
@@ -228,21 +134,10 @@ pub async fn put_plain_file(pem_identity_path: &str, file_path_str: &str, data_b
 /// }
 /// ```
 pub async fn upload_avatar(pem_identity_path: &str, data_box_canister_id_text: &str, avatar_file_path: &str) -> UploadResult {
-    let canister_id = Principal::from_text(data_box_canister_id_text).unwrap();
-    let context = fs::read(avatar_file_path).expect("read file failed");
-    let file_extension = String::from(get_file_type(Path::new(avatar_file_path).extension().unwrap().to_str().unwrap()));
-    let upload_args = Avatar {
-        data: context,
-        data_type: file_extension,
-    };
-    let response_blob = build_agent(pem_identity_path)
-        .update(&canister_id, "upload")
-        .with_arg(Encode!(&upload_args).expect("encode piece failed"))
-        .call_and_wait(get_waiter())
+    build_client(pem_identity_path, data_box_canister_id_text)
+        .upload_avatar(avatar_file_path)
         .await
-        .expect("response error");
-    let response = Decode!(&response_blob, UploadResult).unwrap();
-    response
+        .expect("response error")
 }
 
 /// Delete a file
@@ -261,15 +156,10 @@ pub async fn upload_avatar(pem_identity_path: &str, data_box_canister_id_text: &
 /// }
 /// ```
 pub async fn delete_file(pem_identity_path: &str, data_box_canister_id_text: &str, file_key: String) -> DeleteKeyResult {
-    let canister_id = Principal::from_text(data_box_canister_id_text).unwrap();
-    let response_blob = build_agent(pem_identity_path)
-        .update(&canister_id, "deletekey")
-        .with_arg(Encode!(&file_key).expect("encode piece failed"))
-        .call_and_wait(get_waiter())
+    build_client(pem_identity_path, data_box_canister_id_text)
+        .delete_file(file_key)
         .await
-        .expect("response error");
-    let response = Decode!(&response_blob, DeleteKeyResult).unwrap();
-    response
+        .expect("response error")
 }
 
 /// Clear the DataBox
@@ -288,15 +178,10 @@ pub async fn delete_file(pem_identity_path: &str, data_box_canister_id_text: &st
 /// }
 /// ```
 pub async fn clear_data_box(pem_identity_path: &str, data_box_canister_id_text: &str,) -> ClearAllResult {
-    let canister_id = Principal::from_text(data_box_canister_id_text).unwrap();
-    let response_blob = build_agent(pem_identity_path)
-        .update(&canister_id, "clearall")
-        .with_arg(Encode!().expect("encode piece failed"))
-        .call_and_wait(get_waiter())
+    build_client(pem_identity_path, data_box_canister_id_text)
+        .clear_data_box()
         .await
-        .expect("response error");
-    let response = Decode!(&response_blob, ClearAllResult).unwrap();
-    response
+        .expect("response error")
 }
 
 /// Get the plain file Data
@@ -319,43 +204,10 @@ pub async fn clear_data_box(pem_identity_path: &str, data_box_canister_id_text:
 /// }
 /// ```
 pub async fn get_plain_file(pem_identity_path: &str, data_box_canister_id_text: &str, file_key: &str) -> Result<Vec<u8>, DataErr> {
-    let canister_id = Principal::from_text(data_box_canister_id_text).unwrap();
-    let agent = build_agent(pem_identity_path);
-    let file_ext = get_file_info(pem_identity_path, data_box_canister_id_text, file_key).await.unwrap();
-    match file_ext {
-        FileExt::PlainFileExt(asset_ext) => {
-            let waiter = get_waiter();
-            let mut i = 0;
-            let need_query_times = asset_ext.need_query_times;
-            let mut ans: Vec<u8> = Vec::new();
-            while Nat::from(i) < need_query_times {
-                let arg = GET {
-                    flag: Nat::from(i),
-                    file_key: file_key.to_string(),
-                };
-                let response_blob = agent
-                    .update(&canister_id, "getPlain")
-                    .with_arg(Encode!(&arg).expect("encode piece failed"))
-                    .call_and_wait(waiter.clone())
-                    .await
-                    .expect("response error");
-                i += 1;
-                let response = Decode!(&response_blob, GetPlainResult).unwrap();
-                match response {
-                    GetPlainResult::ok(mut payload) => {
-                        ans.append(&mut payload)
-                    },
-                    GetPlainResult::err(data_err) => {
-                        return Err(data_err);
-                    },
-                }
-            }
-            Ok(ans)
-        },
-        _ => {
-            Err(DataErr::FileKeyErr)
-        }
-    }
+    build_client(pem_identity_path, data_box_canister_id_text)
+        .get_plain_file(file_key)
+        .await
+        .expect("response error")
 }
 
 /// Get a file 's information
@@ -393,18 +245,10 @@ pub async fn get_plain_file(pem_identity_path: &str, data_box_canister_id_text:
 /// }
 /// ```
 pub async fn get_file_info(pem_identity_path: &str, data_box_canister_id_text: &str, file_key: &str) -> Result<FileExt, DataErr> {
-    let canister_id = Principal::from_text(data_box_canister_id_text).unwrap();
-    let response_blob = build_agent(pem_identity_path)
-        .query(&canister_id, "getAssetextkey")
-        .with_arg(Encode!(&file_key).expect("encode piece failed"))
-        .call()
+    build_client(pem_identity_path, data_box_canister_id_text)
+        .get_file_info(file_key)
         .await
-        .expect("response error");
-    let response = Decode!(&response_blob, GetAssetExtKeyResult).unwrap();
-    match response {
-        GetAssetExtKeyResult::ok(file_ext) => Ok(file_ext),
-        GetAssetExtKeyResult::err(data_err) => Err(data_err),
-    }
+        .expect("response error")
 }
 
 /// Get all plain files 's information
@@ -448,22 +292,10 @@ pub async fn get_file_info(pem_identity_path: &str, data_box_canister_id_text: &
 /// }
 /// ```
 pub async fn get_all_plain_files_info(pem_identity_path: &str, data_box_canister_id_text: &str) -> Result<Vec<FileExt>, DataErr> {
-    let canister_id = Principal::from_text(data_box_canister_id_text).unwrap();
-    let response_blob = build_agent(pem_identity_path)
-        .query(&canister_id, "getAssetexts")
-        .with_arg(Encode!().expect("encode piece failed"))
-        .call()
+    build_client(pem_identity_path, data_box_canister_id_text)
+        .get_all_plain_files_info()
         .await
-        .expect("response error");
-    let response = Decode!(&response_blob, GetAssetExtsResult).unwrap();
-    match response {
-        GetAssetExtsResult::ok(plain_assets, ..) => {
-            return Ok(plain_assets);
-        },
-        GetAssetExtsResult::err(data_err) => {
-            return Err(data_err);
-        },
-    }
+        .expect("response error")
 }
 
 /// Get DataBox version
@@ -483,15 +315,10 @@ pub async fn get_all_plain_files_info(pem_identity_path: &str, data_box_canister
 /// }
 /// ```
 pub async fn get_version(pem_identity_path: &str, data_box_canister_id_text: &str,) -> Nat {
-    let canister_id = Principal::from_text(data_box_canister_id_text).unwrap();
-    let response_blob = build_agent(pem_identity_path)
-        .query(&canister_id, "getVersion")
-        .with_arg(Encode!().expect("encode piece failed"))
-        .call()
+    build_client(pem_identity_path, data_box_canister_id_text)
+        .get_version()
         .await
-        .expect("response error");
-    let response = Decode!(&response_blob, Nat).unwrap();
-    response
+        .expect("response error")
 }
 
 /// Get DataBox canister state
@@ -510,15 +337,10 @@ pub async fn get_version(pem_identity_path: &str, data_box_canister_id_text: &st
 /// }
 /// ```
 pub async fn get_canister_state(pem_identity_path: &str, data_box_canister_id_text: &str,) -> CanisterStateResult {
-    let canister_id = Principal::from_text(data_box_canister_id_text).unwrap();
-    let response_blob = build_agent(pem_identity_path)
-        .query(&canister_id, "canisterState")
-        .with_arg(Encode!().expect("encode piece failed"))
-        .call()
+    build_client(pem_identity_path, data_box_canister_id_text)
+        .get_canister_state()
         .await
-        .expect("response error");
-    let response = Decode!(&response_blob, CanisterStateResult).unwrap();
-    response
+        .expect("response error")
 }
 
 /// Get DataBox cycle balance
@@ -537,15 +359,10 @@ pub async fn get_canister_state(pem_identity_path: &str, data_box_canister_id_te
 /// }
 /// ```
 pub async fn get_cycle_balance(pem_identity_path: &str, data_box_canister_id_text: &str,) -> CycleBalanceResult {
-    let canister_id = Principal::from_text(data_box_canister_id_text).unwrap();
-    let response_blob = build_agent(pem_identity_path)
-        .query(&canister_id, "cycleBalance")
-        .with_arg(Encode!().expect("encode piece failed"))
-        .call()
+    build_client(pem_identity_path, data_box_canister_id_text)
+        .get_cycle_balance()
         .await
-        .expect("response error");
-    let response = Decode!(&response_blob, CycleBalanceResult).unwrap();
-    response
+        .expect("response error")
 }
 
 /// Get DataBox available stable memory
@@ -564,15 +381,10 @@ pub async fn get_cycle_balance(pem_identity_path: &str, data_box_canister_id_tex
 /// }
 /// ```
 pub async fn get_avl_sm(pem_identity_path: &str, data_box_canister_id_text: &str,) -> AvlSMResult {
-    let canister_id = Principal::from_text(data_box_canister_id_text).unwrap();
-    let response_blob = build_agent(pem_identity_path)
-        .query(&canister_id, "avlSM")
-        .with_arg(Encode!().expect("encode piece failed"))
-        .call()
+    build_client(pem_identity_path, data_box_canister_id_text)
+        .get_avl_sm()
         .await
-        .expect("response error");
-    let response = Decode!(&response_blob, AvlSMResult).unwrap();
-    response
+        .expect("response error")
 }
 
 /// Get DataBox owner
@@ -592,20 +404,618 @@ pub async fn get_avl_sm(pem_identity_path: &str, data_box_canister_id_text: &str
 /// }
 /// ```
 pub async fn get_owner(pem_identity_path: &str, data_box_canister_id_text: &str,) -> candid::Principal {
-    let canister_id = Principal::from_text(data_box_canister_id_text).unwrap();
-    let response_blob = build_agent(pem_identity_path)
-        .query(&canister_id, "getOwner")
-        .with_arg(Encode!().expect("encode piece failed"))
-        .call()
+    build_client(pem_identity_path, data_box_canister_id_text)
+        .get_owner()
         .await
-        .expect("response error");
-    let response = Decode!(&response_blob, candid::Principal).unwrap();
-    response
+        .expect("response error")
+}
+
+/// DataBox client bound to one agent and one DataBox canister
+///
+/// The free functions of this module build a mainnet agent per call, the client lets the caller
+/// choose the network and identity once and reuse the agent.
+///
+/// Example code :
+/// ``` no_run
+/// use candid::Principal;
+/// use metabox_sdk::agent::{self, Network};
+/// use metabox_sdk::databox::DataBoxClient;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let agent = agent::build_agent_for("identities/identity.pem", &Network::Ic).await.unwrap();
+///     let client = DataBoxClient::new(agent, Principal::from_text("4radi-oqaaa-aaaan-qapwa-cai").unwrap());
+///     println!("data box version: {:?}", client.get_version().await);
+/// }
+/// ```
+#[derive(Clone)]
+pub struct DataBoxClient {
+    transport: Transport,
+    canister_id: Principal,
+    retry: RetryPolicies,
+    key_scheme: KeyScheme,
+    cache: Option<InventoryCache>,
+}
+
+impl DataBoxClient {
+    pub fn new(agent: Agent, canister_id: Principal) -> Self {
+        DataBoxClient::with_transport(Transport::Agent(agent), canister_id)
+    }
+
+    /// Client of an in-memory [`MockDataBox`], calls are made as `caller` and never leave the process
+    pub fn mock(data_box: &MockDataBox, caller: Principal) -> Self {
+        let transport = Transport::Mock {
+            canister: data_box.canister(),
+            caller,
+        };
+        DataBoxClient::with_transport(transport, data_box.canister_id())
+    }
+
+    pub(crate) fn with_transport(transport: Transport, canister_id: Principal) -> Self {
+        DataBoxClient {
+            transport,
+            canister_id,
+            retry: RetryPolicies::default(),
+            key_scheme: KeyScheme::default(),
+            cache: None,
+        }
+    }
+
+    pub fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+
+    /// Retry policy of every call, [`RetryPolicy::default`] unless set
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry.default = policy;
+        self
+    }
+
+    /// Retry policy of one canister method (`"put"`, `"getPlain"`, ...), over the client 's policy
+    pub fn with_method_retry_policy(mut self, method_name: &str, policy: RetryPolicy) -> Self {
+        self.retry.methods.insert(method_name.to_string(), policy);
+        self
+    }
+
+    /// Scheme of the keys of uploaded files, [`KeyScheme::V1`] by default
+    pub fn with_key_scheme(mut self, key_scheme: KeyScheme) -> Self {
+        self.key_scheme = key_scheme;
+        self
+    }
+
+    /// Answer listings and file information from an on-disk cache, see [`cache`]
+    ///
+    /// Example code :
+    /// ```
+    /// use candid::Principal;
+    /// use metabox_sdk::databox::DataBoxClient;
+    /// use metabox_sdk::databox::cache::InventoryCache;
+    /// use metabox_sdk::databox::mock::MockDataBox;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let dir = std::env::temp_dir().join("metabox-cache-example");
+    ///     let _ = std::fs::remove_dir_all(&dir);
+    ///     let cache = InventoryCache::new(&dir).unwrap();
+    ///     let owner = Principal::anonymous();
+    ///     let data_box = MockDataBox::new(Principal::management_canister(), owner);
+    ///     let client = DataBoxClient::mock(&data_box, owner).with_cache(cache.clone());
+    ///
+    ///     assert!(client.get_all_plain_files_info().await.unwrap().unwrap().is_empty());
+    ///     // The upload drops the cached inventory
+    ///     let put = client.put_bytes(b"hello".to_vec(), "hello.txt", "text/plain").await.unwrap();
+    ///     assert_eq!(client.get_all_plain_files_info().await.unwrap().unwrap().len(), 1);
+    ///
+    ///     // Offline, the box is not asked : a file deleted by another client is still listed
+    ///     DataBoxClient::mock(&data_box, owner).delete_file(put.file_key.clone()).await.unwrap();
+    ///     let offline = DataBoxClient::mock(&data_box, owner).with_cache(cache.with_offline(true));
+    ///     assert!(offline.get_file_info(&put.file_key).await.unwrap().is_ok());
+    /// }
+    /// ```
+    pub fn with_cache(mut self, cache: InventoryCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub async fn put_plain_files(&self, folder_path: &str) -> Result<Vec<PutPlainFileResult>, CallError> {
+        self.put_plain_files_with(folder_path, &Transfer::default()).await
+    }
+
+    /// [`DataBoxClient::put_plain_files`] reporting to the transfer 's observer, file by file
+    ///
+    /// When the transfer is cancelled, the file in progress ends `UploadStatus::Cancelled` and the
+    /// files after it are left out of the results.
+    pub async fn put_plain_files_with(&self, folder_path: &str, transfer: &Transfer) -> Result<Vec<PutPlainFileResult>, CallError> {
+        let paths = fs::read_dir(folder_path)?.map(|entry| entry.map(|entry| entry.path())).collect::<Result<Vec<_>, _>>()?;
+        self.put_plain_paths_with(&paths, transfer).await
+    }
+
+    /// Upload a batch of files, in order
+    ///
+    /// With a capacity policy, the batch is checked as a whole before anything is sent.
+    pub async fn put_plain_paths_with(&self, paths: &[PathBuf], transfer: &Transfer) -> Result<Vec<PutPlainFileResult>, CallError> {
+        let mut ans: Vec<PutPlainFileResult> = Vec::new();
+        let count = paths.len();
+        let rejected = match self.check_capacity(paths, transfer).await? {
+            Ok(rejected) => rejected,
+            Err(refused) => return Ok(refused),
+        };
+        // The batch was checked as a whole, not file by file
+        let transfer = transfer.clone().with_capacity_policy(CapacityPolicy::Unchecked);
+        for (index, path) in paths.iter().enumerate() {
+            // Files after a cancellation are not started
+            if transfer.is_cancelled() {
+                break;
+            }
+            match rejected.iter().find(|(rejected_index, _)| *rejected_index == index) {
+                Some((_, file)) => ans.push(PutPlainFileResult {
+                    file_name: file.file_name.clone(),
+                    file_extension: file.file_extension.clone(),
+                    file_key: file.file_key.clone(),
+                    upload_status: UploadStatus::Err(DataErr::MemoryInsufficient),
+                    databox_canister_id: self.canister_id,
+                    total_size: file.total_size,
+                    chunk_number: file.chunk_number,
+                }),
+                None => ans.push(self.put_plain(path, None, None, &transfer.in_batch(index, count)).await?),
+            }
+        }
+        Ok(ans)
+    }
+
+    /// The files of a batch which do not fit in the box (by index), following the transfer 's
+    /// capacity policy, or the results of a batch refused as a whole
+    async fn check_capacity(
+        &self,
+        paths: &[PathBuf],
+        transfer: &Transfer,
+    ) -> Result<Result<Vec<(usize, PlannedFile)>, Vec<PutPlainFileResult>>, CallError> {
+        let policy = transfer.capacity_policy();
+        if policy == CapacityPolicy::Unchecked {
+            return Ok(Ok(Vec::new()));
+        }
+        let mut files = Vec::new();
+        for path in paths {
+            let data = Bytes::from(fs::read(path)?);
+            let mut file = PlannedFile::new(&data, &path.file_name().unwrap_or_default().to_string_lossy(), None, self.key_scheme);
+            capacity::exclude_stored(self, &mut file, transfer).await?;
+            files.push(file);
+        }
+        let plan = match capacity::plan_upload(self, files.clone()).await? {
+            Ok(plan) => plan,
+            Err(data_err) => {
+                let refused = files
+                    .into_iter()
+                    .map(|file| PutPlainFileResult {
+                        file_name: file.file_name,
+                        file_extension: file.file_extension,
+                        file_key: file.file_key,
+                        upload_status: UploadStatus::Err(data_err.clone()),
+                        databox_canister_id: self.canister_id,
+                        total_size: file.total_size,
+                        chunk_number: file.chunk_number,
+                    })
+                    .collect();
+                return Ok(Err(refused));
+            }
+        };
+        if plan.rejected.is_empty() {
+            return Ok(Ok(Vec::new()));
+        }
+        if policy == CapacityPolicy::Reject {
+            return Err(CallError::Capacity(Box::new(plan.error())));
+        }
+        let mut rejected = plan.rejected.into_iter().peekable();
+        let mut by_index = Vec::new();
+        for (index, file) in files.iter().enumerate() {
+            if rejected.peek().is_some_and(|next| next.file_key == file.file_key && next.file_name == file.file_name) {
+                by_index.push((index, rejected.next().unwrap()));
+            }
+        }
+        Ok(Ok(by_index))
+    }
+
+    pub async fn put_plain_file(&self, file_path_str: &str) -> Result<PutPlainFileResult, CallError> {
+        self.put_plain_file_with(file_path_str, &Transfer::default()).await
+    }
+
+    /// [`DataBoxClient::put_plain_file`] reporting to the transfer 's observer
+    pub async fn put_plain_file_with(&self, file_path_str: &str, transfer: &Transfer) -> Result<PutPlainFileResult, CallError> {
+        self.put_plain(Path::new(file_path_str), None, None, transfer).await
+    }
+
+    /// [`DataBoxClient::put_plain_file_with`] storing `file_name` and `content_type` instead of the
+    /// ones of the path, when given
+    pub async fn put_plain_file_as(
+        &self,
+        file_path_str: &str,
+        file_name: Option<&str>,
+        content_type: Option<&str>,
+        transfer: &Transfer,
+    ) -> Result<PutPlainFileResult, CallError> {
+        self.put_plain(Path::new(file_path_str), file_name, content_type, transfer).await
+    }
+
+    /// Upload in-memory data as a plain file, with the same chunks and file key as from a path
+    ///
+    /// An extension of `file_name` which is the one of `content_type` is not part of the stored
+    /// name, like for path uploads.
+    ///
+    /// Example code :
+    /// ```
+    /// use candid::Principal;
+    /// use metabox_sdk::databox::{DataBoxClient, FileExt};
+    /// use metabox_sdk::databox::mock::MockDataBox;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let owner = Principal::anonymous();
+    ///     let client = DataBoxClient::mock(&MockDataBox::new(Principal::management_canister(), owner), owner);
+    ///     let report = b"date,total\n2022-10-01,42\n".to_vec();
+    ///     let put = client.put_bytes(report, "report.2022-10.csv", "text/csv").await.unwrap();
+    ///     match client.get_file_info(&put.file_key).await.unwrap().unwrap() {
+    ///         FileExt::PlainFileExt(asset_ext) => {
+    ///             assert_eq!(asset_ext.file_name, "report.2022-10");
+    ///             assert_eq!(asset_ext.file_extension, "text/csv");
+    ///         }
+    ///         _ => unreachable!(),
+    ///     }
+    /// }
+    /// ```
+    pub async fn put_bytes(&self, data: Vec<u8>, file_name: &str, content_type: &str) -> Result<PutPlainFileResult, CallError> {
+        self.put_named(Bytes::from(data), file_name, Some(content_type), &Transfer::default()).await
+    }
+
+    /// [`DataBoxClient::put_bytes`] of shared data, the chunks are views of `data` rather than copies
+    pub async fn put_shared_bytes(&self, data: Bytes, file_name: &str, content_type: &str) -> Result<PutPlainFileResult, CallError> {
+        self.put_named(data, file_name, Some(content_type), &Transfer::default()).await
+    }
+
+    /// [`DataBoxClient::put_shared_bytes`] reporting to the transfer 's observer, the type is detected
+    /// from the content and `file_name` when `content_type` is `None`
+    pub async fn put_bytes_with(
+        &self,
+        data: Bytes,
+        file_name: &str,
+        content_type: Option<&str>,
+        transfer: &Transfer,
+    ) -> Result<PutPlainFileResult, CallError> {
+        self.put_named(data, file_name, content_type, transfer).await
+    }
+
+    async fn put_plain(
+        &self,
+        file_path: &Path,
+        file_name: Option<&str>,
+        content_type: Option<&str>,
+        transfer: &Transfer,
+    ) -> Result<PutPlainFileResult, CallError> {
+        let data = fs::read(file_path)?;
+        let name = match file_name {
+            Some(file_name) => file_name.to_string(),
+            None => file_path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+        };
+        self.put_named(Bytes::from(data), &name, content_type, transfer).await
+    }
+
+    async fn put_named(&self, data: Bytes, name: &str, content_type: Option<&str>, transfer: &Transfer) -> Result<PutPlainFileResult, CallError> {
+        let file_extension = match content_type {
+            Some(content_type) => content_type.to_string(),
+            None => mime::detect(name, &data),
+        };
+        let file_name = stored_name(name, &file_extension);
+        backend::put_slices(self, file_name, file_extension, data, transfer).await
+    }
+
+    /// Upload a file as the avatar of the box, as it is
+    ///
+    /// A copy is kept in the box for [`DataBoxClient::get_avatar`], an [internal](is_internal) file.
+    pub async fn upload_avatar(&self, avatar_file_path: &str) -> Result<UploadResult, CallError> {
+        let context = fs::read(avatar_file_path)?;
+        let file_extension = mime::detect(avatar_file_path, &context);
+        let upload_args = Avatar {
+            data: context,
+            data_type: file_extension,
+        };
+        self.set_avatar(&upload_args).await
+    }
+
+    /// Upload an image as the avatar of the box, cropped and scaled by [`avatar::prepare_avatar`]
+    ///
+    /// Example code :
+    /// ```
+    /// use candid::Principal;
+    /// use metabox_sdk::databox::DataBoxClient;
+    /// use metabox_sdk::databox::avatar::AvatarOptions;
+    /// use metabox_sdk::databox::image::{self, DynamicImage};
+    /// use metabox_sdk::databox::mock::MockDataBox;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let owner = Principal::anonymous();
+    ///     let client = DataBoxClient::mock(&MockDataBox::new(Principal::management_canister(), owner), owner);
+    ///     let photo = image::encode_png(&DynamicImage::new_rgba8(600, 400)).unwrap();
+    ///     let avatar = client.upload_avatar_bytes(&photo, &AvatarOptions::default()).await.unwrap().unwrap();
+    ///
+    ///     let stored = client.get_avatar().await.unwrap().unwrap().unwrap();
+    ///     assert_eq!(stored.data, avatar.data);
+    ///     assert_eq!(image::dimensions(&stored.data), Some((256, 256)));
+    ///     // The copy is not listed
+    ///     assert!(client.get_all_plain_files_info().await.unwrap().unwrap().is_empty());
+    /// }
+    /// ```
+    pub async fn upload_avatar_bytes(&self, data: &[u8], options: &AvatarOptions) -> Result<Result<Avatar, AvatarError>, CallError> {
+        let avatar = match avatar::prepare_avatar(data, options) {
+            Ok(avatar) => avatar,
+            Err(error) => return Ok(Err(error)),
+        };
+        Ok(match self.set_avatar(&avatar).await? {
+            UploadResult::ok => Ok(avatar),
+            UploadResult::err(data_err) => Err(data_err.into()),
+        })
+    }
+
+    /// The avatar of the box, as last uploaded through the SDK, `None` when there is none
+    pub async fn get_avatar(&self) -> Result<Result<Option<Avatar>, DataErr>, CallError> {
+        let copy = match self.avatar_copies().await? {
+            Ok(copies) => copies.into_iter().next(),
+            Err(data_err) => return Ok(Err(data_err)),
+        };
+        let asset_ext = match copy {
+            Some(asset_ext) => asset_ext,
+            None => return Ok(Ok(None)),
+        };
+        Ok(self.get_plain_file(&asset_ext.file_key).await?.map(|data| {
+            Some(Avatar {
+                data,
+                data_type: asset_ext.file_extension,
+            })
+        }))
+    }
+
+    pub async fn delete_file(&self, file_key: String) -> Result<DeleteKeyResult, CallError> {
+        self.delete(&file_key).await
+    }
+
+    pub async fn clear_data_box(&self) -> Result<ClearAllResult, CallError> {
+        let result = self.update("clearall", Encode!()?).await;
+        self.invalidate_cache();
+        result
+    }
+
+    pub async fn get_plain_file(&self, file_key: &str) -> Result<Result<Vec<u8>, DataErr>, CallError> {
+        self.get_plain_file_with(file_key, &Transfer::default()).await
+    }
+
+    /// [`DataBoxClient::get_plain_file`] reporting to the transfer 's observer
+    pub async fn get_plain_file_with(&self, file_key: &str, transfer: &Transfer) -> Result<Result<Vec<u8>, DataErr>, CallError> {
+        backend::get_plain_data(self, file_key, transfer).await
+    }
+
+    /// Get `len` bytes of a plain file from `offset`, only the chunks covering them are fetched
+    ///
+    /// Example code :
+    /// ```
+    /// use candid::Principal;
+    /// use metabox_sdk::databox::DataBoxClient;
+    /// use metabox_sdk::databox::mock::MockDataBox;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let owner = Principal::anonymous();
+    ///     let client = DataBoxClient::mock(&MockDataBox::new(Principal::management_canister(), owner), owner);
+    ///     let data: Vec<u8> = (0..5_000_000).map(|i| i as u8).collect();
+    ///     let put = client.put_bytes(data.clone(), "archive.bin", "application/octet-stream").await.unwrap();
+    ///
+    ///     let range = client.read_range(&put.file_key, 1_990_000, 10_000).await.unwrap().unwrap();
+    ///     assert_eq!(range, &data[1_990_000..2_000_000]);
+    ///     let tail = client.read_range(&put.file_key, 4_999_990, 100).await.unwrap().unwrap();
+    ///     assert_eq!(tail, &data[4_999_990..]);
+    ///     assert!(client.read_range(&put.file_key, 6_000_000, 100).await.unwrap().unwrap().is_empty());
+    /// }
+    /// ```
+    pub async fn read_range(&self, file_key: &str, offset: u64, len: u64) -> Result<Result<Vec<u8>, DataErr>, CallError> {
+        self.read_range_with(file_key, offset, len, &Transfer::default()).await
+    }
+
+    /// [`DataBoxClient::read_range`] reporting to the transfer 's observer
+    pub async fn read_range_with(&self, file_key: &str, offset: u64, len: u64, transfer: &Transfer) -> Result<Result<Vec<u8>, DataErr>, CallError> {
+        backend::get_plain_range(self, file_key, offset, len, transfer).await
+    }
+
+    /// Get one chunk of a plain file, chunk `index` holds the bytes from `index * UPDATE_SIZE`
+    pub async fn get_plain_chunk(&self, file_key: &str, index: u64) -> Result<GetPlainResult, CallError> {
+        self.get_chunk(file_key, index, &Transfer::default()).await
+    }
+
+    /// Information of a file, from the cached inventory when there is one
+    pub async fn get_file_info(&self, file_key: &str) -> Result<Result<FileExt, DataErr>, CallError> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return self.info(file_key).await,
+        };
+        let cached = cache.load(&self.canister_id);
+        let cached_file = cached.as_ref().and_then(|inventory| inventory.find(file_key).map(|file| (inventory, file)));
+        match cached_file {
+            Some((inventory, file)) if cache.is_fresh(inventory) => return Ok(Ok(file.clone())),
+            _ if cache.is_offline() => return Ok(Err(DataErr::FileKeyErr)),
+            _ => {}
+        }
+        match self.info(file_key).await {
+            // The box can not be reached, what was last known of the file is answered
+            Err(CallError::Agent(_)) if cached_file.is_some() => Ok(Ok(cached_file.unwrap().1.clone())),
+            result => result,
+        }
+    }
+
+    /// Information of the plain files, from the cached inventory when there is one, the
+    /// [internal](is_internal) files aside
+    pub async fn get_all_plain_files_info(&self) -> Result<Result<Vec<FileExt>, DataErr>, CallError> {
+        let files = match &self.cache {
+            Some(cache) => self.cached_inventory(cache).await?.map(|inventory| inventory.files),
+            None => self.list().await?,
+        };
+        Ok(files.map(|files| {
+            files
+                .into_iter()
+                .filter(|file| matches!(file, FileExt::PlainFileExt(_)) && !is_internal(file))
+                .collect()
+        }))
+    }
+
+    /// Information of the plain, encrypted and shared files, from the cached inventory when there
+    /// is one, the [internal](is_internal) files aside
+    pub async fn get_all_files_info(&self) -> Result<Result<Vec<FileExt>, DataErr>, CallError> {
+        let files = match &self.cache {
+            Some(cache) => self.cached_inventory(cache).await?.map(|inventory| inventory.files),
+            None => self.list_all().await?,
+        };
+        Ok(files.map(|files| files.into_iter().filter(|file| !is_internal(file)).collect()))
+    }
+
+    pub async fn get_version(&self) -> Result<Nat, CallError> {
+        self.query("getVersion", Encode!()?).await
+    }
+
+    pub async fn get_canister_state(&self) -> Result<CanisterStateResult, CallError> {
+        self.state().await
+    }
+
+    pub async fn get_cycle_balance(&self) -> Result<CycleBalanceResult, CallError> {
+        self.query("cycleBalance", Encode!()?).await
+    }
+
+    pub async fn get_avl_sm(&self) -> Result<AvlSMResult, CallError> {
+        self.available_memory().await
+    }
+
+    pub async fn get_owner(&self) -> Result<Principal, CallError> {
+        self.query("getOwner", Encode!()?).await
+    }
+
+    async fn cached_inventory(&self, cache: &InventoryCache) -> Result<Result<Inventory, DataErr>, CallError> {
+        let cached = cache.load(&self.canister_id);
+        match cached {
+            Some(inventory) if cache.is_fresh(&inventory) => return Ok(Ok(inventory)),
+            None if cache.is_offline() => {
+                let message = format!("no cached inventory of {}", self.canister_id);
+                return Err(io::Error::new(io::ErrorKind::NotFound, message).into());
+            }
+            _ => {}
+        }
+        match self.list_all().await {
+            Ok(Ok(files)) => {
+                // The cache is an optimisation, a failed write only costs a listing
+                let _ = cache.store(&self.canister_id, &files);
+                Ok(Ok(Inventory { fetched_at: SystemTime::now(), files }))
+            }
+            Ok(Err(data_err)) => Ok(Err(data_err)),
+            Err(CallError::Agent(_)) if cached.is_some() => Ok(Ok(cached.unwrap())),
+            Err(error) => Err(error),
+        }
+    }
+
+    async fn list_all(&self) -> Result<Result<Vec<FileExt>, DataErr>, CallError> {
+        let response: GetAssetExtsResult = self.query("getAssetexts", Encode!()?).await?;
+        Ok(match response {
+            GetAssetExtsResult::ok(plain_assets, encrypt_assets, shared_assets, ..) => {
+                Ok(plain_assets.into_iter().chain(encrypt_assets).chain(shared_assets).collect())
+            }
+            GetAssetExtsResult::err(data_err) => Err(data_err),
+        })
+    }
+
+    // `upload`, then keep a copy of the avatar in place of the previous one
+    async fn set_avatar(&self, avatar: &Avatar) -> Result<UploadResult, CallError> {
+        let result: UploadResult = self.update("upload", Encode!(avatar)?).await?;
+        if let UploadResult::ok = result {
+            let data = Bytes::from(avatar.data.clone());
+            let copy = self.put_bytes_with(data, AVATAR_NAME, Some(&avatar.data_type), &Transfer::default()).await?;
+            if let UploadStatus::Ok | UploadStatus::AlreadyExists = copy.upload_status {
+                if let Ok(copies) = self.avatar_copies().await? {
+                    for previous in copies.into_iter().filter(|asset_ext| asset_ext.file_key != copy.file_key) {
+                        self.delete(&previous.file_key).await?;
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    // The complete copies of the avatar in the box
+    async fn avatar_copies(&self) -> Result<Result<Vec<AssetExt>, DataErr>, CallError> {
+        Ok(self.list().await?.map(|files| {
+            files
+                .into_iter()
+                .filter_map(|file| match file {
+                    FileExt::PlainFileExt(asset_ext) if asset_ext.upload_status && asset_ext.file_name == AVATAR_NAME => Some(asset_ext),
+                    _ => None,
+                })
+                .collect()
+        }))
+    }
+
+    // After a mutation, the cached inventory is stale
+    fn invalidate_cache(&self) {
+        if let Some(cache) = &self.cache {
+            let _ = cache.invalidate(&self.canister_id);
+        }
+    }
+
+    async fn query<R: CandidType + DeserializeOwned>(&self, method_name: &str, arg: Vec<u8>) -> Result<R, CallError> {
+        let response_blob = self.transport.query(&self.canister_id, method_name, arg, self.retry.for_method(method_name), None).await?;
+        Ok(Decode!(&response_blob, R)?)
+    }
+
+    async fn update<R: CandidType + DeserializeOwned>(&self, method_name: &str, arg: Vec<u8>) -> Result<R, CallError> {
+        self.update_observed(method_name, arg, None).await
+    }
+
+    /// Update reporting its retries to `observer`
+    async fn update_observed<R: CandidType + DeserializeOwned>(
+        &self,
+        method_name: &str,
+        arg: Vec<u8>,
+        observer: Option<&dyn ProgressObserver>,
+    ) -> Result<R, CallError> {
+        let response_blob = self.transport.update(&self.canister_id, method_name, arg, &self.retry.for_update(method_name), observer).await?;
+        Ok(Decode!(&response_blob, R)?)
+    }
 }
 
-// Access file from file path, slice and return [each slice] array
-fn get_file_from_source(path: &str) -> (usize, usize, Vec<Vec<u8>>) {
-    let context = fs::read(path).expect("read file failed");
+/// Whether a file is one the SDK keeps in the box for itself : the copy of the avatar and the
+/// versions of the manifest
+///
+/// The listings of [`DataBoxClient`], search, copy, export and mounts leave these files out.
+pub fn is_internal(file: &FileExt) -> bool {
+    match file {
+        FileExt::PlainFileExt(asset_ext) => {
+            asset_ext.file_name == AVATAR_NAME
+                || (asset_ext.file_extension == manifest::MANIFEST_TYPE && asset_ext.file_name.starts_with(manifest::MANIFEST_NAME))
+        }
+        _ => false,
+    }
+}
+
+// A count or an index of the box, `None` past `u64`
+pub(crate) fn nat_to_u64(nat: &Nat) -> Option<u64> {
+    match nat.0.to_u64_digits().as_slice() {
+        [] => Some(0),
+        [value] => Some(*value),
+        _ => None,
+    }
+}
+
+// The name a file is stored under : without the extension its MIME type gives it back
+pub(crate) fn stored_name(name: &str, mime_type: &str) -> String {
+    match mime::split_name(name) {
+        (stem, Some(extension)) if mime::from_extension(extension) == mime_type => stem.to_string(),
+        _ => name.to_string(),
+    }
+}
+
+// Slice data into [each slice] array, the slices share the data without copying it
+fn slice_data(context: Bytes) -> (usize, usize, Vec<Bytes>) {
     let size = context.len();
     let slice_size = if context.len() % UPDATE_SIZE == 0 {
         context.len() / UPDATE_SIZE
@@ -615,115 +1025,41 @@ fn get_file_from_source(path: &str) -> (usize, usize, Vec<Vec<u8>>) {
     let mut res = Vec::new();
     for index in 0..slice_size {
         if index == slice_size - 1 {
-            res.push(context[index * UPDATE_SIZE..context.len()].to_owned())
+            res.push(context.slice(index * UPDATE_SIZE..context.len()))
         } else {
-            res.push(context[index * UPDATE_SIZE..(index + 1) * UPDATE_SIZE].to_owned())
+            res.push(context.slice(index * UPDATE_SIZE..(index + 1) * UPDATE_SIZE))
         }
     }
     (size, slice_size, res)
 }
 
-fn build_put_plain_args(
-    file_name: String,
-    file_extension: String,
+// The put of one slice, built when it is sent so that a single copy of the data is held
+fn build_put_plain_arg(
+    file_key: &str,
+    file_name: &str,
+    file_extension: &str,
     total_size: u64,
     chunk_number: u64,
-    data_slice: &Vec<Vec<u8>>,
-) -> Vec<FilePut> {
-    let mut order = 0;
-    let mut puts: Vec<FilePut> = Vec::new();
-    let file_key = get_file_key(&get_file_sha256_digest(data_slice));
-    for data in data_slice {
-        puts.push(FilePut::PlainFilePut(PUT::segment {
-            aes_pub_key: None,
-            file_key: file_key.clone(),
-            file_name: file_name.clone(),
-            file_extension: file_extension.clone(),
-            chunk: Chunk {
-                data: data.clone(),
-            },
-            chunk_number: Nat::from(chunk_number),
-            order: Nat::from(order),
-            total_size: total_size.clone(),
-        }));
-        order += 1;
-    }
-    puts
-}
-
-fn get_file_sha256_digest(context: &Vec<Vec<u8>>) -> Vec<Vec<u8>> {
-    let mut digests = vec![vec![0x00 as u8]; context.len()];
-    let mut contents = digests.iter_mut().zip(context.iter()).collect::<Vec<_>>();
-    contents
-        .par_iter_mut()
-        .for_each(|(d, text)| **d = digest_bytes(*text).into_bytes()[..32].to_vec());
-    digests
-}
-
-fn get_file_key(digests: &Vec<Vec<u8>>) -> String {
-    let mut digest = vec![0x00 as u8; 32 * digests.len()];
-    let mut _index = 0;
-    for bytes in digests {
-        for byte in bytes {
-            digest.push(*byte);
-            _index += 1;
-        }
-    }
-    digest_bytes(&digest)
-}
-
-fn get_file_type(file_type: &str) -> &str {
-    if file_type == "pdf" {
-        return "application/pdf";
-    } else if file_type == "jpg" || file_type == "jpeg" {
-        return "image/jpg";
-    } else if file_type == "png" {
-        return "image/png";
-    } else if file_type == "mp4" {
-        return "video/mp4";
-    } else if file_type == "mp3" {
-        return "audio/mp3";
-    } else if file_type == "gif" {
-        return "image/gif";
-    } else if file_type == "txt" {
-        return "text/plain";
-    } else if file_type == "ppt" || file_type == "pptx" {
-        return "application/vnd.ms-powerpoint";
-    } else if file_type == "html" || file_type == "xhtml" {
-        return "text/html";
-    } else if file_type == "doc" || file_type == "docx" {
-        return "application/msword";
-    } else if file_type == "xls" {
-        return "application/x-xls";
-    } else if file_type == "apk" {
-        return "application/vnd.android.package-archive";
-    } else if file_type == "svg" {
-        return "text/xml";
-    } else if file_type == "wmv" {
-        return "video/x-ms-wmv";
-    } else {
-        return "application/octet-stream";
-    }
-}
-
-fn get_waiter() -> Delay {
-    let waiter = garcon::Delay::builder()
-        .throttle(std::time::Duration::from_millis(500))
-        .timeout(std::time::Duration::from_secs(60 * 5))
-        .build();
-    waiter
+    order: u64,
+    data: &[u8],
+) -> FilePut {
+    FilePut::PlainFilePut(PUT::segment {
+        aes_pub_key: None,
+        file_key: file_key.to_string(),
+        file_name: file_name.to_string(),
+        file_extension: file_extension.to_string(),
+        chunk: Chunk {
+            data: data.to_vec(),
+        },
+        chunk_number: Nat::from(chunk_number),
+        order: Nat::from(order),
+        total_size,
+    })
 }
 
-fn build_agent(pem_identity_path: &str) -> Agent {
-    let url = "https://ic0.app".to_string();
-    let identity = Secp256k1Identity::from_pem_file(String::from(pem_identity_path)).unwrap();
-    let transport = ReqwestHttpReplicaV2Transport::create(url).expect("transport error");
-    let agent = Agent::builder()
-        .with_transport(transport)
-        .with_identity(identity)
-        .build()
-        .expect("build agent error");
-    agent
+fn build_client(pem_identity_path: &str, data_box_canister_id_text: &str) -> DataBoxClient {
+    let canister_id = Principal::from_text(data_box_canister_id_text).unwrap();
+    DataBoxClient::new(build_agent(pem_identity_path), canister_id)
 }
 
 // pub async fn put_encrypt_files(pem_identity_path: &str, folder_path: &str, databox_canister_id_text: &str,) -> Vec<PutPlainFileResult> {
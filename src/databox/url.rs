@@ -0,0 +1,207 @@
+//! HTTP URLs of box files
+//!
+//! A DataBox serves its public plain files over HTTP at `/fk/<file_key>`, through a gateway :
+//!
+//! * certified, `https://<canister>.ic0.app/fk/<file_key>`, the gateway checks the answer is
+//!   certified by the canister ;
+//! * raw, `https://<canister>.raw.ic0.app/fk/<file_key>`, it is passed as it is.
+//!
+//! A [`Gateway`] builds both for the mainnet, a local replica or any other host. A URL only
+//! serves a file which is [`Reachability::Public`] : complete, not encrypted, in a public box.
+use std::fmt::Write;
+use candid::Principal;
+use crate::agent::{CallError, Network};
+use crate::metabox::MetaBoxClient;
+use super::{DataBoxClient, DataErr, FileExt};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GatewayStyle {
+    /// The canister is a subdomain : `<canister>.<host>`, `<canister>.raw.<host>`
+    Subdomain,
+    /// The canister is a query parameter, `?canisterId=<canister>`, for hosts without subdomains
+    /// (`127.0.0.1`) : raw and certified URLs are the same
+    Query,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Gateway {
+    scheme: String,
+    /// Host, and port
+    host: String,
+    style: GatewayStyle,
+}
+
+impl Default for Gateway {
+    fn default() -> Self {
+        Gateway::ic()
+    }
+}
+
+impl Gateway {
+    /// Gateway of `host` (with its port), `scheme` is `https` or `http`
+    pub fn new(scheme: &str, host: &str, style: GatewayStyle) -> Self {
+        Gateway {
+            scheme: scheme.to_string(),
+            host: host.trim_end_matches('/').to_string(),
+            style,
+        }
+    }
+
+    /// The mainnet gateway, `ic0.app`
+    pub fn ic() -> Self {
+        Gateway::new("https", "ic0.app", GatewayStyle::Subdomain)
+    }
+
+    /// The gateway of a local replica started by dfx, `localhost:4943`
+    pub fn local() -> Self {
+        Gateway::new("http", "localhost:4943", GatewayStyle::Subdomain)
+    }
+
+    /// The gateway of the replica of a network, by the query style for an IP address
+    ///
+    /// Example code :
+    /// ```
+    /// use metabox_sdk::agent::Network;
+    /// use metabox_sdk::databox::url::Gateway;
+    ///
+    /// let gateway = Gateway::for_network(&Network::Custom("http://127.0.0.1:8080".to_string()));
+    /// let canister_id = candid::Principal::from_text("4radi-oqaaa-aaaan-qapwa-cai").unwrap();
+    /// assert_eq!(gateway.file_url(&canister_id, "3f9a"), "http://127.0.0.1:8080/fk/3f9a?canisterId=4radi-oqaaa-aaaan-qapwa-cai");
+    /// ```
+    pub fn for_network(network: &Network) -> Self {
+        match network {
+            Network::Ic => Gateway::ic(),
+            Network::Local => Gateway::local(),
+            Network::Custom(url) => {
+                let (scheme, host) = url.split_once("://").unwrap_or(("https", url));
+                let host = host.split('/').next().unwrap_or(host);
+                let name = host.rsplit_once(':').map_or(host, |(name, _)| name);
+                let is_address = name.parse::<std::net::IpAddr>().is_ok() || name.starts_with('[');
+                let style = if is_address { GatewayStyle::Query } else { GatewayStyle::Subdomain };
+                Gateway::new(scheme, host, style)
+            }
+        }
+    }
+
+    /// The certified URL of a file
+    ///
+    /// Example code :
+    /// ```
+    /// use candid::Principal;
+    /// use metabox_sdk::databox::url::Gateway;
+    ///
+    /// let canister_id = Principal::from_text("4radi-oqaaa-aaaan-qapwa-cai").unwrap();
+    /// assert_eq!(Gateway::ic().file_url(&canister_id, "3f9a"), "https://4radi-oqaaa-aaaan-qapwa-cai.ic0.app/fk/3f9a");
+    /// assert_eq!(Gateway::ic().raw_file_url(&canister_id, "3f9a"), "https://4radi-oqaaa-aaaan-qapwa-cai.raw.ic0.app/fk/3f9a");
+    /// ```
+    pub fn file_url(&self, canister_id: &Principal, file_key: &str) -> String {
+        self.url(canister_id, file_key, false)
+    }
+
+    /// The raw URL of a file, not checked by the gateway
+    pub fn raw_file_url(&self, canister_id: &Principal, file_key: &str) -> String {
+        self.url(canister_id, file_key, true)
+    }
+
+    fn url(&self, canister_id: &Principal, file_key: &str, raw: bool) -> String {
+        let path = format!("/fk/{}", encode_path_segment(file_key));
+        match self.style {
+            GatewayStyle::Subdomain => {
+                let raw = if raw { ".raw" } else { "" };
+                format!("{}://{}{}.{}{}", self.scheme, canister_id.to_text(), raw, self.host, path)
+            }
+            GatewayStyle::Query => format!("{}://{}{}?canisterId={}", self.scheme, self.host, path, canister_id.to_text()),
+        }
+    }
+}
+
+/// Whether a URL serves a file, and why not
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reachability {
+    Public,
+    /// The box is private, its files are only served to its owner
+    PrivateBox,
+    /// Encrypted files are not served
+    Encrypted,
+    /// The upload of the file is not complete
+    Incomplete,
+    /// A file shared by another box, it is served by that box
+    Shared,
+    /// The box is not among the boxes of its owner in MetaBox, whether it is private is unknown
+    UnknownBox,
+}
+
+impl Reachability {
+    pub fn is_public(&self) -> bool {
+        *self == Reachability::Public
+    }
+}
+
+/// Whether a file of a box whose `is_private` flag is given is served to anyone
+pub fn reachability(is_private: bool, file: &FileExt) -> Reachability {
+    match file {
+        FileExt::SharedFileExt { .. } => Reachability::Shared,
+        FileExt::EncryptFileExt(_) => Reachability::Encrypted,
+        FileExt::PlainFileExt(asset_ext) if !asset_ext.upload_status => Reachability::Incomplete,
+        FileExt::PlainFileExt(_) if is_private => Reachability::PrivateBox,
+        FileExt::PlainFileExt(_) => Reachability::Public,
+    }
+}
+
+/// Whether a file of the box is served to anyone, the box is looked up among those of its owner
+///
+/// Example code :
+/// ```
+/// use candid::Principal;
+/// use metabox_sdk::databox::DataBoxClient;
+/// use metabox_sdk::databox::mock::MockDataBox;
+/// use metabox_sdk::databox::url::{check_reachability, Reachability};
+/// use metabox_sdk::metabox::MetaBoxClient;
+/// use metabox_sdk::metabox::mock::MockMetaBox;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let owner = Principal::anonymous();
+///     let meta_box = MockMetaBox::new();
+///     let private_box = MockDataBox::new(Principal::from_slice(&[7; 10]), owner);
+///     meta_box.register(private_box.clone(), "private".to_string(), true);
+///
+///     let metabox = MetaBoxClient::mock(&meta_box, owner);
+///     let client = DataBoxClient::mock(&private_box, owner);
+///     let put = client.put_bytes(vec![1; 100], "notes.txt", "text/plain").await.unwrap();
+///     let reachability = check_reachability(&metabox, &client, &put.file_key).await.unwrap().unwrap();
+///     assert_eq!(reachability, Reachability::PrivateBox);
+///     assert!(!reachability.is_public());
+/// }
+/// ```
+pub async fn check_reachability(
+    metabox: &MetaBoxClient,
+    client: &DataBoxClient,
+    file_key: &str,
+) -> Result<Result<Reachability, DataErr>, CallError> {
+    let file = match client.get_file_info(file_key).await? {
+        Ok(file) => file,
+        Err(data_err) => return Ok(Err(data_err)),
+    };
+    let owner = client.get_owner().await?;
+    let canister_id = client.canister_id();
+    let boxes = metabox.get_boxes(owner).await?;
+    Ok(Ok(match boxes.iter().find(|box_info| box_info.canister_id == canister_id) {
+        Some(box_info) => reachability(box_info.is_private, &file),
+        None => Reachability::UnknownBox,
+    }))
+}
+
+// Characters other than the unreserved ones of RFC 3986 are percent encoded
+fn encode_path_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => {
+                let _ = write!(encoded, "%{:02X}", byte);
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,384 @@
+//! FUSE kernel protocol (7.31) over `/dev/fuse`, without libfuse
+use std::ffi::{CString, OsStr};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::process::Command;
+
+pub const FUSE_KERNEL_VERSION: u32 = 7;
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+pub const FUSE_ROOT_ID: u64 = 1;
+pub const MAX_WRITE: u32 = 128 * 1024;
+pub const BUFFER_SIZE: usize = MAX_WRITE as usize + 4096;
+
+const FUSE_ASYNC_READ: u32 = 1 << 0;
+const FUSE_BIG_WRITES: u32 = 1 << 5;
+
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const DT_DIR: u32 = 4;
+pub const DT_REG: u32 = 8;
+
+pub const IN_HEADER_SIZE: usize = 40;
+const OUT_HEADER_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Lookup,
+    Forget,
+    Getattr,
+    Setattr,
+    Unlink,
+    Open,
+    Read,
+    Write,
+    Statfs,
+    Release,
+    Flush,
+    Init,
+    Opendir,
+    Readdir,
+    Releasedir,
+    Access,
+    Create,
+    Interrupt,
+    Destroy,
+    BatchForget,
+    Other(u32),
+}
+
+impl From<u32> for Opcode {
+    fn from(opcode: u32) -> Self {
+        match opcode {
+            1 => Opcode::Lookup,
+            2 => Opcode::Forget,
+            3 => Opcode::Getattr,
+            4 => Opcode::Setattr,
+            10 => Opcode::Unlink,
+            14 => Opcode::Open,
+            15 => Opcode::Read,
+            16 => Opcode::Write,
+            17 => Opcode::Statfs,
+            18 => Opcode::Release,
+            25 => Opcode::Flush,
+            26 => Opcode::Init,
+            27 => Opcode::Opendir,
+            28 => Opcode::Readdir,
+            29 => Opcode::Releasedir,
+            34 => Opcode::Access,
+            35 => Opcode::Create,
+            36 => Opcode::Interrupt,
+            38 => Opcode::Destroy,
+            42 => Opcode::BatchForget,
+            other => Opcode::Other(other),
+        }
+    }
+}
+
+/// A request read from the device : `fuse_in_header` followed by the opcode specific body
+pub struct Request<'a> {
+    pub opcode: Opcode,
+    pub unique: u64,
+    pub nodeid: u64,
+    body: &'a [u8],
+}
+
+impl<'a> Request<'a> {
+    pub fn parse(buffer: &'a [u8]) -> Option<Self> {
+        if buffer.len() < IN_HEADER_SIZE {
+            return None;
+        }
+        let len = u32_at(buffer, 0) as usize;
+        if len > buffer.len() || len < IN_HEADER_SIZE {
+            return None;
+        }
+        Some(Request {
+            opcode: Opcode::from(u32_at(buffer, 4)),
+            unique: u64_at(buffer, 8),
+            nodeid: u64_at(buffer, 16),
+            body: &buffer[IN_HEADER_SIZE..len],
+        })
+    }
+
+    pub fn u32_arg(&self, offset: usize) -> u32 {
+        self.body.get(offset..offset + 4).map(|_| u32_at(self.body, offset)).unwrap_or(0)
+    }
+
+    pub fn u64_arg(&self, offset: usize) -> u64 {
+        self.body.get(offset..offset + 8).map(|_| u64_at(self.body, offset)).unwrap_or(0)
+    }
+
+    /// Nul terminated name starting at `offset` of the body
+    pub fn name_arg(&self, offset: usize) -> &'a OsStr {
+        let bytes = self.body.get(offset..).unwrap_or(&[]);
+        let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        OsStr::from_bytes(&bytes[..end])
+    }
+
+    pub fn data_arg(&self, offset: usize, size: usize) -> &'a [u8] {
+        let start = offset.min(self.body.len());
+        let end = (offset + size).min(self.body.len());
+        &self.body[start..end]
+    }
+}
+
+fn u32_at(buffer: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes(buffer[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buffer: &[u8], offset: usize) -> u64 {
+    u64::from_ne_bytes(buffer[offset..offset + 8].try_into().unwrap())
+}
+
+/// Little builder of reply bodies in the kernel 's native layout
+#[derive(Default)]
+pub struct Reply(Vec<u8>);
+
+impl Reply {
+    pub fn u16(mut self, value: u16) -> Self {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+
+    pub fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+
+    pub fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_ne_bytes());
+        self
+    }
+
+    pub fn bytes(mut self, bytes: &[u8]) -> Self {
+        self.0.extend_from_slice(bytes);
+        self
+    }
+
+    /// `fuse_attr`
+    pub fn attr(self, attr: &Attr) -> Self {
+        self.u64(attr.ino)
+            .u64(attr.size)
+            .u64(attr.size.div_ceil(512))
+            .u64(attr.time)
+            .u64(attr.time)
+            .u64(attr.time)
+            .u32(0)
+            .u32(0)
+            .u32(0)
+            .u32(attr.mode)
+            .u32(attr.nlink)
+            .u32(attr.uid)
+            .u32(attr.gid)
+            .u32(0)
+            .u32(4096)
+            .u32(0)
+    }
+
+    /// `fuse_entry_out`
+    pub fn entry(self, attr: &Attr, ttl_secs: u64) -> Self {
+        self.u64(attr.ino).u64(0).u64(ttl_secs).u64(ttl_secs).u32(0).u32(0).attr(attr)
+    }
+
+    /// `fuse_attr_out`
+    pub fn attr_out(self, attr: &Attr, ttl_secs: u64) -> Self {
+        self.u64(ttl_secs).u32(0).u32(0).attr(attr)
+    }
+
+    /// `fuse_open_out`
+    pub fn open(self, fh: u64) -> Self {
+        self.u64(fh).u32(0).u32(0)
+    }
+
+    /// `fuse_dirent`, gives the reply back unchanged when the entry does not fit in `max_size`
+    pub fn dirent(self, ino: u64, offset: u64, kind: u32, name: &[u8], max_size: usize) -> Result<Self, Self> {
+        let entry_size = (24 + name.len()).div_ceil(8) * 8;
+        if self.0.len() + entry_size > max_size {
+            return Err(self);
+        }
+        let mut reply = self.u64(ino).u64(offset).u32(name.len() as u32).u32(kind).bytes(name);
+        reply.0.resize(reply.0.len() + entry_size - 24 - name.len(), 0);
+        Ok(reply)
+    }
+
+    /// `fuse_init_out`
+    pub fn init(self, max_readahead: u32) -> Self {
+        self.u32(FUSE_KERNEL_VERSION)
+            .u32(FUSE_KERNEL_MINOR_VERSION)
+            .u32(max_readahead)
+            .u32(FUSE_ASYNC_READ | FUSE_BIG_WRITES)
+            .u16(16)
+            .u16(12)
+            .u32(MAX_WRITE)
+            .u32(1)
+            .u16(0)
+            .u16(0)
+            .u32(0)
+            .bytes(&[0u8; 28])
+    }
+
+    /// `fuse_kstatfs`
+    pub fn statfs(self, blocks: u64, free_blocks: u64, files: u64) -> Self {
+        self.u64(blocks)
+            .u64(free_blocks)
+            .u64(free_blocks)
+            .u64(files)
+            .u64(0)
+            .u32(4096)
+            .u32(255)
+            .u32(4096)
+            .u32(0)
+            .bytes(&[0u8; 24])
+    }
+}
+
+pub struct Attr {
+    pub ino: u64,
+    pub size: u64,
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub time: u64,
+}
+
+/// An open `/dev/fuse` channel of a mounted filesystem
+pub struct Channel {
+    device: File,
+}
+
+impl Channel {
+    pub fn receive(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.device.read(buffer) {
+                Ok(size) => return Ok(size),
+                // The request was interrupted before we read it, or the kernel wants us to retry
+                Err(error) if matches!(error.raw_os_error(), Some(libc::ENOENT) | Some(libc::EINTR) | Some(libc::EAGAIN)) => continue,
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    pub fn reply_ok(&mut self, unique: u64, reply: Reply) -> io::Result<()> {
+        self.send(unique, 0, &reply.0)
+    }
+
+    pub fn reply_error(&mut self, unique: u64, errno: i32) -> io::Result<()> {
+        self.send(unique, -errno, &[])
+    }
+
+    fn send(&mut self, unique: u64, error: i32, body: &[u8]) -> io::Result<()> {
+        let mut message = Vec::with_capacity(OUT_HEADER_SIZE + body.len());
+        message.extend_from_slice(&((OUT_HEADER_SIZE + body.len()) as u32).to_ne_bytes());
+        message.extend_from_slice(&error.to_ne_bytes());
+        message.extend_from_slice(&unique.to_ne_bytes());
+        message.extend_from_slice(body);
+        match self.device.write(&message) {
+            Ok(_) => Ok(()),
+            // The request was interrupted, the kernel does not wait for the answer anymore
+            Err(error) if error.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+/// Mount through `fusermount3` / `fusermount`, falling back to mount(2) when running as root,
+/// the error tells why both failed
+pub fn mount(mountpoint: &Path, fs_name: &str) -> io::Result<Channel> {
+    let options = format!("fsname={},subtype=metabox,default_permissions", fs_name);
+    match mount_with_fusermount(mountpoint, &options) {
+        Ok(channel) => Ok(channel),
+        Err(fusermount_error) => mount_as_root(mountpoint, fs_name).map_err(|mount_error| {
+            io::Error::new(mount_error.kind(), format!("fusermount : {}, mount : {}", fusermount_error, mount_error))
+        }),
+    }
+}
+
+pub fn unmount(mountpoint: &Path) -> io::Result<()> {
+    for program in ["fusermount3", "fusermount"] {
+        if let Ok(status) = Command::new(program).arg("-u").arg("-z").arg("--").arg(mountpoint).status() {
+            if status.success() {
+                return Ok(());
+            }
+        }
+    }
+    let target = CString::new(mountpoint.as_os_str().as_bytes())?;
+    if unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn mount_with_fusermount(mountpoint: &Path, options: &str) -> io::Result<Channel> {
+    let mut fds = [0 as RawFd; 2];
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (ours, theirs) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "fusermount not found");
+    for program in ["fusermount3", "fusermount"] {
+        let status = Command::new(program)
+            .arg("-o")
+            .arg(options)
+            .arg("--")
+            .arg(mountpoint)
+            .env("_FUSE_COMMFD", theirs.as_raw_fd().to_string())
+            .status();
+        match status {
+            Ok(status) if status.success() => {
+                drop(theirs);
+                let device = receive_fd(ours.as_raw_fd())?;
+                return Ok(Channel { device });
+            }
+            Ok(status) => last_error = io::Error::other(format!("{} exited with {}", program, status)),
+            Err(error) => last_error = error,
+        }
+    }
+    Err(last_error)
+}
+
+fn receive_fd(socket: RawFd) -> io::Result<File> {
+    let mut data = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    message.msg_controllen = space as _;
+    if unsafe { libc::recvmsg(socket, &mut message, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let header = unsafe { libc::CMSG_FIRSTHDR(&message) };
+    if header.is_null() || unsafe { (*header).cmsg_type } != libc::SCM_RIGHTS {
+        return Err(io::Error::other("fusermount did not send the fuse device"));
+    }
+    let fd = unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(header) as *const RawFd) };
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+// The kernel only takes the options of the fuse device in the data, the name of the file system
+// is the source and its subtype is in the type
+fn mount_as_root(mountpoint: &Path, fs_name: &str) -> io::Result<Channel> {
+    let device = std::fs::OpenOptions::new().read(true).write(true).open("/dev/fuse")?;
+    let source = CString::new(fs_name)?;
+    let target = CString::new(mountpoint.as_os_str().as_bytes())?;
+    let fs_type = CString::new("fuse.metabox")?;
+    let data = CString::new(format!(
+        "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+        device.as_raw_fd(),
+        unsafe { libc::getuid() },
+        unsafe { libc::getgid() },
+    ))?;
+    let flags = libc::MS_NOSUID | libc::MS_NODEV;
+    if unsafe { libc::mount(source.as_ptr(), target.as_ptr(), fs_type.as_ptr(), flags, data.as_ptr() as *const libc::c_void) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Channel { device })
+}
@@ -0,0 +1,551 @@
+//! Mount a DataBox as a filesystem (Linux only)
+//!
+//! The box shows up as a flat directory of its complete plain files, named `file_name.ext` where
+//! the extension comes from the stored mime type. Reads fetch only the chunks covering the
+//! requested range through `getPlain` and keep them in a small cache. Files are immutable once
+//! uploaded : new files are buffered in memory and uploaded through the chunked `put` flow when
+//! they are closed, and unlink deletes the file with `deletekey`.
+//!
+//! The kernel protocol is spoken directly on `/dev/fuse`, mounting goes through `fusermount3`
+//! (or `fusermount`), or mount(2) when running as root.
+mod kernel;
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::runtime::Handle;
+use crate::databox::{self, backend, AvlSMResult, DataBoxBackend, DataErr, DeleteKeyResult, FileExt, GetPlainResult, UploadStatus, UPDATE_SIZE};
+use crate::mime;
+use crate::transfer::Transfer;
+use kernel::{Attr, Opcode, Reply, Request, FUSE_ROOT_ID};
+
+const FATTR_SIZE: u32 = 1 << 3;
+
+/// Mount options
+#[derive(Clone, Debug)]
+pub struct MountOptions {
+    /// How long a listing of the box is reused before `getAssetexts` is called again
+    pub listing_ttl: Duration,
+    /// How many chunks (of up to ~1.9 MiB each) the read cache keeps
+    pub cache_chunks: usize,
+    /// Refuse creating and deleting files
+    pub read_only: bool,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        MountOptions {
+            listing_ttl: Duration::from_secs(10),
+            cache_chunks: 32,
+            read_only: false,
+        }
+    }
+}
+
+/// Mount the DataBox of `backend` on `mountpoint`, blocking until it is unmounted
+///
+/// Any [`DataBoxBackend`] can be mounted : a `DataBoxClient`, or a `LocalDirBackend` for tests.
+/// Canister calls are run on `runtime`, so this must not be called from an async context :
+/// use `tokio::task::spawn_blocking` or a dedicated thread.
+///
+/// Example code :
+/// ``` no_run
+/// use candid::Principal;
+/// use metabox_sdk::agent::{self, Network};
+/// use metabox_sdk::databox::DataBoxClient;
+/// use metabox_sdk::fuse::{self, MountOptions};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let agent = agent::build_agent_for("identities/identity.pem", &Network::Ic).await.unwrap();
+///     let client = DataBoxClient::new(agent, Principal::from_text("4radi-oqaaa-aaaan-qapwa-cai").unwrap());
+///     let runtime = tokio::runtime::Handle::current();
+///     tokio::task::spawn_blocking(move || fuse::mount(client, "/mnt/databox", runtime, MountOptions::default()))
+///         .await
+///         .unwrap()
+///         .expect("mount failed");
+/// }
+/// ```
+pub fn mount<B: DataBoxBackend>(backend: B, mountpoint: impl AsRef<Path>, runtime: Handle, options: MountOptions) -> io::Result<()> {
+    let fs_name = format!("databox:{}", backend.canister_id().to_text());
+    let mut channel = kernel::mount(mountpoint.as_ref(), &fs_name)?;
+    let mut fs = DataBoxFs::new(backend, runtime, options);
+    let mut buffer = vec![0u8; kernel::BUFFER_SIZE];
+    loop {
+        let size = match channel.receive(&mut buffer) {
+            Ok(size) => size,
+            // The filesystem was unmounted
+            Err(error) if error.raw_os_error() == Some(libc::ENODEV) => return Ok(()),
+            Err(error) => return Err(error),
+        };
+        let request = match Request::parse(&buffer[..size]) {
+            Some(request) => request,
+            None => continue,
+        };
+        if request.opcode == Opcode::Destroy {
+            return channel.reply_ok(request.unique, Reply::default());
+        }
+        match fs.handle(&request) {
+            Some(Ok(reply)) => channel.reply_ok(request.unique, reply)?,
+            Some(Err(errno)) => channel.reply_error(request.unique, errno)?,
+            None => {}
+        }
+    }
+}
+
+/// Unmount a mounted DataBox, which makes [`mount`] return
+pub fn unmount(mountpoint: impl AsRef<Path>) -> io::Result<()> {
+    kernel::unmount(mountpoint.as_ref())
+}
+
+enum Node {
+    Remote {
+        file_key: String,
+        size: u64,
+    },
+    /// Created through the mount and not uploaded yet
+    Pending {
+        name: OsString,
+        data: Vec<u8>,
+        dirty: bool,
+    },
+}
+
+struct ChunkCache {
+    capacity: usize,
+    order: VecDeque<(String, u64)>,
+    chunks: HashMap<(String, u64), Arc<Vec<u8>>>,
+}
+
+impl ChunkCache {
+    fn get(&mut self, file_key: &str, index: u64) -> Option<Arc<Vec<u8>>> {
+        let key = (file_key.to_string(), index);
+        let chunk = self.chunks.get(&key)?.clone();
+        self.order.retain(|cached| cached != &key);
+        self.order.push_back(key);
+        Some(chunk)
+    }
+
+    fn insert(&mut self, file_key: &str, index: u64, chunk: Arc<Vec<u8>>) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.chunks.remove(&oldest);
+            }
+        }
+        let key = (file_key.to_string(), index);
+        self.order.push_back(key.clone());
+        self.chunks.insert(key, chunk);
+    }
+
+    fn remove_file(&mut self, file_key: &str) {
+        self.order.retain(|(key, _)| key != file_key);
+        self.chunks.retain(|(key, _), _| key != file_key);
+    }
+}
+
+struct DataBoxFs<B> {
+    backend: B,
+    runtime: Handle,
+    options: MountOptions,
+    uid: u32,
+    gid: u32,
+    mounted_at: u64,
+    nodes: HashMap<u64, Node>,
+    inodes: HashMap<String, u64>,
+    names: BTreeMap<OsString, u64>,
+    listed_at: Option<Instant>,
+    next_ino: u64,
+    dirs: HashMap<u64, Vec<(u64, u32, OsString)>>,
+    next_fh: u64,
+    chunks: ChunkCache,
+}
+
+impl<B: DataBoxBackend> DataBoxFs<B> {
+    fn new(backend: B, runtime: Handle, options: MountOptions) -> Self {
+        DataBoxFs {
+            backend,
+            runtime,
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            mounted_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0),
+            nodes: HashMap::new(),
+            inodes: HashMap::new(),
+            names: BTreeMap::new(),
+            listed_at: None,
+            next_ino: FUSE_ROOT_ID + 1,
+            dirs: HashMap::new(),
+            next_fh: 1,
+            chunks: ChunkCache {
+                capacity: options.cache_chunks,
+                order: VecDeque::new(),
+                chunks: HashMap::new(),
+            },
+            options,
+        }
+    }
+
+    /// `None` for the requests the kernel expects no answer to
+    fn handle(&mut self, request: &Request) -> Option<Result<Reply, i32>> {
+        let reply = match request.opcode {
+            Opcode::Init => self.init(request),
+            Opcode::Lookup => self.lookup(request.nodeid, request.name_arg(0)),
+            Opcode::Getattr => self.attr(request.nodeid).map(|attr| Reply::default().attr_out(&attr, 1)),
+            Opcode::Setattr => self.setattr(request),
+            Opcode::Open => self.open(request.nodeid, request.u32_arg(0)),
+            Opcode::Read => self.read(request.nodeid, request.u64_arg(8), request.u32_arg(16) as usize),
+            Opcode::Write => self.write(request),
+            Opcode::Flush => self.upload(request.nodeid).map(|_| Reply::default()),
+            Opcode::Release => self.release(request.nodeid),
+            Opcode::Opendir => self.opendir(request.nodeid),
+            Opcode::Readdir => self.readdir(request.u64_arg(0), request.u64_arg(8), request.u32_arg(16) as usize),
+            Opcode::Releasedir => {
+                self.dirs.remove(&request.u64_arg(0));
+                Ok(Reply::default())
+            }
+            Opcode::Create => self.create(request.nodeid, request.name_arg(16)),
+            Opcode::Unlink => self.unlink(request.nodeid, request.name_arg(0)),
+            Opcode::Statfs => self.statfs(),
+            Opcode::Access => Ok(Reply::default()),
+            Opcode::Forget | Opcode::BatchForget | Opcode::Interrupt => return None,
+            _ => Err(libc::ENOSYS),
+        };
+        Some(reply)
+    }
+
+    fn init(&mut self, request: &Request) -> Result<Reply, i32> {
+        if request.u32_arg(0) != kernel::FUSE_KERNEL_VERSION {
+            return Err(libc::EPROTO);
+        }
+        Ok(Reply::default().init(request.u32_arg(8)))
+    }
+
+    fn lookup(&mut self, parent: u64, name: &OsStr) -> Result<Reply, i32> {
+        if parent != FUSE_ROOT_ID {
+            return Err(libc::ENOENT);
+        }
+        self.refresh()?;
+        let ino = *self.names.get(name).ok_or(libc::ENOENT)?;
+        let attr = self.attr(ino)?;
+        Ok(Reply::default().entry(&attr, 1))
+    }
+
+    fn attr(&self, ino: u64) -> Result<Attr, i32> {
+        let (size, mode, nlink) = if ino == FUSE_ROOT_ID {
+            let mode = if self.options.read_only { 0o555 } else { 0o755 };
+            (0, kernel::S_IFDIR | mode, 2)
+        } else {
+            match self.nodes.get(&ino).ok_or(libc::ENOENT)? {
+                Node::Remote { size, .. } => (*size, kernel::S_IFREG | 0o444, 1),
+                Node::Pending { data, .. } => (data.len() as u64, kernel::S_IFREG | 0o644, 1),
+            }
+        };
+        Ok(Attr {
+            ino,
+            size,
+            mode,
+            nlink,
+            uid: self.uid,
+            gid: self.gid,
+            time: self.mounted_at,
+        })
+    }
+
+    fn setattr(&mut self, request: &Request) -> Result<Reply, i32> {
+        // fuse_setattr_in : valid, padding, fh, size, ...
+        if request.u32_arg(0) & FATTR_SIZE != 0 {
+            let new_size = request.u64_arg(16);
+            match self.nodes.get_mut(&request.nodeid).ok_or(libc::ENOENT)? {
+                Node::Pending { data, dirty, .. } => {
+                    data.resize(new_size as usize, 0);
+                    *dirty = true;
+                }
+                Node::Remote { size, .. } if *size != new_size => return Err(libc::EROFS),
+                Node::Remote { .. } => {}
+            }
+        }
+        // Modes and times are not stored, accept them so that `touch` and `cp -p` work
+        let attr = self.attr(request.nodeid)?;
+        Ok(Reply::default().attr_out(&attr, 1))
+    }
+
+    fn open(&mut self, ino: u64, flags: u32) -> Result<Reply, i32> {
+        let writing = flags as i32 & libc::O_ACCMODE != libc::O_RDONLY;
+        match self.nodes.get(&ino).ok_or(libc::ENOENT)? {
+            Node::Remote { .. } if writing => Err(libc::EROFS),
+            _ => Ok(Reply::default().open(ino)),
+        }
+    }
+
+    fn read(&mut self, ino: u64, offset: u64, size: usize) -> Result<Reply, i32> {
+        let (file_key, file_size) = match self.nodes.get(&ino).ok_or(libc::ENOENT)? {
+            Node::Remote { file_key, size } => (file_key.clone(), *size),
+            Node::Pending { data, .. } => {
+                let start = (offset as usize).min(data.len());
+                let end = (start + size).min(data.len());
+                return Ok(Reply::default().bytes(&data[start..end]));
+            }
+        };
+        let end = (offset + size as u64).min(file_size);
+        let mut reply = Reply::default();
+        if offset >= end {
+            return Ok(reply);
+        }
+        let chunk_size = UPDATE_SIZE as u64;
+        for index in offset / chunk_size..=(end - 1) / chunk_size {
+            let chunk = self.chunk(&file_key, index)?;
+            let chunk_start = index * chunk_size;
+            let from = (offset.max(chunk_start) - chunk_start) as usize;
+            let to = ((end - chunk_start) as usize).min(chunk.len());
+            if from >= to {
+                break;
+            }
+            reply = reply.bytes(&chunk[from..to]);
+        }
+        Ok(reply)
+    }
+
+    fn chunk(&mut self, file_key: &str, index: u64) -> Result<Arc<Vec<u8>>, i32> {
+        if let Some(chunk) = self.chunks.get(file_key, index) {
+            return Ok(chunk);
+        }
+        match self.runtime.block_on(self.backend.get_chunk(file_key, index, &Transfer::default())) {
+            Ok(GetPlainResult::ok(data)) => {
+                let chunk = Arc::new(data);
+                self.chunks.insert(file_key, index, chunk.clone());
+                Ok(chunk)
+            }
+            Ok(GetPlainResult::err(data_err)) => Err(data_errno(&data_err)),
+            Err(_) => Err(libc::EIO),
+        }
+    }
+
+    fn write(&mut self, request: &Request) -> Result<Reply, i32> {
+        // fuse_write_in : fh, offset, size, write_flags, lock_owner, flags, padding, then the data
+        let offset = request.u64_arg(8) as usize;
+        let size = request.u32_arg(16) as usize;
+        let payload = request.data_arg(40, size);
+        match self.nodes.get_mut(&request.nodeid).ok_or(libc::ENOENT)? {
+            Node::Pending { data, dirty, .. } => {
+                if data.len() < offset + payload.len() {
+                    data.resize(offset + payload.len(), 0);
+                }
+                data[offset..offset + payload.len()].copy_from_slice(payload);
+                *dirty = true;
+                Ok(Reply::default().u32(payload.len() as u32).u32(0))
+            }
+            Node::Remote { .. } => Err(libc::EROFS),
+        }
+    }
+
+    /// Upload a written file, it becomes a regular (immutable) file of the box
+    fn upload(&mut self, ino: u64) -> Result<(), i32> {
+        let (name, data) = match self.nodes.get(&ino) {
+            Some(Node::Pending { name, data, dirty: true }) if !data.is_empty() => (name.clone(), data.clone()),
+            _ => return Ok(()),
+        };
+        let name = name.to_string_lossy();
+        let file_extension = mime::detect(&name, &data);
+        let file_name = databox::stored_name(&name, &file_extension);
+        let size = data.len() as u64;
+        let result = self
+            .runtime
+            .block_on(backend::put_plain_data(&self.backend, file_name, file_extension, data, &Transfer::default()))
+            .map_err(|_| libc::EIO)?;
+        match result.upload_status {
+            UploadStatus::Ok | UploadStatus::AlreadyExists | UploadStatus::Err(DataErr::FileRepeat) => {
+                self.inodes.entry(result.file_key.clone()).or_insert(ino);
+                self.nodes.insert(ino, Node::Remote { file_key: result.file_key, size });
+                self.listed_at = None;
+                Ok(())
+            }
+            UploadStatus::Err(data_err) => Err(data_errno(&data_err)),
+            UploadStatus::Cancelled { .. } => Err(libc::EINTR),
+        }
+    }
+
+    fn release(&mut self, ino: u64) -> Result<Reply, i32> {
+        // Errors were already reported by flush, a failed upload is lost on release
+        if self.upload(ino).is_err() {
+            if let Some(Node::Pending { name, .. }) = self.nodes.remove(&ino) {
+                self.names.remove(&name);
+            }
+        }
+        Ok(Reply::default())
+    }
+
+    fn opendir(&mut self, ino: u64) -> Result<Reply, i32> {
+        if ino != FUSE_ROOT_ID {
+            return Err(libc::ENOTDIR);
+        }
+        self.refresh()?;
+        let mut entries = vec![
+            (FUSE_ROOT_ID, kernel::DT_DIR, OsString::from(".")),
+            (FUSE_ROOT_ID, kernel::DT_DIR, OsString::from("..")),
+        ];
+        entries.extend(self.names.iter().map(|(name, ino)| (*ino, kernel::DT_REG, name.clone())));
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.dirs.insert(fh, entries);
+        Ok(Reply::default().open(fh))
+    }
+
+    fn readdir(&mut self, fh: u64, offset: u64, size: usize) -> Result<Reply, i32> {
+        let entries = self.dirs.get(&fh).ok_or(libc::EBADF)?;
+        let mut reply = Reply::default();
+        for (index, (ino, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
+            reply = match reply.dirent(*ino, index as u64 + 1, *kind, name.as_bytes(), size) {
+                Ok(reply) => reply,
+                Err(full) => return Ok(full),
+            };
+        }
+        Ok(reply)
+    }
+
+    fn create(&mut self, parent: u64, name: &OsStr) -> Result<Reply, i32> {
+        if parent != FUSE_ROOT_ID {
+            return Err(libc::ENOENT);
+        }
+        if self.options.read_only {
+            return Err(libc::EROFS);
+        }
+        self.refresh()?;
+        if self.names.contains_key(name) {
+            return Err(libc::EEXIST);
+        }
+        let ino = self.allocate_ino();
+        self.nodes.insert(ino, Node::Pending {
+            name: name.to_os_string(),
+            data: Vec::new(),
+            dirty: false,
+        });
+        self.names.insert(name.to_os_string(), ino);
+        let attr = self.attr(ino)?;
+        Ok(Reply::default().entry(&attr, 1).open(ino))
+    }
+
+    fn unlink(&mut self, parent: u64, name: &OsStr) -> Result<Reply, i32> {
+        if parent != FUSE_ROOT_ID {
+            return Err(libc::ENOENT);
+        }
+        if self.options.read_only {
+            return Err(libc::EROFS);
+        }
+        self.refresh()?;
+        let ino = *self.names.get(name).ok_or(libc::ENOENT)?;
+        if let Some(Node::Remote { file_key, .. }) = self.nodes.get(&ino) {
+            let file_key = file_key.clone();
+            match self.runtime.block_on(self.backend.delete(&file_key)) {
+                Ok(DeleteKeyResult::ok(_)) => {}
+                Ok(DeleteKeyResult::err(data_err)) => return Err(data_errno(&data_err)),
+                Err(_) => return Err(libc::EIO),
+            }
+            self.chunks.remove_file(&file_key);
+            self.inodes.remove(&file_key);
+            self.listed_at = None;
+        }
+        self.names.remove(name);
+        self.nodes.remove(&ino);
+        Ok(Reply::default())
+    }
+
+    fn statfs(&mut self) -> Result<Reply, i32> {
+        let used: u64 = self.nodes.values().map(|node| match node {
+            Node::Remote { size, .. } => *size,
+            Node::Pending { data, .. } => data.len() as u64,
+        }).sum();
+        let available = match self.runtime.block_on(self.backend.available_memory()) {
+            Ok(AvlSMResult::ok(available)) => available,
+            _ => 0,
+        };
+        Ok(Reply::default().statfs((used + available) / 4096, available / 4096, self.nodes.len() as u64))
+    }
+
+    /// Reload the listing of the box once it is older than the listing ttl
+    fn refresh(&mut self) -> Result<(), i32> {
+        if let Some(listed_at) = self.listed_at {
+            if listed_at.elapsed() < self.options.listing_ttl {
+                return Ok(());
+            }
+        }
+        let files = match self.runtime.block_on(self.backend.list()) {
+            Ok(Ok(files)) => files,
+            // Keep serving the last listing when the box can not be reached
+            _ if self.listed_at.is_some() => return Ok(()),
+            Ok(Err(data_err)) => return Err(data_errno(&data_err)),
+            Err(_) => return Err(libc::EIO),
+        };
+
+        let mut names: BTreeMap<OsString, u64> = BTreeMap::new();
+        let mut remotes: HashMap<u64, Node> = HashMap::new();
+        for file in files.into_iter().filter(|file| !databox::is_internal(file)) {
+            let asset_ext = match file {
+                FileExt::PlainFileExt(asset_ext) if asset_ext.upload_status => asset_ext,
+                _ => continue,
+            };
+            let ino = match self.inodes.get(&asset_ext.file_key) {
+                Some(ino) => *ino,
+                None => {
+                    let ino = self.allocate_ino();
+                    self.inodes.insert(asset_ext.file_key.clone(), ino);
+                    ino
+                }
+            };
+            let mut name = display_name(&asset_ext.file_name, &asset_ext.file_extension);
+            if names.contains_key(&name) {
+                name.push(format!("~{}", &asset_ext.file_key[..asset_ext.file_key.len().min(8)]));
+            }
+            names.insert(name, ino);
+            remotes.insert(ino, Node::Remote {
+                file_key: asset_ext.file_key,
+                size: asset_ext.total_size,
+            });
+        }
+
+        self.nodes.retain(|_, node| matches!(node, Node::Pending { .. }));
+        for (ino, node) in &self.nodes {
+            if let Node::Pending { name, .. } = node {
+                names.entry(name.clone()).or_insert(*ino);
+            }
+        }
+        self.inodes.retain(|_, ino| remotes.contains_key(ino));
+        self.nodes.extend(remotes);
+        self.names = names;
+        self.listed_at = Some(Instant::now());
+        Ok(())
+    }
+
+    fn allocate_ino(&mut self) -> u64 {
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        ino
+    }
+}
+
+fn display_name(file_name: &str, file_extension: &str) -> OsString {
+    let mut name: String = file_name.chars().map(|c| if c == '/' || c == '\0' { '_' } else { c }).collect();
+    if name.is_empty() {
+        name.push('_');
+    }
+    if let Some(extension) = mime::to_extension(file_extension) {
+        name.push('.');
+        name.push_str(&extension);
+    }
+    OsString::from(name)
+}
+
+fn data_errno(data_err: &DataErr) -> i32 {
+    match data_err {
+        DataErr::FileKeyErr => libc::ENOENT,
+        DataErr::PermissionDenied | DataErr::UserAccessErr => libc::EACCES,
+        DataErr::MemoryInsufficient => libc::ENOSPC,
+        DataErr::FileRepeat => libc::EEXIST,
+        _ => libc::EIO,
+    }
+}
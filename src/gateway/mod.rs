@@ -0,0 +1,446 @@
+//! HTTP gateway serving DataBox files
+//!
+//! `GET /<canister>/<file_key>` answers the complete plain file `file_key` of one of the boxes
+//! the gateway serves, so browsers and media players can stream box content through a local
+//! server. A `Range` is mapped onto the chunks of `getPlain` (chunk `i` holds the bytes from
+//! `i * UPDATE_SIZE`) : only the chunks covering it are fetched, one at a time as the body is
+//! sent, and they are kept in a small cache.
+//!
+//! File keys are digests of the content, so the key is the `ETag` and answers do not change. They
+//! are only cached by the client by default, a gateway of private boxes must not fill shared
+//! caches : [`GatewayOptions::cache_control`] sets it otherwise. `HEAD` is answered too, without
+//! a body.
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::future::Future;
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use candid::Principal;
+use hyper::body::{Body, Bytes};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Method, Request, Response, Server, StatusCode};
+use crate::databox::{AssetExt, DataBoxBackend, DataErr, FileExt, GetPlainResult, UPDATE_SIZE};
+use crate::mime;
+use crate::transfer::Transfer;
+
+const CACHE_CONTROL: &str = "private, max-age=31536000, immutable";
+const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Gateway options
+#[derive(Clone, Debug)]
+pub struct GatewayOptions {
+    /// How many chunks (of up to ~1.9 MiB each) the cache keeps
+    pub cache_chunks: usize,
+    /// How many file infos the cache keeps
+    pub cache_files: usize,
+    /// `Cache-Control` of the files, `private, max-age=31536000, immutable` by default : `public`
+    /// lets shared caches keep them too
+    pub cache_control: HeaderValue,
+}
+
+impl Default for GatewayOptions {
+    fn default() -> Self {
+        GatewayOptions {
+            cache_chunks: 32,
+            cache_files: 1024,
+            cache_control: HeaderValue::from_static(CACHE_CONTROL),
+        }
+    }
+}
+
+/// An HTTP gateway of DataBoxes
+///
+/// [`serve`](HttpGateway::serve) runs it as a server, [`handle`](HttpGateway::handle) answers one
+/// request, to embed it in another hyper service.
+///
+/// Example code :
+/// ```
+/// use candid::Principal;
+/// use hyper::{Body, Request, StatusCode};
+/// use metabox_sdk::databox::DataBoxClient;
+/// use metabox_sdk::databox::mock::MockDataBox;
+/// use metabox_sdk::gateway::{GatewayOptions, HttpGateway};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let owner = Principal::anonymous();
+///     let data_box = MockDataBox::new(Principal::from_slice(&[1; 10]), owner);
+///     let client = DataBoxClient::mock(&data_box, owner);
+///     let data: Vec<u8> = (0..5_000_000).map(|i| i as u8).collect();
+///     let put = client.put_bytes(data.clone(), "movie.mp4", "video/mp4").await.unwrap();
+///
+///     let gateway = HttpGateway::new(GatewayOptions::default()).with_box(client);
+///     let uri = format!("/{}/{}", data_box.canister_id(), put.file_key);
+///     let request = Request::get(&uri).header("Range", "bytes=1992000-1993000").body(Body::empty()).unwrap();
+///     let response = gateway.handle(&request).await;
+///     assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+///     assert_eq!(response.headers()["Content-Type"], "video/mp4");
+///     assert_eq!(response.headers()["Content-Range"], "bytes 1992000-1993000/5000000");
+///     assert_eq!(response.headers()["ETag"], format!("\"{}\"", put.file_key).as_str());
+///     let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+///     assert_eq!(&body[..], &data[1992000..=1993000]);
+/// }
+/// ```
+#[derive(Clone)]
+pub struct HttpGateway {
+    boxes: HashMap<Principal, Arc<dyn DataBoxBackend>>,
+    cache: Arc<GatewayCache>,
+    cache_control: HeaderValue,
+}
+
+impl HttpGateway {
+    /// A gateway of no box
+    pub fn new(options: GatewayOptions) -> Self {
+        HttpGateway {
+            boxes: HashMap::new(),
+            cache: Arc::new(GatewayCache {
+                files: Mutex::new(Lru::new(options.cache_files)),
+                chunks: Mutex::new(Lru::new(options.cache_chunks)),
+            }),
+            cache_control: options.cache_control,
+        }
+    }
+
+    /// Serve the files of the box of `backend`, at `/<canister>/`
+    pub fn with_box<B: DataBoxBackend + 'static>(mut self, backend: B) -> Self {
+        self.boxes.insert(backend.canister_id(), Arc::new(backend));
+        self
+    }
+
+    /// The boxes served
+    pub fn boxes(&self) -> Vec<Principal> {
+        self.boxes.keys().copied().collect()
+    }
+
+    /// Serve HTTP on `address` until `shutdown` completes
+    ///
+    /// Example code :
+    /// ``` no_run
+    /// use candid::Principal;
+    /// use metabox_sdk::agent::{self, Network};
+    /// use metabox_sdk::databox::DataBoxClient;
+    /// use metabox_sdk::gateway::{GatewayOptions, HttpGateway};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let agent = agent::build_agent_for("identities/identity.pem", &Network::Ic).await.unwrap();
+    ///     let client = DataBoxClient::new(agent, Principal::from_text("4radi-oqaaa-aaaan-qapwa-cai").unwrap());
+    ///     let gateway = HttpGateway::new(GatewayOptions::default()).with_box(client);
+    ///     gateway.serve(([127, 0, 0, 1], 8080).into(), std::future::pending()).await.unwrap();
+    /// }
+    /// ```
+    pub async fn serve(self, address: SocketAddr, shutdown: impl Future<Output = ()>) -> Result<(), hyper::Error> {
+        let make_service = make_service_fn(move |_| {
+            let gateway = self.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let gateway = gateway.clone();
+                    async move { Ok::<_, Infallible>(gateway.handle(&request).await) }
+                }))
+            }
+        });
+        Server::try_bind(&address)?.serve(make_service).with_graceful_shutdown(shutdown).await
+    }
+
+    /// The response to `request`, its body is streamed from the box by a spawned task
+    ///
+    /// Example code :
+    /// ```
+    /// use candid::Principal;
+    /// use hyper::header::HeaderValue;
+    /// use hyper::{Body, Request, StatusCode};
+    /// use metabox_sdk::databox::DataBoxClient;
+    /// use metabox_sdk::databox::mock::MockDataBox;
+    /// use metabox_sdk::gateway::{GatewayOptions, HttpGateway};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let owner = Principal::anonymous();
+    ///     let data_box = MockDataBox::new(Principal::from_slice(&[1; 10]), owner);
+    ///     let client = DataBoxClient::mock(&data_box, owner);
+    ///     let put = client.put_bytes(b"hello".to_vec(), "hello", "text/plain\r\nX-Injected: 1").await.unwrap();
+    ///     let uri = format!("/{}/{}", data_box.canister_id(), put.file_key);
+    ///
+    ///     let gateway = HttpGateway::new(GatewayOptions::default()).with_box(client.clone());
+    ///     let response = gateway.handle(&Request::get(&uri).body(Body::empty()).unwrap()).await;
+    ///     assert_eq!(response.status(), StatusCode::OK);
+    ///     assert_eq!(response.headers()["Content-Type"], "application/octet-stream");
+    ///     assert_eq!(response.headers()["Cache-Control"], "private, max-age=31536000, immutable");
+    ///
+    ///     let options = GatewayOptions { cache_control: HeaderValue::from_static("public, max-age=3600"), ..Default::default() };
+    ///     let gateway = HttpGateway::new(options).with_box(client);
+    ///     let response = gateway.handle(&Request::head(&uri).body(Body::empty()).unwrap()).await;
+    ///     assert_eq!(response.headers()["Cache-Control"], "public, max-age=3600");
+    /// }
+    /// ```
+    pub async fn handle<B>(&self, request: &Request<B>) -> Response<Body> {
+        let head = request.method() == Method::HEAD;
+        if request.method() != Method::GET && !head {
+            let mut response = empty(StatusCode::METHOD_NOT_ALLOWED);
+            response.headers_mut().insert(header::ALLOW, HeaderValue::from_static("GET, HEAD"));
+            return response;
+        }
+        let (canister_id, file_key) = match parse_path(request.uri().path()) {
+            Some(target) => target,
+            None => return empty(StatusCode::NOT_FOUND),
+        };
+        let backend = match self.boxes.get(&canister_id) {
+            Some(backend) => backend.clone(),
+            None => return empty(StatusCode::NOT_FOUND),
+        };
+        let asset_ext = match self.cache.file(backend.as_ref(), &file_key).await {
+            Ok(asset_ext) => asset_ext,
+            Err(status) => return empty(status),
+        };
+
+        let etag = format!("\"{}\"", file_key);
+        // Keys are decoded from the path, one which is not a valid header value is no box file
+        let etag_value = match HeaderValue::from_str(&etag) {
+            Ok(etag_value) => etag_value,
+            Err(_) => return empty(StatusCode::NOT_FOUND),
+        };
+        let size = asset_ext.total_size;
+        let mut response = Response::builder()
+            .header(header::ETAG, etag_value)
+            .header(header::CACHE_CONTROL, self.cache_control.clone())
+            .header(header::ACCEPT_RANGES, "bytes");
+        if matches_etag(request.headers().get(header::IF_NONE_MATCH), &etag) {
+            return response.status(StatusCode::NOT_MODIFIED).body(Body::empty()).unwrap();
+        }
+        let range = match request.headers().get(header::RANGE) {
+            Some(range) if if_range_holds(request.headers(), &etag) => parse_range(range, size),
+            _ => ByteRange::Full,
+        };
+        let (start, end) = match range {
+            ByteRange::Full => (0, size),
+            ByteRange::Part(start, end) => {
+                let content_range = format!("bytes {}-{}/{}", start, end - 1, size);
+                response = response.status(StatusCode::PARTIAL_CONTENT).header(header::CONTENT_RANGE, content_range);
+                (start, end)
+            }
+            ByteRange::Unsatisfiable => {
+                return response
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(header::CONTENT_RANGE, format!("bytes */{}", size))
+                    .body(Body::empty())
+                    .unwrap();
+            }
+        };
+        let response = response
+            .header(header::CONTENT_TYPE, content_type(&asset_ext.file_extension))
+            .header(header::CONTENT_LENGTH, end - start);
+        if head || start == end {
+            return response.body(Body::empty()).unwrap();
+        }
+
+        let (mut sender, body) = Body::channel();
+        let cache = self.cache.clone();
+        tokio::spawn(async move {
+            let chunk_size = UPDATE_SIZE as u64;
+            for index in start / chunk_size..=(end - 1) / chunk_size {
+                let chunk = match cache.chunk(backend.as_ref(), &file_key, index).await {
+                    Some(chunk) => chunk,
+                    None => return sender.abort(),
+                };
+                let chunk_start = index * chunk_size;
+                let from = (start.max(chunk_start) - chunk_start) as usize;
+                let to = ((end - chunk_start) as usize).min(chunk.len());
+                if from >= to {
+                    return sender.abort();
+                }
+                if sender.send_data(chunk.slice(from..to)).await.is_err() {
+                    // The client went away
+                    return;
+                }
+            }
+        });
+        response.body(body).unwrap()
+    }
+}
+
+struct GatewayCache {
+    files: Mutex<Lru<(Principal, String), AssetExt>>,
+    chunks: Mutex<Lru<(Principal, String, u64), Bytes>>,
+}
+
+impl GatewayCache {
+    // The complete plain file `file_key`, or the status answered instead
+    async fn file(&self, backend: &dyn DataBoxBackend, file_key: &str) -> Result<AssetExt, StatusCode> {
+        let key = (backend.canister_id(), file_key.to_string());
+        if let Some(asset_ext) = self.files.lock().unwrap().get(&key) {
+            return Ok(asset_ext);
+        }
+        match backend.info(file_key).await {
+            Ok(Ok(FileExt::PlainFileExt(asset_ext))) if asset_ext.upload_status => {
+                self.files.lock().unwrap().insert(key, asset_ext.clone());
+                Ok(asset_ext)
+            }
+            // Incomplete, encrypted or shared files are not served
+            Ok(Ok(_)) | Ok(Err(DataErr::FileKeyErr)) => Err(StatusCode::NOT_FOUND),
+            Ok(Err(DataErr::PermissionDenied)) | Ok(Err(DataErr::UserAccessErr)) => Err(StatusCode::FORBIDDEN),
+            Ok(Err(_)) | Err(_) => Err(StatusCode::BAD_GATEWAY),
+        }
+    }
+
+    async fn chunk(&self, backend: &dyn DataBoxBackend, file_key: &str, index: u64) -> Option<Bytes> {
+        let key = (backend.canister_id(), file_key.to_string(), index);
+        if let Some(chunk) = self.chunks.lock().unwrap().get(&key) {
+            return Some(chunk);
+        }
+        match backend.get_chunk(file_key, index, &Transfer::default()).await {
+            Ok(GetPlainResult::ok(data)) => {
+                let chunk = Bytes::from(data);
+                self.chunks.lock().unwrap().insert(key, chunk.clone());
+                Some(chunk)
+            }
+            _ => None,
+        }
+    }
+}
+
+// Least recently used entries are dropped first
+struct Lru<K, V> {
+    capacity: usize,
+    order: VecDeque<K>,
+    entries: HashMap<K, V>,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> Lru<K, V> {
+    fn new(capacity: usize) -> Self {
+        Lru {
+            capacity,
+            order: VecDeque::new(),
+            entries: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &K) -> Option<V> {
+        let value = self.entries.get(key)?.clone();
+        self.order.retain(|cached| cached != key);
+        self.order.push_back(key.clone());
+        Some(value)
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.insert(key.clone(), value).is_some() {
+            self.order.retain(|cached| cached != &key);
+        }
+        self.order.push_back(key);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    Full,
+    /// From `start` to `end` excluded
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+// A single range of `bytes`, other ranges are answered with the whole file
+fn parse_range(range: &HeaderValue, size: u64) -> ByteRange {
+    let spec = match range.to_str().ok().and_then(|range| range.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Full,
+    };
+    let (first, last) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Full,
+    };
+    match (first.parse::<u64>(), last.parse::<u64>()) {
+        // The last `suffix` bytes
+        (Err(_), Ok(suffix)) if first.is_empty() => {
+            if suffix == 0 || size == 0 {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Part(size.saturating_sub(suffix), size)
+            }
+        }
+        (Ok(start), Err(_)) if last.is_empty() => {
+            if start >= size {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Part(start, size)
+            }
+        }
+        (Ok(start), Ok(last)) if start <= last => {
+            if start >= size {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Part(start, last.saturating_add(1).min(size))
+            }
+        }
+        _ => ByteRange::Full,
+    }
+}
+
+fn matches_etag(condition: Option<&HeaderValue>, etag: &str) -> bool {
+    match condition.and_then(|condition| condition.to_str().ok()) {
+        Some(condition) => condition
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag),
+        None => false,
+    }
+}
+
+// A `Range` only applies when `If-Range` is absent or names the current file
+fn if_range_holds(headers: &HeaderMap, etag: &str) -> bool {
+    match headers.get(header::IF_RANGE) {
+        Some(if_range) => if_range.to_str().is_ok_and(|if_range| if_range.trim() == etag),
+        None => true,
+    }
+}
+
+// `/<canister>/<file_key>`, the key percent decoded
+fn parse_path(path: &str) -> Option<(Principal, String)> {
+    let (canister, file_key) = path.strip_prefix('/')?.split_once('/')?;
+    let canister_id = Principal::from_text(canister).ok()?;
+    let file_key = percent_decode(file_key)?;
+    if file_key.is_empty() || file_key.contains('/') {
+        return None;
+    }
+    Some((canister_id, file_key))
+}
+
+fn percent_decode(encoded: &str) -> Option<String> {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = encoded.get(index + 1..index + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// Boxes store the mime type as the extension, older files may have a bare extension. A stored
+// type which is not a valid header value is served as `application/octet-stream`
+fn content_type(file_extension: &str) -> HeaderValue {
+    let content_type = if file_extension.contains('/') {
+        file_extension.to_string()
+    } else {
+        mime::from_extension(file_extension)
+    };
+    HeaderValue::from_str(&content_type).unwrap_or_else(|_| HeaderValue::from_static(OCTET_STREAM))
+}
+
+fn empty(status: StatusCode) -> Response<Body> {
+    Response::builder().status(status).body(Body::empty()).unwrap()
+}
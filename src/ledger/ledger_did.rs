@@ -0,0 +1,9 @@
+use ic_cdk::export::candid::{CandidType, Deserialize};
+
+pub type AccountIdentifier = Vec<u8>;
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct Tokens { pub e8s: u64 }
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct AccountBalanceArgs { pub account: AccountIdentifier }
@@ -0,0 +1,263 @@
+use candid::{CandidType, Encode, Decode, Principal};
+use ic_agent::Agent;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha224};
+use std::fmt;
+use crate::agent::{build_agent, CallError, RetryPolicies, RetryPolicy, Transport};
+mod ledger_did;
+pub use ledger_did::{Tokens, AccountBalanceArgs};
+pub use crate::metabox::{BlockIndex, Token, TransferError, TransferOutICPResult};
+
+static LEDGER_CANISTER_ID_TEXT: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+static METABOX_CANISTER_ID_TEXT: &str = "zbzr7-xyaaa-aaaan-qadeq-cai";
+
+/// The fee charged by the ICP ledger for every transfer, in e8s
+pub const ICP_FEE: u64 = 10_000;
+
+const ACCOUNT_DOMAIN_SEPARATOR: &[u8] = b"\x0Aaccount-id";
+
+/// 32 bytes subaccount, the default subaccount is all zeros
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Subaccount(pub [u8; 32]);
+
+impl From<&Principal> for Subaccount {
+    /// Subaccount derived from a principal : length byte followed by the principal bytes
+    fn from(principal: &Principal) -> Self {
+        let bytes = principal.as_slice();
+        let mut subaccount = [0u8; 32];
+        subaccount[0] = bytes.len() as u8;
+        subaccount[1..1 + bytes.len()].copy_from_slice(bytes);
+        Subaccount(subaccount)
+    }
+}
+
+/// Ledger account identifier : crc32 checksum (4 bytes) followed by the sha224 hash (28 bytes)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AccountIdentifier([u8; 32]);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AccountIdentifierError {
+    InvalidHex,
+    InvalidLength(usize),
+    ChecksumMismatch,
+}
+
+impl AccountIdentifier {
+    pub fn new(principal: &Principal, subaccount: Option<Subaccount>) -> Self {
+        let mut hasher = Sha224::new();
+        hasher.update(ACCOUNT_DOMAIN_SEPARATOR);
+        hasher.update(principal.as_slice());
+        hasher.update(subaccount.unwrap_or_default().0);
+        let hash = hasher.finalize();
+
+        let mut bytes = [0u8; 32];
+        bytes[0..4].copy_from_slice(&crc32fast::hash(&hash).to_be_bytes());
+        bytes[4..32].copy_from_slice(&hash);
+        AccountIdentifier(bytes)
+    }
+
+    pub fn from_hex(hex_text: &str) -> Result<Self, AccountIdentifierError> {
+        let bytes = hex::decode(hex_text).map_err(|_| AccountIdentifierError::InvalidHex)?;
+        Self::try_from(bytes.as_slice())
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl TryFrom<&[u8]> for AccountIdentifier {
+    type Error = AccountIdentifierError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| AccountIdentifierError::InvalidLength(bytes.len()))?;
+        if crc32fast::hash(&bytes[4..]).to_be_bytes() != bytes[0..4] {
+            return Err(AccountIdentifierError::ChecksumMismatch);
+        }
+        Ok(AccountIdentifier(bytes))
+    }
+}
+
+impl fmt::Display for AccountIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+/// Get the account identifier of a principal
+///
+/// Example code :
+/// ```
+/// use candid::Principal;
+/// use metabox_sdk::ledger;
+///
+/// let account = ledger::account_identifier(&Principal::anonymous(), None);
+/// assert_eq!(account.to_hex(), "1c7a48ba6a562aa9eaa2481a9049cdf0433b9738c992d698c31d8abf89cadc79");
+/// ```
+pub fn account_identifier(principal: &Principal, subaccount: Option<Subaccount>) -> AccountIdentifier {
+    AccountIdentifier::new(principal, subaccount)
+}
+
+/// Get the default account identifier of the pem identity
+///
+/// Example code :
+/// ``` no_run
+/// use metabox_sdk::ledger;
+///
+/// println!("account identifier: {}", ledger::get_account_identifier("identities/identity.pem"));
+/// ```
+pub fn get_account_identifier(pem_identity_path: &str) -> AccountIdentifier {
+    build_client(pem_identity_path).account_identifier()
+}
+
+/// Get the ICP balance of an account
+///
+/// Example code :
+/// ``` no_run
+/// use metabox_sdk::ledger;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let account = ledger::get_account_identifier("identities/identity.pem");
+///     let balance = ledger::get_icp_balance("identities/identity.pem", &account).await;
+///     println!("icp balance (e8s): {:?}", balance.e8s);
+/// }
+/// ```
+pub async fn get_icp_balance(pem_identity_path: &str, account: &AccountIdentifier) -> Tokens {
+    build_client(pem_identity_path)
+        .get_icp_balance(account)
+        .await
+        .expect("response error")
+}
+
+/// Transfer ICP out of the user 's MetaBox account
+///
+/// `amount` is in e8s, the ledger fee ([`ICP_FEE`]) is charged on top of it.
+///
+/// Example code :
+/// ``` no_run
+/// use metabox_sdk::ledger::{self, AccountIdentifier, TransferError};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let to = AccountIdentifier::from_hex("1c7a48ba6a562aa9eaa2481a9049cdf0433b9738c992d698c31d8abf89cadc79").unwrap();
+///     match ledger::transfer_out_icp("identities/identity.pem", &to, 100_000_000).await {
+///         Ok(block_index) => println!("transfer in block: {:?}", block_index),
+///         Err(TransferError::InsufficientFunds { balance }) => println!("insufficient funds, balance: {:?}", balance.e8s),
+///         Err(TransferError::BadFee { expected_fee }) => println!("bad fee, expected: {:?}", expected_fee.e8s),
+///         Err(error) => println!("transfer error: {:?}", error),
+///     }
+/// }
+/// ```
+pub async fn transfer_out_icp(pem_identity_path: &str, to: &AccountIdentifier, amount: u64) -> Result<BlockIndex, TransferError> {
+    build_client(pem_identity_path)
+        .transfer_out_icp(to, amount)
+        .await
+        .expect("response error")
+}
+
+/// Ledger client bound to one agent : balances on the ICP ledger, transfers out of MetaBox
+///
+/// Calls go through the same retry policies as [`crate::metabox::MetaBoxClient`], and the agent
+/// decides the network.
+///
+/// Example code :
+/// ``` no_run
+/// use metabox_sdk::agent::{self, Network};
+/// use metabox_sdk::ledger::LedgerClient;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let agent = agent::build_agent_for("identities/identity.pem", &Network::Ic).await.unwrap();
+///     let client = LedgerClient::new(agent);
+///     let balance = client.get_icp_balance(&client.account_identifier()).await.unwrap();
+///     println!("icp balance (e8s): {:?}", balance.e8s);
+/// }
+/// ```
+#[derive(Clone)]
+pub struct LedgerClient {
+    transport: Transport,
+    ledger_canister_id: Principal,
+    metabox_canister_id: Principal,
+    retry: RetryPolicies,
+}
+
+impl LedgerClient {
+    pub fn new(agent: Agent) -> Self {
+        LedgerClient::with_canister_ids(
+            agent,
+            Principal::from_text(LEDGER_CANISTER_ID_TEXT).unwrap(),
+            Principal::from_text(METABOX_CANISTER_ID_TEXT).unwrap(),
+        )
+    }
+
+    /// Client of another ledger, or of a self-hosted MetaBox deployment
+    pub fn with_canister_ids(agent: Agent, ledger_canister_id: Principal, metabox_canister_id: Principal) -> Self {
+        LedgerClient {
+            transport: Transport::Agent(agent),
+            ledger_canister_id,
+            metabox_canister_id,
+            retry: RetryPolicies::default(),
+        }
+    }
+
+    /// Retry policy of every call, [`RetryPolicy::default`] unless set
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry.default = policy;
+        self
+    }
+
+    /// Retry policy of one canister method (`"account_balance"`, `"transferOutICP"`), over the client 's policy
+    pub fn with_method_retry_policy(mut self, method_name: &str, policy: RetryPolicy) -> Self {
+        self.retry.methods.insert(method_name.to_string(), policy);
+        self
+    }
+
+    /// Principal of the agent 's identity
+    pub fn caller(&self) -> Principal {
+        self.transport.caller()
+    }
+
+    /// Default account identifier of the agent 's identity
+    pub fn account_identifier(&self) -> AccountIdentifier {
+        AccountIdentifier::new(&self.caller(), None)
+    }
+
+    pub async fn get_icp_balance(&self, account: &AccountIdentifier) -> Result<Tokens, CallError> {
+        let args = AccountBalanceArgs {
+            account: account.as_bytes().to_vec(),
+        };
+        let response_blob = self
+            .transport
+            .query(&self.ledger_canister_id, "account_balance", Encode!(&args)?, self.retry.for_method("account_balance"), None)
+            .await?;
+        Ok(Decode!(&response_blob, Tokens)?)
+    }
+
+    /// Transfer ICP out of the caller 's MetaBox account, see [`transfer_out_icp`]
+    pub async fn transfer_out_icp(&self, to: &AccountIdentifier, amount: u64) -> Result<Result<BlockIndex, TransferError>, CallError> {
+        let result: TransferOutICPResult = self.update("transferOutICP", Encode!(&to.as_bytes().to_vec(), &amount)?).await?;
+        Ok(match result {
+            TransferOutICPResult::ok(block_index) => Ok(block_index),
+            TransferOutICPResult::err(transfer_error) => Err(transfer_error),
+        })
+    }
+
+    async fn update<R: CandidType + DeserializeOwned>(&self, method_name: &str, arg: Vec<u8>) -> Result<R, CallError> {
+        let response_blob = self
+            .transport
+            .update(&self.metabox_canister_id, method_name, arg, &self.retry.for_update(method_name), None)
+            .await?;
+        Ok(Decode!(&response_blob, R)?)
+    }
+}
+
+fn build_client(pem_identity_path: &str) -> LedgerClient {
+    LedgerClient::new(build_agent(pem_identity_path))
+}
@@ -9,6 +9,18 @@
 //! ![](http://scf3f-cyaaa-aaaal-aas3q-cai.raw.ic0.app/fk/1lSD8Nfc1WiBqd20_Yqz1)
 //!
 //!
+pub mod agent;
 pub mod metabox;
 pub mod databox;
+pub mod ledger;
+pub mod cycles;
+pub mod mime;
+pub mod pool;
+pub mod search;
+pub mod transfer;
 
+#[cfg(all(feature = "fuse", target_os = "linux"))]
+pub mod fuse;
+
+#[cfg(feature = "gateway")]
+pub mod gateway;
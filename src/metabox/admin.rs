@@ -0,0 +1,328 @@
+//! Operator API of a MetaBox deployment, only callable by its admins
+//!
+//! Every function takes the MetaBox canister id explicitly, so it works for self-hosted deployments.
+//! [`AdminClient`] makes the same calls with an agent of any network and the retry policies of the
+//! other clients.
+use candid::{CandidType, Decode, Encode, Nat, Principal};
+use ic_agent::Agent;
+use serde::de::DeserializeOwned;
+use sha256::digest_bytes;
+use std::fs;
+use std::path::Path;
+use super::metabox_did::{BoxType, InstallCycleWasmResult, UpdateWasmArgs, UpdateWasmResult};
+use crate::agent::{build_agent, CallError, RetryPolicies, RetryPolicy, Transport};
+use super::Error;
+
+#[derive(Debug)]
+pub struct LogEntry {
+    pub time: Nat,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub struct UpdateWasmReport {
+    pub box_type: BoxType,
+    /// Hex encoded sha256 of the uploaded wasm module
+    pub sha256: String,
+    pub wasm_size: usize,
+    pub result: Result<String, String>,
+}
+
+/// Add an admin
+///
+/// Returns `false` when the caller is not an admin.
+///
+/// Example code :
+/// ``` no_run
+/// use candid::Principal;
+/// use metabox_sdk::metabox::admin;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let new_admin = Principal::from_text("2vxsx-fae").unwrap();
+///     println!("add admin result: {:?}", admin::add_admin("identities/identity.pem", "zbzr7-xyaaa-aaaan-qadeq-cai", new_admin).await);
+/// }
+/// ```
+pub async fn add_admin(pem_identity_path: &str, metabox_canister_id_text: &str, new_admin: Principal) -> bool {
+    build_client(pem_identity_path, metabox_canister_id_text)
+        .add_admin(new_admin)
+        .await
+        .expect("response error")
+}
+
+/// Replace the admin list
+///
+/// Returns `false` when the caller is not an admin.
+///
+/// Example code :
+/// ``` no_run
+/// use candid::Principal;
+/// use metabox_sdk::metabox::admin;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let admins = vec![Principal::from_text("2vxsx-fae").unwrap()];
+///     println!("change admin result: {:?}", admin::change_admin("identities/identity.pem", "zbzr7-xyaaa-aaaan-qadeq-cai", admins).await);
+/// }
+/// ```
+pub async fn change_admin(pem_identity_path: &str, metabox_canister_id_text: &str, admins: Vec<Principal>) -> bool {
+    build_client(pem_identity_path, metabox_canister_id_text)
+        .change_admin(admins)
+        .await
+        .expect("response error")
+}
+
+/// Get the admins
+///
+/// Example code :
+/// ``` no_run
+/// use metabox_sdk::metabox::admin;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     for admin in admin::get_admins("identities/identity.pem", "zbzr7-xyaaa-aaaan-qadeq-cai").await {
+///         println!("admin: {}", admin.to_text());
+///     }
+/// }
+/// ```
+pub async fn get_admins(pem_identity_path: &str, metabox_canister_id_text: &str) -> Vec<Principal> {
+    build_client(pem_identity_path, metabox_canister_id_text)
+        .get_admins()
+        .await
+        .expect("response error")
+}
+
+/// Get the MetaBox log
+///
+/// Example code :
+/// ``` no_run
+/// use metabox_sdk::metabox::admin;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     for entry in admin::get_log("identities/identity.pem", "zbzr7-xyaaa-aaaan-qadeq-cai").await {
+///         println!("{}: {}", entry.time, entry.message);
+///     }
+/// }
+/// ```
+pub async fn get_log(pem_identity_path: &str, metabox_canister_id_text: &str) -> Vec<LogEntry> {
+    build_client(pem_identity_path, metabox_canister_id_text)
+        .get_log()
+        .await
+        .expect("response error")
+}
+
+/// Clear the MetaBox log
+///
+/// Example code :
+/// ``` no_run
+/// use metabox_sdk::metabox::admin;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     admin::clear_log("identities/identity.pem", "zbzr7-xyaaa-aaaan-qadeq-cai").await;
+/// }
+/// ```
+pub async fn clear_log(pem_identity_path: &str, metabox_canister_id_text: &str) {
+    build_client(pem_identity_path, metabox_canister_id_text)
+        .clear_log()
+        .await
+        .expect("response error")
+}
+
+/// Upload a new wasm module used to install boxes of `box_type`
+///
+/// Example code :
+/// ``` no_run
+/// use metabox_sdk::metabox::{admin, BoxType};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let wasm = std::fs::read("wasm/data_box.wasm").unwrap();
+///     println!("update wasm result: {:?}", admin::update_wasm("identities/identity.pem", "zbzr7-xyaaa-aaaan-qadeq-cai", BoxType::data_box, wasm).await);
+/// }
+/// ```
+pub async fn update_wasm(pem_identity_path: &str, metabox_canister_id_text: &str, box_type: BoxType, wasm: Vec<u8>) -> Result<String, String> {
+    build_client(pem_identity_path, metabox_canister_id_text)
+        .update_wasm(box_type, wasm)
+        .await
+        .expect("response error")
+}
+
+/// Upload a new box wasm module from a file, reporting its sha256
+///
+/// Example code :
+/// ``` no_run
+/// use metabox_sdk::metabox::{admin, BoxType};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let report = admin::update_wasm_from_file("identities/identity.pem", "zbzr7-xyaaa-aaaan-qadeq-cai", BoxType::data_box, "wasm/data_box.wasm").await;
+///     println!("wasm sha256: {}", report.sha256);
+///     println!("update wasm result: {:?}", report.result);
+/// }
+/// ```
+pub async fn update_wasm_from_file(pem_identity_path: &str, metabox_canister_id_text: &str, box_type: BoxType, wasm_file_path: &str) -> UpdateWasmReport {
+    let wasm = fs::read(Path::new(wasm_file_path)).expect("read file failed");
+    let sha256 = digest_bytes(&wasm);
+    let wasm_size = wasm.len();
+    let result = update_wasm(pem_identity_path, metabox_canister_id_text, box_type, wasm).await;
+    UpdateWasmReport {
+        box_type,
+        sha256,
+        wasm_size,
+        result,
+    }
+}
+
+/// Install the cycle wasm module
+///
+/// Example code :
+/// ``` no_run
+/// use metabox_sdk::metabox::admin;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let wasm = std::fs::read("wasm/cycle.wasm").unwrap();
+///     println!("install cycle wasm result: {:?}", admin::install_cycle_wasm("identities/identity.pem", "zbzr7-xyaaa-aaaan-qadeq-cai", wasm).await);
+/// }
+/// ```
+pub async fn install_cycle_wasm(pem_identity_path: &str, metabox_canister_id_text: &str, wasm: Vec<u8>) -> Result<(), Error> {
+    build_client(pem_identity_path, metabox_canister_id_text)
+        .install_cycle_wasm(wasm)
+        .await
+        .expect("response error")
+}
+
+/// Mint a box for a user, e.g. for a promotional activity
+///
+/// Returns the message of MetaBox.
+///
+/// Example code :
+/// ``` no_run
+/// use candid::Principal;
+/// use metabox_sdk::metabox::{admin, BoxType};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let to = Principal::from_text("2vxsx-fae").unwrap();
+///     println!("mint box result: {:?}", admin::mint_box("identities/identity.pem", "zbzr7-xyaaa-aaaan-qadeq-cai", to, BoxType::data_box, "airdrop".to_string()).await);
+/// }
+/// ```
+pub async fn mint_box(pem_identity_path: &str, metabox_canister_id_text: &str, to: Principal, box_type: BoxType, activity: String) -> String {
+    build_client(pem_identity_path, metabox_canister_id_text)
+        .mint_box(to, box_type, activity)
+        .await
+        .expect("response error")
+}
+
+/// Admin client of one MetaBox deployment, bound to one agent
+///
+/// Calls go through the same retry policies as [`super::MetaBoxClient`], and the agent decides
+/// the network.
+///
+/// Example code :
+/// ``` no_run
+/// use candid::Principal;
+/// use metabox_sdk::agent::{self, Network};
+/// use metabox_sdk::metabox::admin::AdminClient;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let agent = agent::build_agent_for("identities/identity.pem", &Network::Local).await.unwrap();
+///     let client = AdminClient::new(agent, Principal::from_text("zbzr7-xyaaa-aaaan-qadeq-cai").unwrap());
+///     for admin in client.get_admins().await.unwrap() {
+///         println!("admin: {}", admin.to_text());
+///     }
+/// }
+/// ```
+#[derive(Clone)]
+pub struct AdminClient {
+    transport: Transport,
+    canister_id: Principal,
+    retry: RetryPolicies,
+}
+
+impl AdminClient {
+    pub fn new(agent: Agent, metabox_canister_id: Principal) -> Self {
+        AdminClient {
+            transport: Transport::Agent(agent),
+            canister_id: metabox_canister_id,
+            retry: RetryPolicies::default(),
+        }
+    }
+
+    pub fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+
+    /// Retry policy of every call, [`RetryPolicy::default`] unless set
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry.default = policy;
+        self
+    }
+
+    /// Retry policy of one canister method (`"mintBox"`, `"getLog"`, ...), over the client 's policy
+    pub fn with_method_retry_policy(mut self, method_name: &str, policy: RetryPolicy) -> Self {
+        self.retry.methods.insert(method_name.to_string(), policy);
+        self
+    }
+
+    /// Add an admin, `false` when the caller is not an admin
+    pub async fn add_admin(&self, new_admin: Principal) -> Result<bool, CallError> {
+        self.update("addAdmin", Encode!(&new_admin)?).await
+    }
+
+    /// Replace the admin list, `false` when the caller is not an admin
+    pub async fn change_admin(&self, admins: Vec<Principal>) -> Result<bool, CallError> {
+        self.update("changeAdmin", Encode!(&admins)?).await
+    }
+
+    pub async fn get_admins(&self) -> Result<Vec<Principal>, CallError> {
+        self.query("getAdmins", Encode!()?).await
+    }
+
+    pub async fn get_log(&self) -> Result<Vec<LogEntry>, CallError> {
+        let log: Vec<(Nat, String)> = self.query("getLog", Encode!()?).await?;
+        Ok(log.into_iter().map(|(time, message)| LogEntry { time, message }).collect())
+    }
+
+    pub async fn clear_log(&self) -> Result<(), CallError> {
+        self.update("clearLog", Encode!()?).await
+    }
+
+    /// Upload a new wasm module used to install boxes of `box_type`
+    pub async fn update_wasm(&self, box_type: BoxType, wasm: Vec<u8>) -> Result<Result<String, String>, CallError> {
+        let args = UpdateWasmArgs { wasm, box_type };
+        Ok(match self.update("update_wasm", Encode!(&args)?).await? {
+            UpdateWasmResult::ok(message) => Ok(message),
+            UpdateWasmResult::err(message) => Err(message),
+        })
+    }
+
+    pub async fn install_cycle_wasm(&self, wasm: Vec<u8>) -> Result<Result<(), Error>, CallError> {
+        Ok(match self.update("installCycleWasm", Encode!(&wasm)?).await? {
+            InstallCycleWasmResult::ok => Ok(()),
+            InstallCycleWasmResult::err(error) => Err(error),
+        })
+    }
+
+    /// Mint a box for a user, answers the message of MetaBox
+    pub async fn mint_box(&self, to: Principal, box_type: BoxType, activity: String) -> Result<String, CallError> {
+        self.update("mintBox", Encode!(&to, &box_type, &activity)?).await
+    }
+
+    async fn query<R: CandidType + DeserializeOwned>(&self, method_name: &str, arg: Vec<u8>) -> Result<R, CallError> {
+        let response_blob = self.transport.query(&self.canister_id, method_name, arg, self.retry.for_method(method_name), None).await?;
+        Ok(Decode!(&response_blob, R)?)
+    }
+
+    async fn update<R: CandidType + DeserializeOwned>(&self, method_name: &str, arg: Vec<u8>) -> Result<R, CallError> {
+        let response_blob = self.transport.update(&self.canister_id, method_name, arg, &self.retry.for_update(method_name), None).await?;
+        Ok(Decode!(&response_blob, R)?)
+    }
+}
+
+fn build_client(pem_identity_path: &str, metabox_canister_id_text: &str) -> AdminClient {
+    AdminClient::new(build_agent(pem_identity_path), Principal::from_text(metabox_canister_id_text).unwrap())
+}
@@ -1,7 +1,7 @@
 use ic_cdk::export::candid::{self, CandidType, Deserialize};
 use ic_cdk::api::call::CallResult;
 
-#[derive(CandidType, Deserialize,Debug)]
+#[derive(CandidType, Deserialize,Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BoxType { xid, data_box, profile }
 
 #[derive(CandidType, Deserialize,Debug)]
@@ -72,7 +72,7 @@ pub struct TopUpArgs { pub box_id: candid::Principal, pub icp_amount: u64 }
 
 pub type AccountIdentifier = Vec<u8>;
 #[derive(CandidType, Deserialize,Debug)]
-pub struct Token { e8s: u64 }
+pub struct Token { pub e8s: u64 }
 
 pub type BlockIndex = u64;
 #[derive(CandidType, Deserialize,Debug)]
@@ -0,0 +1,211 @@
+//! In-memory MetaBox for offline tests
+//!
+//! [`MockMetaBox`] keeps the box registry of a MetaBox canister : `createBox` creates a
+//! [`MockDataBox`] owned by the principal in the install args, `getBoxes` lists the boxes of an
+//! owner, `topUpBox` adds cycles and `deleteBox` removes a box of the caller.
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use candid::{Decode, Encode, Principal};
+use crate::agent::{CallError, MockCanister};
+use crate::databox::mock::{reject, MockDataBox};
+use super::metabox_did::{CreateBoxArgs, DelBoxArgs, TopUpArgs};
+use super::{BoxInfo, BoxStatus, BoxType, CreateBoxResult, DeleteBoxResult, Error, TopUpBoxResult, MetaBox_CANISTER_ID_TEXT};
+
+/// Cycles a mock box receives per e8s of ICP
+pub const CYCLES_PER_E8S: u64 = 10_000;
+
+/// In-memory MetaBox, cheap to clone : clones share the same registry
+///
+/// Example code :
+/// ```
+/// use candid::Principal;
+/// use metabox_sdk::databox::DataBoxClient;
+/// use metabox_sdk::metabox::{CreateBoxResult, MetaBoxClient};
+/// use metabox_sdk::metabox::mock::MockMetaBox;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let user = Principal::anonymous();
+///     let meta_box = MockMetaBox::new();
+///     let client = MetaBoxClient::mock(&meta_box, user);
+///
+///     let box_id = match client.create_data_box(100_000_000, "photos".to_string(), false).await.unwrap() {
+///         CreateBoxResult::ok(box_id) => box_id,
+///         CreateBoxResult::err(error) => panic!("create box error: {:?}", error),
+///     };
+///     assert_eq!(client.get_boxes(user).await.unwrap().len(), 1);
+///
+///     let data_box = DataBoxClient::mock(&meta_box.data_box(box_id).unwrap(), user);
+///     assert_eq!(data_box.get_owner().await.unwrap(), user);
+/// }
+/// ```
+#[derive(Clone)]
+pub struct MockMetaBox {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    canister_id: Principal,
+    registry: Mutex<Registry>,
+}
+
+struct Registry {
+    next_id: u64,
+    boxes: BTreeMap<Principal, RegisteredBox>,
+}
+
+struct RegisteredBox {
+    owner: Principal,
+    box_name: String,
+    is_private: bool,
+    box_type: BoxType,
+    data_box: MockDataBox,
+}
+
+impl Default for MockMetaBox {
+    fn default() -> Self {
+        MockMetaBox::new()
+    }
+}
+
+impl MockMetaBox {
+    pub fn new() -> Self {
+        MockMetaBox::with_canister_id(Principal::from_text(MetaBox_CANISTER_ID_TEXT).unwrap())
+    }
+
+    pub fn with_canister_id(canister_id: Principal) -> Self {
+        MockMetaBox {
+            inner: Arc::new(Inner {
+                canister_id,
+                registry: Mutex::new(Registry {
+                    next_id: 1,
+                    boxes: BTreeMap::new(),
+                }),
+            }),
+        }
+    }
+
+    pub fn canister_id(&self) -> Principal {
+        self.inner.canister_id
+    }
+
+    /// The DataBox behind a box id, to build a [`crate::databox::DataBoxClient::mock`]
+    pub fn data_box(&self, box_id: Principal) -> Option<MockDataBox> {
+        let registry = self.inner.registry.lock().unwrap();
+        registry.boxes.get(&box_id).map(|registered| registered.data_box.clone())
+    }
+
+    /// Register an existing mock box, for example one with a limited capacity
+    pub fn register(&self, data_box: MockDataBox, box_name: String, is_private: bool) {
+        let mut registry = self.inner.registry.lock().unwrap();
+        registry.boxes.insert(data_box.canister_id(), RegisteredBox {
+            owner: data_box.owner(),
+            box_name,
+            is_private,
+            box_type: BoxType::data_box,
+            data_box,
+        });
+    }
+
+    pub(crate) fn canister(&self) -> Arc<dyn MockCanister> {
+        Arc::new(self.clone())
+    }
+
+    fn create_box(&self, registry: &mut Registry, args: CreateBoxArgs) -> CreateBoxResult {
+        if args.metadata.box_type != BoxType::data_box {
+            return CreateBoxResult::err(Error::Invalid_Operation);
+        }
+        let owner = match Decode!(&args.install_args, Principal) {
+            Ok(owner) => owner,
+            Err(_) => return CreateBoxResult::err(Error::Invalid_Operation),
+        };
+        let name_taken = registry
+            .boxes
+            .values()
+            .any(|registered| registered.owner == owner && registered.box_name == args.metadata.box_name);
+        if name_taken {
+            return CreateBoxResult::err(Error::NameRepeat);
+        }
+
+        // Opaque ids, distinct from the MetaBox one
+        let mut id_bytes = registry.next_id.to_be_bytes().to_vec();
+        id_bytes.extend_from_slice(&[0x4d, 0x42, 0x01]);
+        registry.next_id += 1;
+        let box_id = Principal::from_slice(&id_bytes);
+
+        let data_box = MockDataBox::new(box_id, owner);
+        data_box.add_cycles(args.icp_amount.saturating_mul(CYCLES_PER_E8S));
+        registry.boxes.insert(box_id, RegisteredBox {
+            owner,
+            box_name: args.metadata.box_name,
+            is_private: args.metadata.is_private,
+            box_type: args.metadata.box_type,
+            data_box,
+        });
+        CreateBoxResult::ok(box_id)
+    }
+
+    fn dispatch(&self, caller: Principal, method_name: &str, arg: &[u8]) -> Result<Vec<u8>, CallError> {
+        let mut registry = self.inner.registry.lock().unwrap();
+        let response = match method_name {
+            "createBox" => {
+                let args = Decode!(arg, CreateBoxArgs)?;
+                Encode!(&self.create_box(&mut registry, args))?
+            }
+            "getBoxes" => {
+                let who = Decode!(arg, Principal)?;
+                let boxes: Vec<BoxInfo> = registry
+                    .boxes
+                    .iter()
+                    .filter(|(_, registered)| registered.owner == who)
+                    .map(|(box_id, registered)| BoxInfo {
+                        status: BoxStatus::running,
+                        canister_id: *box_id,
+                        is_private: registered.is_private,
+                        box_name: registered.box_name.clone(),
+                        box_type: registered.box_type,
+                    })
+                    .collect();
+                Encode!(&boxes)?
+            }
+            "topUpBox" => {
+                let args = Decode!(arg, TopUpArgs)?;
+                let result = match registry.boxes.get(&args.box_id) {
+                    Some(registered) => {
+                        registered.data_box.add_cycles(args.icp_amount.saturating_mul(CYCLES_PER_E8S));
+                        TopUpBoxResult::ok
+                    }
+                    None => TopUpBoxResult::err(Error::NoBox),
+                };
+                Encode!(&result)?
+            }
+            "deleteBox" => {
+                let args = Decode!(arg, DelBoxArgs)?;
+                let result = match registry.boxes.get(&args.canisterId) {
+                    None => DeleteBoxResult::err(Error::NoBox),
+                    Some(registered) if registered.owner != caller => DeleteBoxResult::err(Error::UnAuthorized),
+                    Some(registered) if registered.box_type != BoxType::data_box || args.box_type != BoxType::data_box => {
+                        DeleteBoxResult::err(Error::OnlyDataBoxCanDeleted)
+                    }
+                    Some(_) => {
+                        registry.boxes.remove(&args.canisterId);
+                        DeleteBoxResult::ok("delete box success".to_string())
+                    }
+                };
+                Encode!(&result)?
+            }
+            other => return Err(reject(self.inner.canister_id, other)),
+        };
+        Ok(response)
+    }
+}
+
+impl MockCanister for MockMetaBox {
+    fn call(&self, caller: Principal, method_name: &str, arg: &[u8]) -> Result<Vec<u8>, CallError> {
+        self.dispatch(caller, method_name, arg)
+    }
+
+    fn sibling(&self, canister_id: &Principal) -> Option<Arc<dyn MockCanister>> {
+        self.data_box(*canister_id).map(|data_box| data_box.canister())
+    }
+}
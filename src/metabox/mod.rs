@@ -1,63 +1,175 @@
-use garcon::Delay;
 use ic_agent::Agent;
-use ic_agent::identity::Secp256k1Identity;
-use ic_agent::agent::http_transport::ReqwestHttpReplicaV2Transport;
-use candid::{Decode, Encode, Principal};
+use candid::{CandidType, Decode, Encode, Principal};
+use serde::de::DeserializeOwned;
+use crate::agent::{build_agent, CallError, RetryPolicies, RetryPolicy, Transport};
+use crate::databox::DataBoxClient;
 mod metabox_did;
-use metabox_did::{CreateBoxArgs, CreateBoxResult, BoxMetadata, BoxInfo};
-use crate::metabox::metabox_did::BoxType;
+#[cfg(feature = "admin")]
+pub mod admin;
+pub mod mock;
+use mock::MockMetaBox;
+use metabox_did::{CreateBoxArgs, BoxMetadata, DelBoxArgs, TopUpArgs};
+pub use metabox_did::{BoxInfo, BoxType, BoxStatus, Error, CreateBoxResult, DeleteBoxResult, TopUpBoxResult};
+pub use metabox_did::{BlockIndex, Token, TransferError, TransferOutICPResult};
 
 static MetaBox_CANISTER_ID_TEXT: &'static str = "zbzr7-xyaaa-aaaan-qadeq-cai";
 
 pub async fn create_data_box(pem_identity_path: &str, icp_amount: u64, box_name: String, is_private: bool) -> CreateBoxResult{
-    let canister_id = Principal::from_text(MetaBox_CANISTER_ID_TEXT).unwrap();
-    let agent = build_agent(pem_identity_path);
-    let user_principal = agent.get_principal().unwrap();
-    let args = CreateBoxArgs {
-        metadata: BoxMetadata {
-            is_private: is_private.clone(),
-            box_name: box_name.clone(),
-            box_type: BoxType::data_box,
-        },
-        install_args: Encode!(&user_principal).expect("encode install args failed"),
-        icp_amount: icp_amount.clone(),
-    };
-    let response_blob = agent
-        .update(&canister_id, "createBox")
-        .with_arg(Encode!(&args).expect("encode piece failed"))
-        .call_and_wait(get_waiter())
+    build_client(pem_identity_path)
+        .create_data_box(icp_amount, box_name, is_private)
         .await
-        .expect("response error");
-    Decode!(&response_blob, CreateBoxResult).unwrap()
+        .expect("response error")
 }
 
 pub async fn get_boxes(pem_identity_path: &str, who: candid::Principal) -> Vec<BoxInfo> {
-    let canister_id = Principal::from_text(MetaBox_CANISTER_ID_TEXT).unwrap();
-    let response_blob = build_agent(pem_identity_path)
-        .query(&canister_id, "getBoxes")
-        .with_arg(Encode!(&who).expect("encode piece failed"))
-        .call()
+    build_client(pem_identity_path)
+        .get_boxes(who)
+        .await
+        .expect("response error")
+}
+
+/// Top up a box with cycles converted from the user 's ICP in MetaBox
+///
+/// Example code :
+/// ``` no_run
+/// use candid::Principal;
+/// use metabox_sdk::metabox::{self, TopUpBoxResult};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let box_id = Principal::from_text("4radi-oqaaa-aaaan-qapwa-cai").unwrap();
+///     println!("top up box result: {:?}", metabox::top_up_box("identities/identity.pem", box_id, 10_000_000).await);
+/// }
+/// ```
+pub async fn top_up_box(pem_identity_path: &str, box_id: Principal, icp_amount: u64) -> TopUpBoxResult {
+    build_client(pem_identity_path)
+        .top_up_box(box_id, icp_amount)
+        .await
+        .expect("response error")
+}
+
+/// Delete a DataBox, the remaining cycles go to `cycle_to` if given
+///
+/// Example code :
+/// ``` no_run
+/// use candid::Principal;
+/// use metabox_sdk::metabox;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let box_id = Principal::from_text("4radi-oqaaa-aaaan-qapwa-cai").unwrap();
+///     println!("delete box result: {:?}", metabox::delete_data_box("identities/identity.pem", box_id, None).await);
+/// }
+/// ```
+pub async fn delete_data_box(pem_identity_path: &str, box_id: Principal, cycle_to: Option<Principal>) -> DeleteBoxResult {
+    build_client(pem_identity_path)
+        .delete_data_box(box_id, cycle_to)
         .await
-        .expect("response error");
-    Decode!(&response_blob, Vec<BoxInfo>).unwrap()
+        .expect("response error")
+}
+
+/// MetaBox client bound to one agent, see [`crate::databox::DataBoxClient`]
+#[derive(Clone)]
+pub struct MetaBoxClient {
+    transport: Transport,
+    canister_id: Principal,
+    retry: RetryPolicies,
 }
 
-fn get_waiter() -> Delay {
-    let waiter = garcon::Delay::builder()
-        .throttle(std::time::Duration::from_millis(500))
-        .timeout(std::time::Duration::from_secs(60 * 5))
-        .build();
-    waiter
+impl MetaBoxClient {
+    pub fn new(agent: Agent) -> Self {
+        MetaBoxClient::with_canister_id(agent, Principal::from_text(MetaBox_CANISTER_ID_TEXT).unwrap())
+    }
+
+    /// Client of a self-hosted MetaBox deployment
+    pub fn with_canister_id(agent: Agent, canister_id: Principal) -> Self {
+        MetaBoxClient {
+            transport: Transport::Agent(agent),
+            canister_id,
+            retry: RetryPolicies::default(),
+        }
+    }
+
+    /// Client of an in-memory [`MockMetaBox`], calls are made as `caller` and never leave the process
+    pub fn mock(meta_box: &MockMetaBox, caller: Principal) -> Self {
+        MetaBoxClient {
+            transport: Transport::Mock {
+                canister: meta_box.canister(),
+                caller,
+            },
+            canister_id: meta_box.canister_id(),
+            retry: RetryPolicies::default(),
+        }
+    }
+
+    pub fn canister_id(&self) -> Principal {
+        self.canister_id
+    }
+
+    /// Retry policy of every call, [`RetryPolicy::default`] unless set
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry.default = policy;
+        self
+    }
+
+    /// Retry policy of one canister method (`"put"`, `"getPlain"`, ...), over the client 's policy
+    pub fn with_method_retry_policy(mut self, method_name: &str, policy: RetryPolicy) -> Self {
+        self.retry.methods.insert(method_name.to_string(), policy);
+        self
+    }
+
+    /// Principal of the agent 's identity
+    pub fn caller(&self) -> Principal {
+        self.transport.caller()
+    }
+
+    /// Client of one of the boxes, with the same agent (or mock)
+    pub fn data_box_client(&self, box_id: Principal) -> DataBoxClient {
+        DataBoxClient::with_transport(self.transport.for_canister(&box_id), box_id)
+    }
+
+    pub async fn create_data_box(&self, icp_amount: u64, box_name: String, is_private: bool) -> Result<CreateBoxResult, CallError> {
+        let args = CreateBoxArgs {
+            metadata: BoxMetadata {
+                is_private,
+                box_name,
+                box_type: BoxType::data_box,
+            },
+            install_args: Encode!(&self.caller())?,
+            icp_amount,
+        };
+        self.update("createBox", Encode!(&args)?).await
+    }
+
+    pub async fn get_boxes(&self, who: Principal) -> Result<Vec<BoxInfo>, CallError> {
+        self.query("getBoxes", Encode!(&who)?).await
+    }
+
+    pub async fn top_up_box(&self, box_id: Principal, icp_amount: u64) -> Result<TopUpBoxResult, CallError> {
+        let args = TopUpArgs { box_id, icp_amount };
+        self.update("topUpBox", Encode!(&args)?).await
+    }
+
+    pub async fn delete_data_box(&self, box_id: Principal, cycle_to: Option<Principal>) -> Result<DeleteBoxResult, CallError> {
+        let args = DelBoxArgs {
+            cycleTo: cycle_to,
+            box_type: BoxType::data_box,
+            canisterId: box_id,
+        };
+        self.update("deleteBox", Encode!(&args)?).await
+    }
+
+    async fn query<R: CandidType + DeserializeOwned>(&self, method_name: &str, arg: Vec<u8>) -> Result<R, CallError> {
+        let response_blob = self.transport.query(&self.canister_id, method_name, arg, self.retry.for_method(method_name), None).await?;
+        Ok(Decode!(&response_blob, R)?)
+    }
+
+    async fn update<R: CandidType + DeserializeOwned>(&self, method_name: &str, arg: Vec<u8>) -> Result<R, CallError> {
+        let response_blob = self.transport.update(&self.canister_id, method_name, arg, &self.retry.for_update(method_name), None).await?;
+        Ok(Decode!(&response_blob, R)?)
+    }
 }
 
-fn build_agent(pem_identity_path: &str) -> Agent {
-    let url = "https://ic0.app".to_string();
-    let identity = Secp256k1Identity::from_pem_file(String::from(pem_identity_path)).unwrap();
-    let transport = ReqwestHttpReplicaV2Transport::create(url).expect("transport error");
-    let agent = Agent::builder()
-        .with_transport(transport)
-        .with_identity(identity)
-        .build()
-        .expect("build agent error");
-    agent
-}
\ No newline at end of file
+fn build_client(pem_identity_path: &str) -> MetaBoxClient {
+    MetaBoxClient::new(build_agent(pem_identity_path))
+}
@@ -0,0 +1,441 @@
+//! MIME types of stored files
+//!
+//! A DataBox keeps the MIME type of a file in its `file_extension` field. [`detect`] picks it from
+//! the first bytes of the file (magic numbers) and falls back to the extension of its name, the
+//! [`MimeRegistry`] maps extensions to MIME types and back, for downloads. Types can be registered
+//! to override or extend the built in table, globally with [`register`].
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Extensions and their MIME type, the first extension of a type is the one given back for it
+const TYPES: &[(&str, &str)] = &[
+    // Documents
+    ("pdf", "application/pdf"),
+    ("txt", "text/plain"),
+    ("text", "text/plain"),
+    ("log", "text/plain"),
+    ("md", "text/markdown"),
+    ("markdown", "text/markdown"),
+    ("csv", "text/csv"),
+    ("tsv", "text/tab-separated-values"),
+    ("rtf", "application/rtf"),
+    ("doc", "application/msword"),
+    ("docx", "application/vnd.openxmlformats-officedocument.wordprocessingml.document"),
+    ("xls", "application/vnd.ms-excel"),
+    ("xlsx", "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"),
+    ("ppt", "application/vnd.ms-powerpoint"),
+    ("pptx", "application/vnd.openxmlformats-officedocument.presentationml.presentation"),
+    ("odt", "application/vnd.oasis.opendocument.text"),
+    ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+    ("odp", "application/vnd.oasis.opendocument.presentation"),
+    ("epub", "application/epub+zip"),
+    ("pages", "application/vnd.apple.pages"),
+    ("numbers", "application/vnd.apple.numbers"),
+    ("key", "application/vnd.apple.keynote"),
+    // Web and data
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("xhtml", "application/xhtml+xml"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("json", "application/json"),
+    ("jsonld", "application/ld+json"),
+    ("xml", "application/xml"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("toml", "application/toml"),
+    ("ics", "text/calendar"),
+    ("vcf", "text/vcard"),
+    ("wasm", "application/wasm"),
+    ("did", "text/plain"),
+    ("rs", "text/x-rust"),
+    ("py", "text/x-python"),
+    ("sh", "application/x-sh"),
+    // Images
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("jpe", "image/jpeg"),
+    ("png", "image/png"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("svg", "image/svg+xml"),
+    ("svgz", "image/svg+xml"),
+    ("bmp", "image/bmp"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    ("heic", "image/heic"),
+    ("heif", "image/heif"),
+    ("avif", "image/avif"),
+    ("jxl", "image/jxl"),
+    ("psd", "image/vnd.adobe.photoshop"),
+    // Audio
+    ("mp3", "audio/mpeg"),
+    ("m4a", "audio/mp4"),
+    ("aac", "audio/aac"),
+    ("wav", "audio/wav"),
+    ("ogg", "audio/ogg"),
+    ("oga", "audio/ogg"),
+    ("opus", "audio/opus"),
+    ("flac", "audio/flac"),
+    ("mid", "audio/midi"),
+    ("midi", "audio/midi"),
+    ("weba", "audio/webm"),
+    ("aiff", "audio/aiff"),
+    // Video
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("mov", "video/quicktime"),
+    ("webm", "video/webm"),
+    ("mkv", "video/x-matroska"),
+    ("avi", "video/x-msvideo"),
+    ("wmv", "video/x-ms-wmv"),
+    ("flv", "video/x-flv"),
+    ("mpeg", "video/mpeg"),
+    ("mpg", "video/mpeg"),
+    ("ogv", "video/ogg"),
+    ("3gp", "video/3gpp"),
+    ("ts", "video/mp2t"),
+    // Archives
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tgz", "application/gzip"),
+    ("tar.gz", "application/gzip"),
+    ("bz2", "application/x-bzip2"),
+    ("tar.bz2", "application/x-bzip2"),
+    ("xz", "application/x-xz"),
+    ("tar.xz", "application/x-xz"),
+    ("zst", "application/zstd"),
+    ("tar.zst", "application/zstd"),
+    ("tar", "application/x-tar"),
+    ("7z", "application/x-7z-compressed"),
+    ("rar", "application/vnd.rar"),
+    ("jar", "application/java-archive"),
+    ("apk", "application/vnd.android.package-archive"),
+    ("ipa", "application/octet-stream"),
+    ("dmg", "application/x-apple-diskimage"),
+    ("iso", "application/x-iso9660-image"),
+    ("deb", "application/vnd.debian.binary-package"),
+    ("rpm", "application/x-rpm"),
+    ("exe", "application/vnd.microsoft.portable-executable"),
+    ("msi", "application/x-msi"),
+    // Fonts
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    // Others
+    ("sqlite", "application/vnd.sqlite3"),
+    ("db", "application/vnd.sqlite3"),
+    ("pem", "application/x-pem-file"),
+    ("bin", OCTET_STREAM),
+];
+
+/// Types stored by earlier versions of the SDK, only read back
+const LEGACY_TYPES: &[(&str, &str)] = &[
+    ("image/jpg", "jpg"),
+    ("audio/mp3", "mp3"),
+    ("application/x-xls", "xls"),
+    ("text/xml", "xml"),
+];
+
+/// Extensions of more than one part, matched before the last one
+const COMPOUND_EXTENSIONS: &[&str] = &["tar.gz", "tar.bz2", "tar.xz", "tar.zst"];
+
+/// Sniffed types which only tell the container, the extension says better what is in it
+const CONTAINER_TYPES: &[&str] = &["application/zip", "application/x-cfb", "application/xml"];
+
+/// Split a file name into its stem and extension
+///
+/// The extension is what follows the last dot (or a known compound extension like `tar.gz`),
+/// a leading dot belongs to the stem.
+///
+/// Example code :
+/// ```
+/// use metabox_sdk::mime::split_name;
+///
+/// assert_eq!(split_name("report.v2.pdf"), ("report.v2", Some("pdf")));
+/// assert_eq!(split_name("backup.tar.gz"), ("backup", Some("tar.gz")));
+/// assert_eq!(split_name("Makefile"), ("Makefile", None));
+/// assert_eq!(split_name(".bashrc"), (".bashrc", None));
+/// ```
+pub fn split_name(file_name: &str) -> (&str, Option<&str>) {
+    let lower = file_name.to_ascii_lowercase();
+    for compound in COMPOUND_EXTENSIONS {
+        if lower.len() > compound.len() + 1 && lower.ends_with(&format!(".{}", compound)) {
+            let split = file_name.len() - compound.len();
+            return (&file_name[..split - 1], Some(&file_name[split..]));
+        }
+    }
+    match file_name.rfind('.') {
+        Some(dot) if dot > 0 && dot + 1 < file_name.len() => (&file_name[..dot], Some(&file_name[dot + 1..])),
+        _ => (file_name, None),
+    }
+}
+
+/// Type of a content recognized by its first bytes
+///
+/// Zip and OLE (legacy Office) files are reported as `application/zip` and `application/x-cfb`,
+/// [`MimeRegistry::detect`] refines them with the extension.
+///
+/// Example code :
+/// ```
+/// use metabox_sdk::mime::sniff;
+///
+/// assert_eq!(sniff(b"%PDF-1.7\n..."), Some("application/pdf"));
+/// assert_eq!(sniff(b"\x89PNG\r\n\x1a\n..."), Some("image/png"));
+/// assert_eq!(sniff(b"hello"), None);
+/// ```
+pub fn sniff(data: &[u8]) -> Option<&'static str> {
+    let starts = |magic: &[u8]| data.starts_with(magic);
+    let at = |offset: usize, magic: &[u8]| data.len() >= offset + magic.len() && &data[offset..offset + magic.len()] == magic;
+
+    if starts(b"%PDF-") {
+        Some("application/pdf")
+    } else if starts(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if starts(b"\xff\xd8\xff") {
+        Some("image/jpeg")
+    } else if starts(b"GIF87a") || starts(b"GIF89a") {
+        Some("image/gif")
+    } else if starts(b"RIFF") && at(8, b"WEBP") {
+        Some("image/webp")
+    } else if starts(b"RIFF") && at(8, b"WAVE") {
+        Some("audio/wav")
+    } else if starts(b"RIFF") && at(8, b"AVI ") {
+        Some("video/x-msvideo")
+    } else if starts(b"BM") && data.len() > 14 && at(6, b"\0\0\0\0") {
+        Some("image/bmp")
+    } else if starts(b"II*\0") || starts(b"MM\0*") {
+        Some("image/tiff")
+    } else if starts(b"\0\0\x01\0") {
+        Some("image/vnd.microsoft.icon")
+    } else if starts(b"8BPS") {
+        Some("image/vnd.adobe.photoshop")
+    } else if starts(b"\xff\x0a") || at(4, b"JXL \r\n\x87\n") {
+        Some("image/jxl")
+    } else if at(4, b"ftyp") {
+        Some(iso_media(data.get(8..12).unwrap_or_default()))
+    } else if starts(b"ID3") || (data.len() > 1 && data[0] == 0xff && data[1] & 0xe6 == 0xe2) {
+        Some("audio/mpeg")
+    } else if starts(b"OggS") {
+        Some("audio/ogg")
+    } else if starts(b"fLaC") {
+        Some("audio/flac")
+    } else if starts(b"MThd") {
+        Some("audio/midi")
+    } else if starts(b"FORM") && at(8, b"AIFF") {
+        Some("audio/aiff")
+    } else if starts(b"\x1a\x45\xdf\xa3") {
+        let header = &data[..data.len().min(64)];
+        if header.windows(4).any(|window| window == b"webm") {
+            Some("video/webm")
+        } else {
+            Some("video/x-matroska")
+        }
+    } else if starts(b"FLV\x01") {
+        Some("video/x-flv")
+    } else if starts(b"\0\0\x01\xba") || starts(b"\0\0\x01\xb3") {
+        Some("video/mpeg")
+    } else if starts(b"\x30\x26\xb2\x75\x8e\x66\xcf\x11") {
+        Some("video/x-ms-wmv")
+    } else if starts(b"PK\x03\x04") || starts(b"PK\x05\x06") {
+        Some(zip_media(data))
+    } else if starts(b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1") {
+        Some("application/x-cfb")
+    } else if starts(b"\x1f\x8b") {
+        Some("application/gzip")
+    } else if starts(b"BZh") {
+        Some("application/x-bzip2")
+    } else if starts(b"\xfd7zXZ\0") {
+        Some("application/x-xz")
+    } else if starts(b"\x28\xb5\x2f\xfd") {
+        Some("application/zstd")
+    } else if starts(b"7z\xbc\xaf\x27\x1c") {
+        Some("application/x-7z-compressed")
+    } else if starts(b"Rar!\x1a\x07") {
+        Some("application/vnd.rar")
+    } else if at(257, b"ustar") {
+        Some("application/x-tar")
+    } else if starts(b"\0asm") {
+        Some("application/wasm")
+    } else if starts(b"MZ") {
+        Some("application/vnd.microsoft.portable-executable")
+    } else if starts(b"wOFF") {
+        Some("font/woff")
+    } else if starts(b"wOF2") {
+        Some("font/woff2")
+    } else if starts(b"\0\x01\0\0\0") {
+        Some("font/ttf")
+    } else if starts(b"OTTO") {
+        Some("font/otf")
+    } else if starts(b"SQLite format 3\0") {
+        Some("application/vnd.sqlite3")
+    } else if starts(b"{\\rtf") {
+        Some("application/rtf")
+    } else {
+        sniff_text(data)
+    }
+}
+
+/// Brand of an ISO base media file (mp4, mov, heic, ...)
+fn iso_media(brand: &[u8]) -> &'static str {
+    match brand {
+        b"M4A " | b"M4B " => "audio/mp4",
+        b"qt  " => "video/quicktime",
+        b"heic" | b"heix" | b"hevc" | b"hevx" => "image/heic",
+        b"mif1" | b"msf1" => "image/heif",
+        b"avif" | b"avis" => "image/avif",
+        b"3gp4" | b"3gp5" | b"3g2a" => "video/3gpp",
+        _ => "video/mp4",
+    }
+}
+
+/// Zip based formats naming themselves in their first entry
+fn zip_media(data: &[u8]) -> &'static str {
+    let header = &data[..data.len().min(128)];
+    let contains = |needle: &[u8]| header.windows(needle.len()).any(|window| window == needle);
+    if contains(b"mimetypeapplication/epub+zip") {
+        "application/epub+zip"
+    } else if contains(b"mimetypeapplication/vnd.oasis.opendocument.text") {
+        "application/vnd.oasis.opendocument.text"
+    } else if contains(b"mimetypeapplication/vnd.oasis.opendocument.spreadsheet") {
+        "application/vnd.oasis.opendocument.spreadsheet"
+    } else if contains(b"mimetypeapplication/vnd.oasis.opendocument.presentation") {
+        "application/vnd.oasis.opendocument.presentation"
+    } else {
+        "application/zip"
+    }
+}
+
+fn sniff_text(data: &[u8]) -> Option<&'static str> {
+    let head = String::from_utf8_lossy(&data[..data.len().min(512)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    let lower = head.to_ascii_lowercase();
+    if lower.starts_with("<!doctype html") || lower.starts_with("<html") {
+        Some("text/html")
+    } else if lower.starts_with("<svg") || (lower.starts_with("<?xml") && lower.contains("<svg")) {
+        Some("image/svg+xml")
+    } else if lower.starts_with("<?xml") {
+        Some("application/xml")
+    } else {
+        None
+    }
+}
+
+/// Extension to MIME type mapping, the built in table plus registered types
+#[derive(Clone, Debug)]
+pub struct MimeRegistry {
+    by_extension: HashMap<String, String>,
+    by_mime: HashMap<String, String>,
+    overrides: HashMap<String, String>,
+}
+
+impl Default for MimeRegistry {
+    fn default() -> Self {
+        let mut by_extension = HashMap::new();
+        let mut by_mime = HashMap::new();
+        for (extension, mime_type) in TYPES {
+            by_extension.insert(extension.to_string(), mime_type.to_string());
+            by_mime.entry(mime_type.to_string()).or_insert_with(|| extension.to_string());
+        }
+        for (mime_type, extension) in LEGACY_TYPES {
+            by_mime.entry(mime_type.to_string()).or_insert_with(|| extension.to_string());
+        }
+        MimeRegistry {
+            by_extension,
+            by_mime,
+            overrides: HashMap::new(),
+        }
+    }
+}
+
+impl MimeRegistry {
+    /// The built in table
+    pub fn new() -> Self {
+        MimeRegistry::default()
+    }
+
+    /// Map `extension` (without dot, any case) to `mime_type`, before anything sniffed
+    ///
+    /// `mime_type` maps back to `extension` unless it already has an extension.
+    pub fn register(&mut self, extension: &str, mime_type: &str) {
+        let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+        self.overrides.insert(extension.clone(), mime_type.to_string());
+        self.by_extension.insert(extension.clone(), mime_type.to_string());
+        self.by_mime.entry(mime_type.to_string()).or_insert(extension);
+    }
+
+    /// MIME type of an extension (without dot, any case)
+    pub fn from_extension(&self, extension: &str) -> Option<&str> {
+        self.by_extension.get(&extension.to_ascii_lowercase()).map(String::as_str)
+    }
+
+    /// Extension of a MIME type, `None` for `application/octet-stream` and unknown types
+    pub fn to_extension(&self, mime_type: &str) -> Option<&str> {
+        let essence = mime_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        if essence == OCTET_STREAM {
+            return None;
+        }
+        self.by_mime.get(&essence).map(String::as_str)
+    }
+
+    /// MIME type of a file, from a registered extension, its content, then its extension
+    ///
+    /// Example code :
+    /// ```
+    /// use metabox_sdk::mime::MimeRegistry;
+    ///
+    /// let mut registry = MimeRegistry::new();
+    /// assert_eq!(registry.detect("scan.jpg", b"\x89PNG\r\n\x1a\n..."), "image/png");
+    /// assert_eq!(registry.detect("report.v2.pdf", b"plain text"), "application/pdf");
+    /// assert_eq!(registry.detect("sheet.xlsx", b"PK\x03\x04..."), "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet");
+    /// assert_eq!(registry.detect("Makefile", b"all:\n"), "application/octet-stream");
+    ///
+    /// registry.register("pdf", "application/x-pdf");
+    /// assert_eq!(registry.detect("report.pdf", b"%PDF-1.7"), "application/x-pdf");
+    /// assert_eq!(registry.to_extension("application/x-pdf"), Some("pdf"));
+    /// ```
+    pub fn detect(&self, file_name: &str, data: &[u8]) -> String {
+        let extension = split_name(file_name).1.map(str::to_ascii_lowercase);
+        if let Some(mime_type) = extension.as_ref().and_then(|extension| self.overrides.get(extension)) {
+            return mime_type.clone();
+        }
+        let by_extension = extension.as_deref().and_then(|extension| self.from_extension(extension));
+        match (sniff(data), by_extension) {
+            (Some(sniffed), Some(mime_type)) if CONTAINER_TYPES.contains(&sniffed) => mime_type.to_string(),
+            (Some("application/x-cfb"), None) => OCTET_STREAM.to_string(),
+            (Some(sniffed), _) => sniffed.to_string(),
+            (None, Some(mime_type)) => mime_type.to_string(),
+            (None, None) => OCTET_STREAM.to_string(),
+        }
+    }
+}
+
+fn registry() -> &'static RwLock<MimeRegistry> {
+    static REGISTRY: OnceLock<RwLock<MimeRegistry>> = OnceLock::new();
+    REGISTRY.get_or_init(|| RwLock::new(MimeRegistry::new()))
+}
+
+/// Register a type in the registry used by the SDK, see [`MimeRegistry::register`]
+pub fn register(extension: &str, mime_type: &str) {
+    registry().write().unwrap().register(extension, mime_type);
+}
+
+/// [`MimeRegistry::from_extension`] of the SDK registry, `application/octet-stream` when unknown
+pub fn from_extension(extension: &str) -> String {
+    registry().read().unwrap().from_extension(extension).unwrap_or(OCTET_STREAM).to_string()
+}
+
+/// [`MimeRegistry::to_extension`] of the SDK registry
+pub fn to_extension(mime_type: &str) -> Option<String> {
+    registry().read().unwrap().to_extension(mime_type).map(str::to_string)
+}
+
+/// [`MimeRegistry::detect`] of the SDK registry
+pub fn detect(file_name: &str, data: &[u8]) -> String {
+    registry().read().unwrap().detect(file_name, data)
+}
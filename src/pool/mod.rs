@@ -0,0 +1,340 @@
+//! Storage pool over several DataBoxes
+//!
+//! A DataBox has a bounded stable memory. A [`StoragePool`] spreads the uploads of a user over
+//! their data boxes, as listed by MetaBox : each file goes to the first box with room for it and,
+//! with a [`Growth`], a new box is created when they are all full. An index of the file keys of
+//! every box lets reads and deletes find the box a file is in.
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use bytes::Bytes;
+use candid::Principal;
+use crate::agent::CallError;
+use crate::databox::capacity::{CapacityError, CapacityPolicy, PlannedFile};
+use crate::databox::key::KeyScheme;
+use crate::databox::{AvlSMResult, DataBoxClient, DataErr, DeleteKeyResult, FileExt, PutPlainFileResult, UploadStatus};
+use crate::metabox::{BoxType, CreateBoxResult, Error, MetaBoxClient};
+use crate::transfer::Transfer;
+
+/// Attempts at a free box name before giving up
+const MAX_NAME_ATTEMPTS: usize = 16;
+
+/// How a pool creates a box when none has room for a file
+#[derive(Clone, Debug)]
+pub struct Growth {
+    /// ICP (e8s) paid for the cycles of each new box
+    pub icp_amount: u64,
+    /// New boxes are named `<box_name>-<n>`
+    pub box_name: String,
+    pub is_private: bool,
+}
+
+#[derive(Debug)]
+pub enum PoolError {
+    Call(CallError),
+    /// MetaBox did not create a box
+    CreateBox(Error),
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::Call(error) => write!(f, "{}", error),
+            PoolError::CreateBox(error) => write!(f, "create box error: {:?}", error),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+impl From<CallError> for PoolError {
+    fn from(error: CallError) -> Self {
+        PoolError::Call(error)
+    }
+}
+
+/// The data boxes of a user, used as one storage
+///
+/// Example code :
+/// ```
+/// use bytes::Bytes;
+/// use candid::Principal;
+/// use metabox_sdk::databox::mock::MockDataBox;
+/// use metabox_sdk::metabox::MetaBoxClient;
+/// use metabox_sdk::metabox::mock::MockMetaBox;
+/// use metabox_sdk::pool::{Growth, StoragePool};
+/// use metabox_sdk::transfer::Transfer;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let user = Principal::anonymous();
+///     let meta_box = MockMetaBox::new();
+///     let small_box = MockDataBox::new(Principal::from_slice(&[7; 10]), user).with_capacity(10_000);
+///     meta_box.register(small_box.clone(), "small".to_string(), false);
+///
+///     let growth = Growth { icp_amount: 100_000_000, box_name: "spill".to_string(), is_private: false };
+///     let pool = StoragePool::open(MetaBoxClient::mock(&meta_box, user)).await.unwrap().with_growth(growth);
+///     let transfer = Transfer::new();
+///     let first = pool.put_bytes_with(Bytes::from(vec![1; 6_000]), "first.bin", None, &transfer).await.unwrap();
+///     let second = pool.put_bytes_with(Bytes::from(vec![2; 6_000]), "second.bin", None, &transfer).await.unwrap();
+///
+///     assert_eq!(first.databox_canister_id, small_box.canister_id());
+///     assert_ne!(second.databox_canister_id, small_box.canister_id());
+///     assert_eq!(pool.boxes().len(), 2);
+///     assert_eq!(pool.locate(&second.file_key).await.unwrap(), Some(second.databox_canister_id));
+///     assert_eq!(pool.get_plain_file(&second.file_key).await.unwrap().unwrap(), vec![2; 6_000]);
+/// }
+/// ```
+pub struct StoragePool {
+    metabox: MetaBoxClient,
+    growth: Option<Growth>,
+    boxes: Mutex<Vec<DataBoxClient>>,
+    /// File key to box, `None` until the boxes are listed
+    index: Mutex<Option<HashMap<String, Principal>>>,
+}
+
+impl StoragePool {
+    /// The pool of the data boxes of the MetaBox client 's caller
+    pub async fn open(metabox: MetaBoxClient) -> Result<Self, CallError> {
+        let boxes = metabox
+            .get_boxes(metabox.caller())
+            .await?
+            .into_iter()
+            .filter(|box_info| box_info.box_type == BoxType::data_box)
+            .map(|box_info| metabox.data_box_client(box_info.canister_id))
+            .collect();
+        Ok(StoragePool {
+            metabox,
+            growth: None,
+            boxes: Mutex::new(boxes),
+            index: Mutex::new(None),
+        })
+    }
+
+    /// Create boxes when the pool is full
+    pub fn with_growth(mut self, growth: Growth) -> Self {
+        self.growth = Some(growth);
+        self
+    }
+
+    /// Ids of the boxes, in placement order
+    pub fn boxes(&self) -> Vec<Principal> {
+        self.boxes.lock().unwrap().iter().map(DataBoxClient::canister_id).collect()
+    }
+
+    pub fn data_box(&self, box_id: Principal) -> Option<DataBoxClient> {
+        self.boxes.lock().unwrap().iter().find(|client| client.canister_id() == box_id).cloned()
+    }
+
+    /// Upload a file to the box it is in already, else to the first box with room for it
+    pub async fn put_plain_file_with(&self, file_path_str: &str, transfer: &Transfer) -> Result<PutPlainFileResult, PoolError> {
+        let file_path = Path::new(file_path_str);
+        let data = Bytes::from(fs::read(file_path).map_err(CallError::from)?);
+        let name = file_path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        self.put_bytes_with(data, &name, None, transfer).await
+    }
+
+    /// [`DataBoxClient::put_bytes_with`] to a box of the pool
+    ///
+    /// When no box has room and the pool does not grow, the file ends
+    /// `Err(DataErr::MemoryInsufficient)`, or the upload fails with `CallError::Capacity` by
+    /// `CapacityPolicy::Reject`.
+    pub async fn put_bytes_with(
+        &self,
+        data: Bytes,
+        file_name: &str,
+        content_type: Option<&str>,
+        transfer: &Transfer,
+    ) -> Result<PutPlainFileResult, PoolError> {
+        let planned = PlannedFile::new(&data, file_name, content_type, KeyScheme::default());
+        let known = if transfer.dedup() { self.locate(&planned.file_key).await? } else { None };
+        let client = match known.and_then(|box_id| self.data_box(box_id)) {
+            Some(client) => client,
+            None => match self.place(&planned).await? {
+                Ok(client) => client,
+                Err(available) => return self.refuse(planned, available, transfer),
+            },
+        };
+        // The box was chosen for its room, it is not checked again
+        let transfer = transfer.clone().with_capacity_policy(CapacityPolicy::Unchecked);
+        let result = client.put_bytes_with(data, file_name, content_type, &transfer).await?;
+        // A cancelled upload is not in its box until it is resumed
+        if matches!(result.upload_status, UploadStatus::Ok | UploadStatus::AlreadyExists) {
+            if let Some(index) = self.index.lock().unwrap().as_mut() {
+                index.insert(result.file_key.clone(), client.canister_id());
+            }
+        }
+        Ok(result)
+    }
+
+    /// The box of a file, from the index
+    ///
+    /// Example code :
+    /// ```
+    /// use bytes::Bytes;
+    /// use candid::Principal;
+    /// use metabox_sdk::databox::mock::MockDataBox;
+    /// use metabox_sdk::metabox::MetaBoxClient;
+    /// use metabox_sdk::metabox::mock::MockMetaBox;
+    /// use metabox_sdk::pool::StoragePool;
+    /// use metabox_sdk::transfer::{CancelToken, Transfer};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let user = Principal::anonymous();
+    ///     let meta_box = MockMetaBox::new();
+    ///     meta_box.register(MockDataBox::new(Principal::from_slice(&[7; 10]), user), "files".to_string(), false);
+    ///     let pool = StoragePool::open(MetaBoxClient::mock(&meta_box, user)).await.unwrap();
+    ///
+    ///     // A cancelled upload leaves an incomplete file, which is not indexed
+    ///     let cancel = CancelToken::new();
+    ///     cancel.cancel();
+    ///     let cancelled = Transfer::new().with_cancel(cancel);
+    ///     let put = pool.put_bytes_with(Bytes::from(vec![1; 6_000]), "data.bin", None, &cancelled).await.unwrap();
+    ///     assert_eq!(pool.locate(&put.file_key).await.unwrap(), None);
+    ///
+    ///     let put = pool.put_bytes_with(Bytes::from(vec![1; 6_000]), "data.bin", None, &Transfer::new()).await.unwrap();
+    ///     assert_eq!(pool.locate(&put.file_key).await.unwrap(), Some(put.databox_canister_id));
+    ///     pool.refresh_index().await.unwrap();
+    ///     assert_eq!(pool.locate(&put.file_key).await.unwrap(), Some(put.databox_canister_id));
+    /// }
+    /// ```
+    pub async fn locate(&self, file_key: &str) -> Result<Option<Principal>, CallError> {
+        if self.index.lock().unwrap().is_none() {
+            self.refresh_index().await?;
+        }
+        Ok(self.index.lock().unwrap().as_ref().and_then(|index| index.get(file_key).copied()))
+    }
+
+    /// List the files of every box again, for files uploaded by other clients
+    ///
+    /// The complete plain and encrypted files are indexed.
+    pub async fn refresh_index(&self) -> Result<(), CallError> {
+        let mut index = HashMap::new();
+        for client in self.clients() {
+            // A box which can not be listed is left out, its files are not found
+            if let Ok(files) = client.get_all_files_info().await? {
+                for file in files {
+                    if let FileExt::PlainFileExt(asset_ext) | FileExt::EncryptFileExt(asset_ext) = file {
+                        if asset_ext.upload_status {
+                            index.insert(asset_ext.file_key, client.canister_id());
+                        }
+                    }
+                }
+            }
+        }
+        *self.index.lock().unwrap() = Some(index);
+        Ok(())
+    }
+
+    /// Information of the plain files of every box
+    pub async fn list(&self) -> Result<Result<Vec<FileExt>, DataErr>, CallError> {
+        let mut all = Vec::new();
+        for client in self.clients() {
+            match client.get_all_plain_files_info().await? {
+                Ok(files) => all.extend(files),
+                Err(data_err) => return Ok(Err(data_err)),
+            }
+        }
+        Ok(Ok(all))
+    }
+
+    pub async fn get_plain_file(&self, file_key: &str) -> Result<Result<Vec<u8>, DataErr>, CallError> {
+        self.get_plain_file_with(file_key, &Transfer::default()).await
+    }
+
+    /// [`DataBoxClient::get_plain_file_with`] from the box the file is in
+    pub async fn get_plain_file_with(&self, file_key: &str, transfer: &Transfer) -> Result<Result<Vec<u8>, DataErr>, CallError> {
+        match self.client_of(file_key).await? {
+            Some(client) => client.get_plain_file_with(file_key, transfer).await,
+            None => Ok(Err(DataErr::FileKeyErr)),
+        }
+    }
+
+    pub async fn get_file_info(&self, file_key: &str) -> Result<Result<FileExt, DataErr>, CallError> {
+        match self.client_of(file_key).await? {
+            Some(client) => client.get_file_info(file_key).await,
+            None => Ok(Err(DataErr::FileKeyErr)),
+        }
+    }
+
+    pub async fn delete_file(&self, file_key: &str) -> Result<DeleteKeyResult, CallError> {
+        let client = match self.client_of(file_key).await? {
+            Some(client) => client,
+            None => return Ok(DeleteKeyResult::err(DataErr::FileKeyErr)),
+        };
+        let result = client.delete_file(file_key.to_string()).await?;
+        if let DeleteKeyResult::ok(_) = result {
+            if let Some(index) = self.index.lock().unwrap().as_mut() {
+                index.remove(file_key);
+            }
+        }
+        Ok(result)
+    }
+
+    fn clients(&self) -> Vec<DataBoxClient> {
+        self.boxes.lock().unwrap().clone()
+    }
+
+    async fn client_of(&self, file_key: &str) -> Result<Option<DataBoxClient>, CallError> {
+        Ok(self.locate(file_key).await?.and_then(|box_id| self.data_box(box_id)))
+    }
+
+    /// The first box with room for `file`, a new one when the pool grows, or the most memory
+    /// any box has
+    async fn place(&self, file: &PlannedFile) -> Result<Result<DataBoxClient, u64>, PoolError> {
+        let mut most_available = 0;
+        for client in self.clients() {
+            if let AvlSMResult::ok(available) = client.get_avl_sm().await? {
+                if available >= file.footprint {
+                    return Ok(Ok(client));
+                }
+                most_available = most_available.max(available);
+            }
+        }
+        match &self.growth {
+            Some(growth) => Ok(Ok(self.create_box(growth).await?)),
+            None => Ok(Err(most_available)),
+        }
+    }
+
+    async fn create_box(&self, growth: &Growth) -> Result<DataBoxClient, PoolError> {
+        let count = self.boxes.lock().unwrap().len();
+        for attempt in 1..=MAX_NAME_ATTEMPTS {
+            let box_name = format!("{}-{}", growth.box_name, count + attempt);
+            match self.metabox.create_data_box(growth.icp_amount, box_name, growth.is_private).await? {
+                CreateBoxResult::ok(box_id) => {
+                    let client = self.metabox.data_box_client(box_id);
+                    self.boxes.lock().unwrap().push(client.clone());
+                    return Ok(client);
+                }
+                CreateBoxResult::err(Error::NameRepeat) => continue,
+                CreateBoxResult::err(error) => return Err(PoolError::CreateBox(error)),
+            }
+        }
+        Err(PoolError::CreateBox(Error::NameRepeat))
+    }
+
+    fn refuse(&self, file: PlannedFile, available: u64, transfer: &Transfer) -> Result<PutPlainFileResult, PoolError> {
+        if transfer.capacity_policy() == CapacityPolicy::Reject {
+            let error = CapacityError {
+                available,
+                used: 0,
+                required: file.footprint,
+                rejected: vec![file],
+            };
+            return Err(CallError::Capacity(Box::new(error)).into());
+        }
+        Ok(PutPlainFileResult {
+            file_name: file.file_name,
+            file_extension: file.file_extension,
+            file_key: file.file_key,
+            upload_status: UploadStatus::Err(DataErr::MemoryInsufficient),
+            databox_canister_id: Principal::anonymous(),
+            total_size: file.total_size,
+            chunk_number: file.chunk_number,
+        })
+    }
+}
@@ -0,0 +1,280 @@
+//! Search over box inventories
+//!
+//! A [`FileQuery`] filters the files of a box, as listed by `getAssetexts`, by name, MIME type,
+//! size, upload completeness and kind (plain, encrypted or shared), then sorts and pages them.
+//! [`search_box`] runs it over one DataBox, [`search_boxes`] over every data box of a user.
+//!
+//! Names are matched against the stored file name and against it with the extension of its type
+//! (`report` and `report.pdf`), the name a file was uploaded under.
+use std::cmp::Ordering;
+use candid::Principal;
+use regex::Regex;
+use crate::agent::CallError;
+use crate::databox::cache::file_key_of as file_key;
+use crate::databox::{DataBoxClient, DataErr, FileExt};
+use crate::metabox::{BoxType, MetaBoxClient};
+use crate::mime;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileKind {
+    Plain,
+    Encrypted,
+    Shared,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortBy {
+    Name,
+    Size,
+    MimeType,
+    FileKey,
+}
+
+/// A file found in a box
+#[derive(Clone, Debug)]
+pub struct FileMatch {
+    pub box_id: Principal,
+    pub file: FileExt,
+}
+
+impl FileMatch {
+    pub fn file_key(&self) -> &str {
+        file_key(&self.file)
+    }
+
+    pub fn file_name(&self) -> &str {
+        file_name(&self.file)
+    }
+}
+
+/// One page of the files found, out of `total`
+#[derive(Clone, Debug)]
+pub struct SearchPage {
+    pub total: usize,
+    pub matches: Vec<FileMatch>,
+}
+
+/// Filters, order and page of a search, every file matches a new query
+///
+/// Example code :
+/// ```
+/// use candid::Principal;
+/// use metabox_sdk::databox::DataBoxClient;
+/// use metabox_sdk::databox::mock::MockDataBox;
+/// use metabox_sdk::search::{search_box, FileQuery, SortBy};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let owner = Principal::anonymous();
+///     let client = DataBoxClient::mock(&MockDataBox::new(Principal::management_canister(), owner), owner);
+///     client.put_bytes(vec![0; 300], "notes.txt", "text/plain").await.unwrap();
+///     client.put_bytes(vec![1; 100], "todo.txt", "text/plain").await.unwrap();
+///     client.put_bytes(vec![2; 200], "photo.png", "image/png").await.unwrap();
+///
+///     let query = FileQuery::new().with_name_glob("*.txt").unwrap().with_sort(SortBy::Size, false);
+///     let page = search_box(&client, &query).await.unwrap().unwrap();
+///     assert_eq!(page.total, 2);
+///     assert_eq!(page.matches[0].file_name(), "todo");
+///
+///     let query = FileQuery::new().with_mime_type("image/*").with_size(150, None);
+///     assert_eq!(search_box(&client, &query).await.unwrap().unwrap().matches[0].file_name(), "photo");
+/// }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct FileQuery {
+    name: Option<Regex>,
+    mime_type: Option<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    complete: Option<bool>,
+    kinds: Option<Vec<FileKind>>,
+    sort: Option<(SortBy, bool)>,
+    offset: usize,
+    limit: Option<usize>,
+}
+
+impl FileQuery {
+    pub fn new() -> Self {
+        FileQuery::default()
+    }
+
+    /// Names matching a glob : `*` any characters, `?` one character
+    pub fn with_name_glob(self, pattern: &str) -> Result<Self, regex::Error> {
+        let mut expression = String::from("^");
+        for c in pattern.chars() {
+            match c {
+                '*' => expression.push_str(".*"),
+                '?' => expression.push('.'),
+                c => expression.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        expression.push('$');
+        self.with_name_regex(&expression)
+    }
+
+    /// Names in which the regular expression is found
+    pub fn with_name_regex(mut self, expression: &str) -> Result<Self, regex::Error> {
+        self.name = Some(Regex::new(expression)?);
+        Ok(self)
+    }
+
+    /// Files of a MIME type, or of a family with `image/*`
+    pub fn with_mime_type(mut self, mime_type: &str) -> Self {
+        self.mime_type = Some(mime_type.to_ascii_lowercase());
+        self
+    }
+
+    /// Files of `min` bytes or more, and at most `max` bytes, shared files have no size
+    pub fn with_size(mut self, min: u64, max: Option<u64>) -> Self {
+        self.min_size = Some(min);
+        self.max_size = max;
+        self
+    }
+
+    /// Files whose upload is complete, or incomplete
+    pub fn with_complete(mut self, complete: bool) -> Self {
+        self.complete = Some(complete);
+        self
+    }
+
+    /// Files of these kinds only
+    pub fn with_kinds(mut self, kinds: &[FileKind]) -> Self {
+        self.kinds = Some(kinds.to_vec());
+        self
+    }
+
+    /// Order of the files, the order of the listings by default
+    pub fn with_sort(mut self, sort_by: SortBy, descending: bool) -> Self {
+        self.sort = Some((sort_by, descending));
+        self
+    }
+
+    /// Skip `offset` files and keep at most `limit`
+    pub fn with_page(mut self, offset: usize, limit: usize) -> Self {
+        self.offset = offset;
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn matches(&self, file: &FileExt) -> bool {
+        if let Some(name) = &self.name {
+            let full_name = match mime::to_extension(mime_type(file)) {
+                Some(extension) => format!("{}.{}", file_name(file), extension),
+                None => file_name(file).to_string(),
+            };
+            if !name.is_match(file_name(file)) && !name.is_match(&full_name) {
+                return false;
+            }
+        }
+        if let Some(wanted) = &self.mime_type {
+            let actual = mime_type(file).to_ascii_lowercase();
+            let found = match wanted.strip_suffix('*') {
+                Some(family) => actual.starts_with(family),
+                None => actual == *wanted,
+            };
+            if !found {
+                return false;
+            }
+        }
+        if self.min_size.is_some() || self.max_size.is_some() {
+            match size(file) {
+                Some(size) if size >= self.min_size.unwrap_or(0) && size <= self.max_size.unwrap_or(u64::MAX) => {}
+                _ => return false,
+            }
+        }
+        if let Some(complete) = self.complete {
+            if upload_complete(file) != Some(complete) {
+                return false;
+            }
+        }
+        match &self.kinds {
+            Some(kinds) => kinds.contains(&kind(file)),
+            None => true,
+        }
+    }
+
+    /// Filter, sort and page `files`
+    pub fn apply(&self, files: Vec<FileMatch>) -> SearchPage {
+        let mut found: Vec<FileMatch> = files.into_iter().filter(|found| self.matches(&found.file)).collect();
+        if let Some((sort_by, descending)) = self.sort {
+            // A stable sort keeps the order of the listings between equal files
+            found.sort_by(|a, b| {
+                let ordering = compare(&a.file, &b.file, sort_by);
+                if descending { ordering.reverse() } else { ordering }
+            });
+        }
+        let total = found.len();
+        let matches = found.into_iter().skip(self.offset).take(self.limit.unwrap_or(usize::MAX)).collect();
+        SearchPage { total, matches }
+    }
+}
+
+/// Search the files of one box, plain, encrypted and shared
+pub async fn search_box(client: &DataBoxClient, query: &FileQuery) -> Result<Result<SearchPage, DataErr>, CallError> {
+    let files = match client.get_all_files_info().await? {
+        Ok(files) => files,
+        Err(data_err) => return Ok(Err(data_err)),
+    };
+    let box_id = client.canister_id();
+    Ok(Ok(query.apply(files.into_iter().map(|file| FileMatch { box_id, file }).collect())))
+}
+
+/// Search the files of every data box of the MetaBox client 's caller, box after box
+pub async fn search_boxes(metabox: &MetaBoxClient, query: &FileQuery) -> Result<Result<SearchPage, DataErr>, CallError> {
+    let mut all = Vec::new();
+    for box_info in metabox.get_boxes(metabox.caller()).await? {
+        if box_info.box_type != BoxType::data_box {
+            continue;
+        }
+        match metabox.data_box_client(box_info.canister_id).get_all_files_info().await? {
+            Ok(files) => all.extend(files.into_iter().map(|file| FileMatch { box_id: box_info.canister_id, file })),
+            Err(data_err) => return Ok(Err(data_err)),
+        }
+    }
+    Ok(Ok(query.apply(all)))
+}
+
+fn compare(a: &FileExt, b: &FileExt, sort_by: SortBy) -> Ordering {
+    match sort_by {
+        SortBy::Name => file_name(a).cmp(file_name(b)),
+        SortBy::Size => size(a).cmp(&size(b)),
+        SortBy::MimeType => mime_type(a).cmp(mime_type(b)),
+        SortBy::FileKey => file_key(a).cmp(file_key(b)),
+    }
+}
+
+fn kind(file: &FileExt) -> FileKind {
+    match file {
+        FileExt::PlainFileExt(_) => FileKind::Plain,
+        FileExt::EncryptFileExt(_) => FileKind::Encrypted,
+        FileExt::SharedFileExt { .. } => FileKind::Shared,
+    }
+}
+
+fn file_name(file: &FileExt) -> &str {
+    match file {
+        FileExt::PlainFileExt(asset_ext) | FileExt::EncryptFileExt(asset_ext) => &asset_ext.file_name,
+        FileExt::SharedFileExt { file_name, .. } => file_name,
+    }
+}
+
+fn mime_type(file: &FileExt) -> &str {
+    match file {
+        FileExt::PlainFileExt(asset_ext) | FileExt::EncryptFileExt(asset_ext) => &asset_ext.file_extension,
+        FileExt::SharedFileExt { file_extension, .. } => file_extension,
+    }
+}
+
+fn size(file: &FileExt) -> Option<u64> {
+    match file {
+        FileExt::PlainFileExt(asset_ext) | FileExt::EncryptFileExt(asset_ext) => Some(asset_ext.total_size),
+        FileExt::SharedFileExt { .. } => None,
+    }
+}
+
+fn upload_complete(file: &FileExt) -> Option<bool> {
+    match file {
+        FileExt::PlainFileExt(asset_ext) | FileExt::EncryptFileExt(asset_ext) => Some(asset_ext.upload_status),
+        FileExt::SharedFileExt { .. } => None,
+    }
+}
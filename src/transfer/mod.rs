@@ -0,0 +1,346 @@
+//! Progress of uploads and downloads
+//!
+//! The chunk loops of uploads (`put` segments) and downloads (`getPlain`) report what they do to
+//! a [`ProgressObserver`] given in a [`Transfer`]. Closures and `std::sync::mpsc::Sender`s are
+//! observers, so a progress bar can be driven from a callback or from another thread.
+//!
+//! A [`CancelToken`] in the [`Transfer`] stops it between two chunks : the chunk in flight is
+//! always completed, so the box never holds a half written segment.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::databox::capacity::CapacityPolicy;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransferOutcome {
+    Completed,
+    /// The box answered a `DataErr`, or the call failed
+    Failed(String),
+    /// The [`CancelToken`] was cancelled, `transferred` bytes went through
+    Cancelled,
+    /// The box already had the complete file
+    AlreadyExists,
+}
+
+#[derive(Clone, Debug)]
+pub enum TransferEvent {
+    /// A file starts, `index` is its position (from 0) in a batch of `count` files
+    FileStarted {
+        direction: Direction,
+        file_name: String,
+        file_key: String,
+        index: usize,
+        count: usize,
+        total_size: u64,
+        chunk_number: u64,
+    },
+    /// A segment was sent with `put`, uploads only
+    ChunkSent {
+        file_key: String,
+        index: u64,
+        bytes: u64,
+    },
+    /// A segment was stored by the box, or a chunk was received from it
+    ChunkAcked {
+        file_key: String,
+        index: u64,
+        bytes: u64,
+        transferred: u64,
+        total_size: u64,
+    },
+    /// A call failed for a transient reason and is tried again after `delay`
+    Retry {
+        method_name: String,
+        attempt: u32,
+        delay: Duration,
+        error: String,
+    },
+    FileFinished {
+        direction: Direction,
+        file_key: String,
+        transferred: u64,
+        outcome: TransferOutcome,
+    },
+}
+
+pub trait ProgressObserver: Send + Sync {
+    fn on_event(&self, event: &TransferEvent);
+}
+
+impl<F: Fn(&TransferEvent) + Send + Sync> ProgressObserver for F {
+    fn on_event(&self, event: &TransferEvent) {
+        self(event)
+    }
+}
+
+impl ProgressObserver for Sender<TransferEvent> {
+    fn on_event(&self, event: &TransferEvent) {
+        // A receiver which went away only stops the reporting, not the transfer
+        let _ = self.send(event.clone());
+    }
+}
+
+/// Shared flag cancelling the transfers it is given to, from any thread or task
+///
+/// Example code :
+/// ```
+/// use std::sync::Arc;
+/// use candid::Principal;
+/// use metabox_sdk::databox::{backend, DataBoxClient, UploadStatus};
+/// use metabox_sdk::databox::mock::MockDataBox;
+/// use metabox_sdk::transfer::{CancelToken, Transfer, TransferEvent};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let owner = Principal::anonymous();
+///     let client = DataBoxClient::mock(&MockDataBox::new(Principal::management_canister(), owner), owner);
+///     let cancel = CancelToken::new();
+///     let stop = cancel.clone();
+///     // Cancel once the first chunk is stored
+///     let transfer = Transfer::new().with_cancel(cancel).with_observer(Arc::new(move |event: &TransferEvent| {
+///         if let TransferEvent::ChunkAcked { .. } = event {
+///             stop.cancel();
+///         }
+///     }));
+///
+///     let data = vec![7; 5_000_000];
+///     let put = backend::put_plain_data(&client, "big".to_string(), "application/octet-stream".to_string(), data, &transfer).await.unwrap();
+///     match put.upload_status {
+///         UploadStatus::Cancelled { committed_chunks } => assert_eq!(committed_chunks, vec![0]),
+///         status => panic!("not cancelled: {:?}", status),
+///     }
+///     assert!(!client.get_file_info(&put.file_key).await.unwrap().is_err());
+/// }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// Stop the transfers after their chunk in flight
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Options of one upload or download operation
+///
+/// Example code :
+/// ``` no_run
+/// use std::sync::Arc;
+/// use candid::Principal;
+/// use metabox_sdk::agent::{self, Network};
+/// use metabox_sdk::databox::DataBoxClient;
+/// use metabox_sdk::transfer::{Transfer, TransferEvent};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let agent = agent::build_agent_for("identities/identity.pem", &Network::Ic).await.unwrap();
+///     let client = DataBoxClient::new(agent, Principal::from_text("4radi-oqaaa-aaaan-qapwa-cai").unwrap());
+///     let transfer = Transfer::new().with_observer(Arc::new(|event: &TransferEvent| {
+///         if let TransferEvent::ChunkAcked { transferred, total_size, .. } = event {
+///             println!("{} / {} bytes", transferred, total_size);
+///         }
+///     }));
+///     client.put_plain_file_with("source/bitcoin.pdf", &transfer).await.unwrap();
+/// }
+/// ```
+#[derive(Clone)]
+pub struct Transfer {
+    observer: Option<Arc<dyn ProgressObserver>>,
+    cancel: Option<CancelToken>,
+    position: (usize, usize),
+    dedup: bool,
+    idempotent: bool,
+    committed_chunks: Vec<u64>,
+    capacity_policy: CapacityPolicy,
+}
+
+impl Default for Transfer {
+    fn default() -> Self {
+        Transfer {
+            observer: None,
+            cancel: None,
+            position: (0, 1),
+            dedup: true,
+            idempotent: false,
+            committed_chunks: Vec::new(),
+            capacity_policy: CapacityPolicy::Unchecked,
+        }
+    }
+}
+
+impl Transfer {
+    pub fn new() -> Self {
+        Transfer::default()
+    }
+
+    pub fn with_observer(mut self, observer: Arc<dyn ProgressObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    pub fn observer(&self) -> Option<&dyn ProgressObserver> {
+        self.observer.as_deref()
+    }
+
+    pub fn with_cancel(mut self, cancel: CancelToken) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(CancelToken::is_cancelled)
+    }
+
+    /// Whether uploads first ask the box for the file, to skip complete files and complete
+    /// incomplete ones, `true` by default
+    ///
+    /// Example code :
+    /// ```
+    /// use candid::Principal;
+    /// use metabox_sdk::databox::{DataBoxClient, UploadStatus};
+    /// use metabox_sdk::databox::mock::MockDataBox;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let owner = Principal::anonymous();
+    ///     let client = DataBoxClient::mock(&MockDataBox::new(Principal::management_canister(), owner), owner);
+    ///     let first = client.put_bytes(b"report".to_vec(), "report.txt", "text/plain").await.unwrap();
+    ///     assert!(matches!(first.upload_status, UploadStatus::Ok));
+    ///     let again = client.put_bytes(b"report".to_vec(), "report.txt", "text/plain").await.unwrap();
+    ///     assert!(matches!(again.upload_status, UploadStatus::AlreadyExists));
+    /// }
+    /// ```
+    pub fn with_dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Whether a `FileRepeat` answer to a segment is a success, `false` by default
+    ///
+    /// A segment of an incomplete file sent again replaces the one the box holds. The box answers
+    /// `FileRepeat` once a file is complete, for example when the first attempt of a retried `put`
+    /// was applied or when another client uploaded the same file meanwhile.
+    pub fn with_idempotent(mut self, idempotent: bool) -> Self {
+        self.idempotent = idempotent;
+        self
+    }
+
+    /// Segments (by order) stored by an earlier transfer of the file, which was cancelled
+    ///
+    /// They are not sent again when the box has the file incomplete (see
+    /// `UploadStatus::Cancelled`), a batch transfer does not pass them on to its files. The box
+    /// can not be asked which segments it holds : without them, the incomplete copy is deleted
+    /// and the whole file sent again.
+    ///
+    /// Example code :
+    /// ```
+    /// use std::sync::Arc;
+    /// use candid::Principal;
+    /// use metabox_sdk::databox::{backend, DataBoxClient, UploadStatus};
+    /// use metabox_sdk::databox::mock::MockDataBox;
+    /// use metabox_sdk::transfer::{CancelToken, Transfer, TransferEvent};
+    ///
+    /// // Cancelled once the first chunk is stored
+    /// fn cancelled_after_one_chunk() -> Transfer {
+    ///     let cancel = CancelToken::new();
+    ///     let stop = cancel.clone();
+    ///     Transfer::new().with_cancel(cancel).with_observer(Arc::new(move |event: &TransferEvent| {
+    ///         if let TransferEvent::ChunkAcked { .. } = event {
+    ///             stop.cancel();
+    ///         }
+    ///     }))
+    /// }
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let owner = Principal::anonymous();
+    ///     let client = DataBoxClient::mock(&MockDataBox::new(Principal::management_canister(), owner), owner);
+    ///     let put = |data: Vec<u8>, transfer: Transfer| {
+    ///         let client = client.clone();
+    ///         async move { backend::put_plain_data(&client, "big".to_string(), "application/octet-stream".to_string(), data, &transfer).await.unwrap() }
+    ///     };
+    ///
+    ///     let data = vec![7; 5_000_000];
+    ///     let committed_chunks = match put(data.clone(), cancelled_after_one_chunk()).await.upload_status {
+    ///         UploadStatus::Cancelled { committed_chunks } => committed_chunks,
+    ///         status => panic!("not cancelled: {:?}", status),
+    ///     };
+    ///     // Only the chunks the box does not hold are sent
+    ///     let resumed = put(data.clone(), Transfer::new().with_committed_chunks(committed_chunks)).await;
+    ///     assert!(matches!(resumed.upload_status, UploadStatus::Ok));
+    ///     assert_eq!(client.get_plain_file(&resumed.file_key).await.unwrap().unwrap(), data);
+    ///
+    ///     // Run again without them, like a batch, the file is uploaded again
+    ///     let data = vec![8; 5_000_000];
+    ///     let cancelled = put(data.clone(), cancelled_after_one_chunk()).await;
+    ///     assert!(matches!(cancelled.upload_status, UploadStatus::Cancelled { .. }));
+    ///     let blind = put(data.clone(), Transfer::new().in_batch(0, 1)).await;
+    ///     assert!(matches!(blind.upload_status, UploadStatus::Ok));
+    ///     assert_eq!(client.get_plain_file(&blind.file_key).await.unwrap().unwrap(), data);
+    /// }
+    /// ```
+    pub fn with_committed_chunks(mut self, committed_chunks: Vec<u64>) -> Self {
+        self.committed_chunks = committed_chunks;
+        self
+    }
+
+    /// Whether uploads first check that the box has room for them, see [`CapacityPolicy`]
+    pub fn with_capacity_policy(mut self, capacity_policy: CapacityPolicy) -> Self {
+        self.capacity_policy = capacity_policy;
+        self
+    }
+
+    /// The same transfer, for file `index` of a batch of `count` files
+    pub fn in_batch(&self, index: usize, count: usize) -> Transfer {
+        Transfer {
+            position: (index, count),
+            committed_chunks: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    /// `(index, count)` of the current file in its batch
+    pub(crate) fn position(&self) -> (usize, usize) {
+        self.position
+    }
+
+    pub(crate) fn dedup(&self) -> bool {
+        self.dedup
+    }
+
+    pub(crate) fn idempotent(&self) -> bool {
+        self.idempotent
+    }
+
+    pub(crate) fn committed_chunks(&self) -> &[u64] {
+        &self.committed_chunks
+    }
+
+    pub(crate) fn capacity_policy(&self) -> CapacityPolicy {
+        self.capacity_policy
+    }
+
+    pub(crate) fn emit(&self, event: TransferEvent) {
+        if let Some(observer) = &self.observer {
+            observer.on_event(&event);
+        }
+    }
+}